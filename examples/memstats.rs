@@ -0,0 +1,58 @@
+//! Populates a synthetic registry under a counting allocator and prints
+//! what it costs, in all and per inode, and how it splits between the
+//! filesystem's structures.
+//!
+//! ```text
+//! cargo run --release --example memstats -- [<crates> [<files per crate>]]
+//! ```
+
+use fuse_crates::{
+    testing::{registry, CountingAllocator, TempDir},
+    CargoCacheFs,
+};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1).map(|arg| arg.parse::<usize>());
+    let crates = args.next().transpose()?.unwrap_or(1000);
+    let files = args.next().transpose()?.unwrap_or(20);
+    let cache = TempDir::new("memstats");
+    registry(cache.path(), crates, files);
+
+    let before = CountingAllocator::allocated();
+    CountingAllocator::reset_peak();
+    let fs = CargoCacheFs::populated(cache.path())?;
+    let resident = CountingAllocator::allocated() - before;
+    let peak = CountingAllocator::peak() - before;
+    let parts = fs.memory_breakdown();
+    let inodes = parts[0].entries.max(1);
+
+    println!("{crates} crates of {files} files, {inodes} inodes");
+    println!("{:<16} {:>12} {:>10}", "", "bytes", "per inode");
+    for (name, bytes) in [("resident", resident), ("peak", peak)] {
+        println!("{name:<16} {bytes:>12} {:>10}", bytes / inodes);
+    }
+    println!();
+    println!(
+        "{:<16} {:>12} {:>10} {:>10}",
+        "structure", "bytes", "entries", "per inode"
+    );
+    for part in &parts {
+        println!(
+            "{:<16} {:>12} {:>10} {:>10}",
+            part.name,
+            part.bytes,
+            part.entries,
+            part.bytes / inodes
+        );
+    }
+    let counted = parts.iter().map(|part| part.bytes).sum::<usize>();
+    println!(
+        "{:<16} {:>12}",
+        "elsewhere",
+        resident.saturating_sub(counted)
+    );
+    Ok(())
+}
@@ -0,0 +1,33 @@
+//! Mounts a cache directory with files of its own next to the crates: a
+//! `VERSION` file, and an `org` directory with a manifest saying when the
+//! filesystem was mounted and a link to the cache directory.
+//!
+//! ```text
+//! cargo run --example virtual_entries -- <cache-dir> <mountpoint>
+//! ```
+
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use fuser::MountOption;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args_os().skip(1).map(PathBuf::from);
+    let (Some(cache), Some(mountpoint)) = (args.next(), args.next()) else {
+        anyhow::bail!("usage: virtual_entries <cache-dir> <mountpoint>");
+    };
+    let mounted = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let cache = cache.canonicalize().context("cache directory")?;
+    let fs = fuse_crates::CargoCacheFs::builder(&cache)
+        .add_virtual_file("VERSION", concat!(env!("CARGO_PKG_VERSION"), "\n"))
+        .add_virtual_file("org/manifest.json", move || {
+            format!("{{\"mounted_at\":{mounted}}}\n").into_bytes()
+        })
+        .add_virtual_symlink("org/cache", &cache)
+        .build()?;
+    fuser::mount2(fs, &mountpoint, &[MountOption::RO])?;
+    Ok(())
+}
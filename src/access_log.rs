@@ -0,0 +1,190 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{error, warn};
+
+const CHANNEL_CAPACITY: usize = 4096;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+enum Message {
+    Record(String),
+    Flush(mpsc::Sender<()>),
+}
+
+pub struct AccessRecord {
+    pub uid: u32,
+    pub pid: u32,
+    pub krate: String,
+    pub path: String,
+}
+
+/// Handle to the `--access-log` writer thread. Each process reading
+/// through an open file gets one record, with the bytes it read, when the
+/// file is released.
+///
+/// Records are sent over a bounded channel; when it is full they are
+/// dropped and counted instead of blocking the caller.
+#[derive(Clone)]
+pub struct AccessLog {
+    sender: mpsc::SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Opening access log {}", path.to_string_lossy()))?;
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        thread::Builder::new()
+            .name("fuse-crates-access-log".to_owned())
+            .spawn(move || write_records(BufWriter::new(file), receiver))
+            .context("Spawning access log writer")?;
+        Ok(Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn record(&self, record: AccessRecord, bytes: u64) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut line = String::new();
+        let _ = write!(
+            line,
+            r#"{{"timestamp":{timestamp:.3},"uid":{},"pid":{},"crate":"{}","path":"{}","bytes":{bytes}}}"#,
+            record.uid,
+            record.pid,
+            json_escape(&record.krate),
+            json_escape(&record.path),
+        );
+        if self.sender.try_send(Message::Record(line)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Blocks until every record sent so far is written to disk.
+    pub fn flush(&self) {
+        let (ack, done) = mpsc::channel();
+        if self.sender.send(Message::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+        let dropped = self.dropped();
+        if dropped != 0 {
+            warn!("[access-log] {dropped} records dropped because the writer fell behind");
+        }
+    }
+}
+
+fn write_records(mut out: BufWriter<File>, receiver: mpsc::Receiver<Message>) {
+    loop {
+        let result = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(Message::Record(line)) => writeln!(out, "{line}"),
+            Ok(Message::Flush(ack)) => {
+                let result = out.flush();
+                let _ = ack.send(());
+                result
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => out.flush(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let _ = out.flush();
+                return;
+            }
+        };
+        if let Err(e) = result {
+            error!("[access-log] write failed: {e}");
+        }
+    }
+}
+
+pub fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str) -> AccessRecord {
+        AccessRecord {
+            uid: 1000,
+            pid: 42,
+            krate: "serde-1.0.0".to_owned(),
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn records_are_json_lines() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let log = AccessLog {
+            sender,
+            dropped: Arc::default(),
+        };
+        log.record(record("src/\"quoted\".rs"), 1234);
+        let Ok(Message::Record(line)) = receiver.try_recv() else {
+            panic!("no record sent");
+        };
+        assert!(line.starts_with(r#"{"timestamp":"#), "{line}");
+        assert!(
+            line.ends_with(
+                r#","uid":1000,"pid":42,"crate":"serde-1.0.0","path":"src/\"quoted\".rs","bytes":1234}"#
+            ),
+            "{line}"
+        );
+    }
+
+    #[test]
+    fn a_full_channel_drops_and_counts() {
+        // Nothing reads the channel, as when the writer falls behind.
+        let (sender, receiver) = mpsc::sync_channel(2);
+        let log = AccessLog {
+            sender,
+            dropped: Arc::default(),
+        };
+        for n in 0..5 {
+            log.record(record(&format!("file-{n}")), 1);
+        }
+        assert_eq!(log.dropped(), 3);
+        let kept = receiver
+            .try_iter()
+            .map(|message| match message {
+                Message::Record(line) => line,
+                Message::Flush(_) => panic!("unexpected flush"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(kept.len(), 2);
+        assert!(kept[0].contains(r#""path":"file-0""#));
+        assert!(kept[1].contains(r#""path":"file-1""#));
+    }
+}
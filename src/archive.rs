@@ -0,0 +1,77 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, Read},
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+
+/// File names picked up from the cache directory, longest first so that
+/// `.tar.gz` is not taken for `.gz`.
+const EXTENSIONS: [&str; 4] = [".tar.gz", ".crate", ".tgz", ".tar"];
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// The crate directory an archive is presented as: its file name without
+/// the extension. `None` for files that are not archives.
+pub fn stem(path: &Path) -> Option<&OsStr> {
+    let name = path.file_name()?.as_bytes();
+    EXTENSIONS
+        .iter()
+        .find_map(|extension| name.strip_suffix(extension.as_bytes()))
+        .filter(|stem| !stem.is_empty())
+        .map(OsStr::from_bytes)
+}
+
+/// Reads the tar stream out of an archive, gzip-compressed or not
+/// depending on its first bytes.
+pub enum Decoder {
+    Gzip(Box<GzDecoder<File>>),
+    Plain(File),
+}
+
+impl Decoder {
+    /// Expects `file` to be at its start.
+    pub fn new(file: File) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        let read = file.read_at(&mut magic, 0)?;
+        let magic = &magic[..read];
+        if magic.starts_with(GZIP_MAGIC) {
+            Ok(Self::Gzip(Box::new(GzDecoder::new(file))))
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "zstd-compressed archives are not supported, only gzip-compressed and plain tar",
+            ))
+        } else {
+            Ok(Self::Plain(file))
+        }
+    }
+
+    pub fn into_inner(self) -> File {
+        match self {
+            Self::Gzip(decoder) => decoder.into_inner(),
+            Self::Plain(file) => file,
+        }
+    }
+}
+
+impl Read for Decoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(decoder) => decoder.read(buf),
+            Self::Plain(file) => file.read(buf),
+        }
+    }
+}
+
+pub fn open<P: AsRef<Path>>(path: P) -> Result<tar::Archive<Decoder>> {
+    let file = File::open(path).context("Opening file")?;
+    Ok(tar::Archive::new(
+        Decoder::new(file).context("Reading archive header")?,
+    ))
+}
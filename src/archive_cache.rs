@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Keeps recently used `.crate` files open between reads.
+///
+/// A worker checks a handle out for the duration of one read and checks it
+/// back in afterwards. At most `capacity` idle handles are kept; the least
+/// recently used one is closed when the bound is hit, and handles idle for
+/// longer than [`IDLE_TIMEOUT`] are closed by a sweeper thread.
+pub struct ArchiveCache {
+    capacity: usize,
+    idle: Mutex<HashMap<PathBuf, Vec<(File, Instant)>>>,
+    /// Archives opened because no idle handle was there to be reused.
+    opened: AtomicU64,
+}
+
+impl ArchiveCache {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        let cache = Arc::new(Self {
+            capacity,
+            idle: Mutex::new(HashMap::new()),
+            opened: AtomicU64::new(0),
+        });
+        let weak = Arc::downgrade(&cache);
+        thread::Builder::new()
+            .name("fuse-crates-archive-sweeper".to_owned())
+            .spawn(move || sweep(weak))
+            .expect("Spawning archive cache sweeper");
+        cache
+    }
+
+    pub fn checkout(&self, path: &Path) -> std::io::Result<File> {
+        let cached = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(path)
+            .and_then(|handles| handles.pop());
+        if let Some((mut file, _)) = cached {
+            file.seek(SeekFrom::Start(0))?;
+            return Ok(file);
+        }
+        let file = match File::open(path) {
+            Err(e) if e.raw_os_error() == Some(libc::EMFILE) => {
+                debug!("[archive-cache] EMFILE, closing every idle handle");
+                self.idle.lock().unwrap().clear();
+                File::open(path)
+            }
+            result => result,
+        }?;
+        self.opened.fetch_add(1, Ordering::Relaxed);
+        Ok(file)
+    }
+
+    /// Closes the idle handles of `path`, which is another file now.
+    pub fn forget(&self, path: &Path) {
+        self.idle.lock().unwrap().remove(path);
+    }
+
+    /// Closes every idle handle.
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+
+    /// How many times an archive had to be opened.
+    pub fn opened(&self) -> u64 {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    pub fn checkin(&self, path: PathBuf, file: File) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        idle.entry(path).or_default().push((file, Instant::now()));
+        let mut count: usize = idle.values().map(Vec::len).sum();
+        while count > self.capacity {
+            let oldest = idle
+                .iter()
+                .filter_map(|(path, handles)| Some((path, handles.first()?.1)))
+                .min_by_key(|(_, last_used)| *last_used)
+                .map(|(path, _)| path.clone());
+            let Some(oldest) = oldest else { break };
+            let handles = idle.get_mut(&oldest).unwrap();
+            handles.remove(0);
+            if handles.is_empty() {
+                idle.remove(&oldest);
+            }
+            count -= 1;
+        }
+    }
+}
+
+fn sweep(cache: Weak<ArchiveCache>) {
+    loop {
+        thread::sleep(IDLE_TIMEOUT / 2);
+        let Some(cache) = cache.upgrade() else {
+            return;
+        };
+        let mut idle = cache.idle.lock().unwrap();
+        idle.retain(|_, handles| {
+            handles.retain(|(_, last_used)| last_used.elapsed() < IDLE_TIMEOUT);
+            !handles.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn archives(dir: &TempDir, names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|name| {
+                let path = dir.path().join(name);
+                std::fs::write(&path, name).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn an_idle_archive_is_reused_from_the_start() {
+        let dir = TempDir::new("archives");
+        let paths = archives(&dir, &["a.crate"]);
+        let cache = ArchiveCache::new(4);
+        for _ in 0..3 {
+            let mut file = cache.checkout(&paths[0]).unwrap();
+            assert_eq!(file.stream_position().unwrap(), 0);
+            file.seek(SeekFrom::End(0)).unwrap();
+            cache.checkin(paths[0].clone(), file);
+        }
+        assert_eq!(cache.opened(), 1);
+    }
+
+    #[test]
+    fn reads_at_once_get_a_handle_each() {
+        let dir = TempDir::new("archives");
+        let paths = archives(&dir, &["a.crate"]);
+        let cache = ArchiveCache::new(4);
+        let (first, second) = (cache.checkout(&paths[0]), cache.checkout(&paths[0]));
+        assert_eq!(cache.opened(), 2);
+        cache.checkin(paths[0].clone(), first.unwrap());
+        cache.checkin(paths[0].clone(), second.unwrap());
+        let _both = (cache.checkout(&paths[0]), cache.checkout(&paths[0]));
+        assert_eq!(cache.opened(), 2);
+    }
+
+    #[test]
+    fn the_least_recently_used_archive_is_closed_beyond_capacity() {
+        let dir = TempDir::new("archives");
+        let paths = archives(&dir, &["a.crate", "b.crate", "c.crate"]);
+        let cache = ArchiveCache::new(2);
+        for path in &paths {
+            cache.checkin(path.clone(), cache.checkout(path).unwrap());
+        }
+        assert_eq!(cache.opened(), 3);
+        cache.checkout(&paths[1]).unwrap();
+        cache.checkout(&paths[2]).unwrap();
+        assert_eq!(cache.opened(), 3);
+        cache.checkout(&paths[0]).unwrap();
+        assert_eq!(cache.opened(), 4);
+    }
+
+    #[test]
+    fn nothing_is_kept_without_capacity() {
+        let dir = TempDir::new("archives");
+        let paths = archives(&dir, &["a.crate"]);
+        let cache = ArchiveCache::new(0);
+        cache.checkin(paths[0].clone(), cache.checkout(&paths[0]).unwrap());
+        cache.checkout(&paths[0]).unwrap();
+        assert_eq!(cache.opened(), 2);
+    }
+}
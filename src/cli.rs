@@ -0,0 +1,887 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use fuser::MountOption;
+use log::warn;
+
+use crate::{
+    control, filter::CrateFilter, name_mapper::NameMapping, preflight, AttrPolicy, PopulateOrder,
+    PrefixPolicy,
+};
+
+pub const USAGE: &str = "Usage: fuse-crates [OPTIONS] [<cache-dir or archive>...] <mountpoint>";
+
+/// What `--help` says of sources, between the usage line and the flags.
+const ARCHIVES: &str =
+    "Sources are directories of archives, or single archives: .crate, .tar.gz and \
+.tgz files compressed with gzip, and plain .tar files. zstd-compressed archives (.tar.zst) are not \
+supported.";
+
+/// Every flag, with what it takes and what it does, for `--help`.
+const FLAGS: &[(&str, Option<&str>, &str)] = &[
+    (
+        "--file-mode",
+        Some("<mode>"),
+        "Mask for the modes of files, at most 555 [555]",
+    ),
+    (
+        "--dir-mode",
+        Some("<mode>"),
+        "Mode of directories, at most 555 [555]",
+    ),
+    (
+        "--uid",
+        Some("<uid>"),
+        "Owner of every file [the mounting user]",
+    ),
+    (
+        "--gid",
+        Some("<gid>"),
+        "Group of every file [that of the mounting user]",
+    ),
+    (
+        "--ttl",
+        Some("<duration>"),
+        "How long the kernel caches names and attributes [1h]",
+    ),
+    (
+        "--icase",
+        None,
+        "Let lookups that find no exact match ignore case",
+    ),
+    (
+        "--name-mapping",
+        Some("<mapping>"),
+        "identity, or sanitize to escape what Windows refuses [identity]",
+    ),
+    (
+        "--prefix-policy",
+        Some("<policy>"),
+        "trust-archive, trust-filename or reject, for entries outside <stem>/",
+    ),
+    (
+        "--shard-root",
+        Some("<n>"),
+        "Put crates under n levels of name prefixes",
+    ),
+    (
+        "--group-by-name",
+        None,
+        "Put the versions of a crate under its name",
+    ),
+    (
+        "--src-dir",
+        Some("<dir>"),
+        "Extracted crates to read from [registry/src]",
+    ),
+    ("--no-src-dir", None, "Always read the archives"),
+    (
+        "--include",
+        Some("<glob>"),
+        "Only mount matching crates (repeatable)",
+    ),
+    (
+        "--exclude",
+        Some("<glob>"),
+        "Leave matching crates out (repeatable)",
+    ),
+    (
+        "--eager",
+        None,
+        "Populate every crate before serving the mount",
+    ),
+    (
+        "--populate-budget",
+        Some("<duration>"),
+        "Populate crates for this long first [0]",
+    ),
+    (
+        "--populate-order",
+        Some("<mtime|size>"),
+        "Which crates the budget goes to [mtime]",
+    ),
+    (
+        "--prefetch",
+        Some("<file>"),
+        "Populate the crates listed in <file> first",
+    ),
+    (
+        "--max-resident-crates",
+        Some("<n>"),
+        "Defer the least recently used crates beyond n",
+    ),
+    (
+        "--jobs",
+        Some("<n>"),
+        "Threads scanning archives [the number of CPUs]",
+    ),
+    (
+        "--io-threads",
+        Some("<n>"),
+        "Threads serving reads [the number of CPUs]",
+    ),
+    (
+        "--max-open-archives",
+        Some("<n>"),
+        "Archives kept open between reads [64]",
+    ),
+    (
+        "--content-cache-size",
+        Some("<size>"),
+        "Memory for decompressed files, with k, M or G [64M]",
+    ),
+    (
+        "--spill-dir",
+        Some("<dir>"),
+        "Decompress larger files to <dir> for random reads",
+    ),
+    (
+        "--spill-size",
+        Some("<size>"),
+        "Disk space --spill-dir may use [1G]",
+    ),
+    (
+        "--max-read-bps",
+        Some("<rate>"),
+        "Bytes per second read from the whole mount",
+    ),
+    (
+        "--max-read-bps-per-handle",
+        Some("<rate>"),
+        "Bytes per second read from each open file",
+    ),
+    (
+        "--verify",
+        None,
+        "Check the sha256 of archives before populating them",
+    ),
+    (
+        "--checksums",
+        Some("<file>"),
+        "Expected digests for --verify, as sha256sum prints them",
+    ),
+    (
+        "--verify-cache",
+        Some("<dir>"),
+        "Where --verify remembers digests [~/.cache/fuse-crates/sha256]",
+    ),
+    (
+        "--verify-reads",
+        None,
+        "Check entry sizes and the gzip CRC while reading",
+    ),
+    (
+        "--strict",
+        None,
+        "List the problems of every crate as JSON instead of mounting",
+    ),
+    (
+        "--fsck",
+        None,
+        "Check the inode table with every crate populated and exit",
+    ),
+    (
+        "--list-only",
+        None,
+        "Print the crates that would be mounted and exit",
+    ),
+    (
+        "--self-test",
+        None,
+        "Check the mount against the archives once mounted",
+    ),
+    (
+        "--self-test-exit",
+        None,
+        "--self-test, then unmount and exit with its result",
+    ),
+    (
+        "--access-log",
+        Some("<file>"),
+        "Append a JSON line for each process reading a file",
+    ),
+    (
+        "--show-control-dir",
+        None,
+        "List the .fuse-crates directory in the root",
+    ),
+    (
+        "--control-dir",
+        Some("<name>"),
+        "Name of the control directory [.fuse-crates]",
+    ),
+    (
+        "--no-control-dir",
+        None,
+        "Leave the root to the crates, with nothing generated",
+    ),
+    (
+        "--control-socket",
+        Some("<path>"),
+        "Serve the control files on a Unix socket, not in the root",
+    ),
+    (
+        "--control-writes",
+        None,
+        "Mount read-write for the drop-caches, refresh and log-level files",
+    ),
+    (
+        "--overlay",
+        Some("<dir>"),
+        "Mount read-write, keeping what is written in <dir>",
+    ),
+    (
+        "-o",
+        Some("<options>"),
+        "Mount options, comma-separated (repeatable)",
+    ),
+    (
+        "--mkdir",
+        None,
+        "Create the mountpoint if it does not exist",
+    ),
+    (
+        "--nonempty",
+        None,
+        "Mount over a directory that is not empty",
+    ),
+    (
+        "--force-remount",
+        None,
+        "Unmount a stale FUSE mount at the mountpoint first",
+    ),
+    (
+        "--daemonize",
+        None,
+        "Detach from the terminal once the mountpoint is checked",
+    ),
+    (
+        "--foreground",
+        None,
+        "Stay attached to the terminal (the default)",
+    ),
+    (
+        "--shutdown-timeout",
+        Some("<duration>"),
+        "How long unmounting waits for running reads [5s]",
+    ),
+    ("-h, --help", None, "Print this help"),
+];
+
+/// [`USAGE`] and [`FLAGS`], for `--help`.
+pub fn usage() -> String {
+    let flag = |(name, value, _): &(&str, Option<&str>, &str)| match value {
+        Some(value) => format!("{name} {value}"),
+        None => name.to_string(),
+    };
+    let width = FLAGS
+        .iter()
+        .map(|entry| flag(entry).len())
+        .max()
+        .unwrap_or(0);
+    let mut usage = format!("{USAGE}\n\n{ARCHIVES}\n\nOptions:\n");
+    for entry in FLAGS {
+        usage += &format!("  {:width$}  {}\n", flag(entry), entry.2);
+    }
+    usage
+}
+
+pub struct Options {
+    /// Registry caches holding `.crate` files, or single archives; empty
+    /// for [`default_source`].
+    pub sources: Vec<PathBuf>,
+    pub mountpoint: Option<PathBuf>,
+    pub daemonize: bool,
+    /// Extra `-o` options, on top of the read-only set main always uses.
+    pub mount_options: Vec<MountOption>,
+    pub attr_policy: AttrPolicy,
+    pub self_test: bool,
+    pub self_test_exit: bool,
+    pub io_threads: usize,
+    pub jobs: usize,
+    pub max_open_archives: usize,
+    pub access_log: Option<PathBuf>,
+    pub prefix_policy: PrefixPolicy,
+    pub name_mapping: NameMapping,
+    pub shard_levels: usize,
+    pub group_by_name: bool,
+    /// How long to spend populating crates before the mount is served; the
+    /// rest are populated on first access. `None` (`--eager`) populates
+    /// everything up front.
+    pub populate_budget: Option<Duration>,
+    pub populate_order: PopulateOrder,
+    /// How many crates stay populated; the least recently used are
+    /// deferred again beyond that.
+    pub max_resident_crates: Option<usize>,
+    pub filter: CrateFilter,
+    /// Where extracted crates are read from instead of their archives;
+    /// `None` looks next to the cache directory.
+    pub src_dir: Option<PathBuf>,
+    /// Unset by `--no-src-dir`, to always read the archives.
+    pub use_src_dir: bool,
+    /// Print the crates that would be mounted and exit.
+    pub list_only: bool,
+    /// List `/.fuse-crates` in the root, which is otherwise hidden.
+    pub show_control_dir: bool,
+    /// The name of the control directory; `None` (`--no-control-dir`, or
+    /// `--control-socket`) puts nothing generated in the root.
+    pub control_dir: Option<String>,
+    /// Where the control files are served instead.
+    pub control_socket: Option<PathBuf>,
+    /// Add the control files that act when written to, the only ones
+    /// writable without `--overlay`.
+    pub control_writes: bool,
+    /// Let lookups that find no exact match ignore case.
+    pub icase: bool,
+    pub fsck: bool,
+    pub strict: bool,
+    pub verify_reads: bool,
+    /// Check archives against their sha256 before populating them.
+    pub verify: bool,
+    /// `sha256sum`-style list of expected digests for `--verify`.
+    pub checksums: Option<PathBuf>,
+    /// Where `--verify` memoizes digests.
+    pub verify_cache: Option<PathBuf>,
+    pub shutdown_timeout: Duration,
+    /// How long the kernel caches attributes and names, negative lookups
+    /// included.
+    pub ttl: Duration,
+    pub content_cache_size: u64,
+    /// Where files too large for the content cache are decompressed to for
+    /// random reads. `None` decompresses up to the offset on every read.
+    pub spill_dir: Option<PathBuf>,
+    pub spill_size: u64,
+    /// Where what is written through the mount goes; `None` mounts
+    /// read-only.
+    pub overlay: Option<PathBuf>,
+    pub max_read_bps: Option<u64>,
+    pub max_read_bps_per_handle: Option<u64>,
+    pub preflight: preflight::Checks,
+    /// `--help`: print [`usage`] and exit.
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            sources: vec![],
+            mountpoint: None,
+            daemonize: false,
+            mount_options: vec![],
+            attr_policy: AttrPolicy::default(),
+            self_test: false,
+            self_test_exit: false,
+            io_threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            jobs: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            max_open_archives: 64,
+            access_log: None,
+            prefix_policy: PrefixPolicy::default(),
+            name_mapping: NameMapping::default(),
+            shard_levels: 0,
+            group_by_name: false,
+            populate_budget: Some(Duration::ZERO),
+            populate_order: PopulateOrder::default(),
+            max_resident_crates: None,
+            filter: CrateFilter::default(),
+            src_dir: None,
+            use_src_dir: true,
+            list_only: false,
+            show_control_dir: false,
+            control_dir: Some(control::DIR.to_owned()),
+            control_socket: None,
+            control_writes: false,
+            icase: false,
+            fsck: false,
+            strict: false,
+            verify_reads: false,
+            verify: false,
+            checksums: None,
+            verify_cache: None,
+            shutdown_timeout: Duration::from_secs(5),
+            ttl: Duration::from_secs(3600),
+            content_cache_size: 64 << 20,
+            spill_dir: None,
+            spill_size: 1 << 30,
+            overlay: None,
+            max_read_bps: None,
+            max_read_bps_per_handle: None,
+            preflight: preflight::Checks {
+                mkdir: false,
+                nonempty: false,
+                force_remount: false,
+            },
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<Self> {
+        let mut options = Options::default();
+        let mut positional = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.as_encoded_bytes().starts_with(b"-") {
+                positional.push(PathBuf::from(arg));
+                continue;
+            }
+            let arg = arg
+                .into_string()
+                .map_err(|arg| anyhow::anyhow!("Invalid argument: {}", arg.to_string_lossy()))?;
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_owned(), Some(value.to_owned()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = || -> Result<String> {
+                match inline_value.clone() {
+                    Some(value) => Ok(value),
+                    None => args
+                        .next()
+                        .with_context(|| format!("Missing value for {flag}"))?
+                        .into_string()
+                        .map_err(|_| anyhow::anyhow!("Invalid value for {flag}")),
+                }
+            };
+            match flag.as_str() {
+                "--file-mode" => {
+                    options.attr_policy.file_mode = parse_mode(&value()?, AttrPolicy::MAX_MODE)
+                        .context("Parsing --file-mode")?
+                }
+                "--dir-mode" => {
+                    options.attr_policy.dir_mode =
+                        parse_mode(&value()?, AttrPolicy::MAX_MODE).context("Parsing --dir-mode")?
+                }
+                "--uid" => options.attr_policy.uid = value()?.parse().context("Parsing --uid")?,
+                "--gid" => options.attr_policy.gid = value()?.parse().context("Parsing --gid")?,
+                "--io-threads" => {
+                    options.io_threads = value()?.parse().context("Parsing --io-threads")?
+                }
+                "--jobs" => options.jobs = value()?.parse().context("Parsing --jobs")?,
+                "--max-open-archives" => {
+                    options.max_open_archives =
+                        value()?.parse().context("Parsing --max-open-archives")?
+                }
+                "--access-log" => options.access_log = Some(PathBuf::from(value()?)),
+                "--prefix-policy" => {
+                    options.prefix_policy = value()?.parse().context("Parsing --prefix-policy")?
+                }
+                "--name-mapping" => {
+                    options.name_mapping = value()?.parse().context("Parsing --name-mapping")?
+                }
+                "--mkdir" => options.preflight.mkdir = true,
+                "--nonempty" => options.preflight.nonempty = true,
+                "--force-remount" => options.preflight.force_remount = true,
+                "--shard-root" => {
+                    options.shard_levels = value()?.parse().context("Parsing --shard-root")?
+                }
+                "--group-by-name" => options.group_by_name = true,
+                "--populate-budget" => {
+                    options.populate_budget =
+                        Some(parse_duration(&value()?).context("Parsing --populate-budget")?)
+                }
+                "--eager" => options.populate_budget = None,
+                "--max-resident-crates" => {
+                    options.max_resident_crates =
+                        Some(value()?.parse().context("Parsing --max-resident-crates")?)
+                }
+                "--populate-order" => {
+                    options.populate_order = value()?.parse().context("Parsing --populate-order")?
+                }
+                "--prefetch" => {
+                    let list = value()?;
+                    let stems = std::fs::read_to_string(&list)
+                        .with_context(|| format!("Reading --prefetch list {list}"))?
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(|line| OsString::from(line.trim_end_matches(".crate")))
+                        .collect();
+                    options.populate_order = PopulateOrder::Prefetch(stems)
+                }
+                "--shutdown-timeout" => {
+                    options.shutdown_timeout =
+                        parse_duration(&value()?).context("Parsing --shutdown-timeout")?
+                }
+                "--ttl" => options.ttl = parse_duration(&value()?).context("Parsing --ttl")?,
+                "--content-cache-size" => {
+                    options.content_cache_size =
+                        parse_size(&value()?).context("Parsing --content-cache-size")?
+                }
+                "--spill-dir" => options.spill_dir = Some(PathBuf::from(value()?)),
+                "--spill-size" => {
+                    options.spill_size = parse_size(&value()?).context("Parsing --spill-size")?
+                }
+                "--overlay" => options.overlay = Some(PathBuf::from(value()?)),
+                "--max-read-bps" => {
+                    options.max_read_bps =
+                        Some(parse_rate(&value()?).context("Parsing --max-read-bps")?)
+                }
+                "--max-read-bps-per-handle" => {
+                    options.max_read_bps_per_handle =
+                        Some(parse_rate(&value()?).context("Parsing --max-read-bps-per-handle")?)
+                }
+                "-o" => {
+                    for option in value()?.split(',').filter(|option| !option.is_empty()) {
+                        let option = parse_mount_option(option)?;
+                        if let Some((name, _, opposite)) = mount_flag(&option) {
+                            if options.mount_options.contains(opposite) {
+                                warn!(
+                                    "-o {name} overrides the earlier -o {}",
+                                    mount_flag(opposite).unwrap().0
+                                );
+                                options.mount_options.retain(|earlier| earlier != opposite);
+                            }
+                        }
+                        options.mount_options.push(option);
+                    }
+                }
+                "--daemonize" => options.daemonize = true,
+                "--foreground" => options.daemonize = false,
+                "--include" => options.filter.include.push(value()?),
+                "--exclude" => options.filter.exclude.push(value()?),
+                "--list-only" => options.list_only = true,
+                "--show-control-dir" => options.show_control_dir = true,
+                "--control-dir" => {
+                    options.control_dir =
+                        Some(parse_file_name(&value()?).context("Parsing --control-dir")?)
+                }
+                "--no-control-dir" => options.control_dir = None,
+                "--control-socket" => options.control_socket = Some(PathBuf::from(value()?)),
+                "--control-writes" => options.control_writes = true,
+                "--icase" => options.icase = true,
+                "--src-dir" => options.src_dir = Some(PathBuf::from(value()?)),
+                "--no-src-dir" => options.use_src_dir = false,
+                "--fsck" => options.fsck = true,
+                "--strict" => options.strict = true,
+                "--verify-reads" => options.verify_reads = true,
+                "--verify" => options.verify = true,
+                "--checksums" => options.checksums = Some(PathBuf::from(value()?)),
+                "--verify-cache" => options.verify_cache = Some(PathBuf::from(value()?)),
+                "--self-test" => options.self_test = true,
+                "--self-test-exit" => {
+                    options.self_test = true;
+                    options.self_test_exit = true;
+                }
+                "-h" | "--help" => options.help = true,
+                _ => bail!("Unknown argument: {flag} (see --help)"),
+            }
+        }
+        if options.control_socket.is_some() {
+            options.control_dir = None;
+        }
+        // Nothing is mounted, so every argument is a source.
+        if !options.fsck && !options.list_only {
+            options.mountpoint = positional.pop();
+        }
+        options.sources = positional;
+        Ok(options)
+    }
+}
+
+fn parse_mode(value: &str, max: u16) -> Result<u16> {
+    let mode = u16::from_str_radix(value.trim_start_matches("0o"), 8)
+        .with_context(|| format!("{value} is not an octal mode"))?;
+    if mode & !max != 0 {
+        bail!("Mode {mode:o} grants more than {max:o} on a read-only filesystem");
+    }
+    Ok(mode)
+}
+
+/// A name for an entry of the root.
+fn parse_file_name(value: &str) -> Result<String> {
+    if value.is_empty() || value == "." || value == ".." || value.contains('/') {
+        bail!("{value:?} is not a file name");
+    }
+    Ok(value.to_owned())
+}
+
+/// Parses `500ms`, `5s` or `2m`; a bare number is seconds.
+fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("{value} is not a duration"))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        _ => bail!("Unknown duration unit {unit} in {value}"),
+    };
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("{value} is not a duration"))
+}
+
+/// Parses a byte count with an optional `k`, `M` or `G` suffix (powers of
+/// 1024).
+fn parse_size(value: &str) -> Result<u64> {
+    let (number, shift) = match value.strip_suffix(['k', 'K']) {
+        Some(number) => (number, 10),
+        None => match value.strip_suffix('M') {
+            Some(number) => (number, 20),
+            None => match value.strip_suffix('G') {
+                Some(number) => (number, 30),
+                None => (value, 0),
+            },
+        },
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(1 << shift))
+        .with_context(|| format!("{value} is not a byte count"))
+}
+
+fn parse_rate(value: &str) -> Result<u64> {
+    match parse_size(value)? {
+        0 => bail!("{value} is not a positive byte rate"),
+        rate => Ok(rate),
+    }
+}
+
+/// What is mounted with unless `-o` says otherwise.
+const DEFAULT_MOUNT_OPTIONS: [MountOption; 7] = [
+    MountOption::Sync,
+    MountOption::DirSync,
+    MountOption::NoExec,
+    MountOption::RO,
+    MountOption::NoAtime,
+    MountOption::NoDev,
+    MountOption::NoSuid,
+];
+
+/// Mount flags with their opposite, which they override.
+const MOUNT_FLAGS: [(&str, MountOption, MountOption); 10] = [
+    ("dev", MountOption::Dev, MountOption::NoDev),
+    ("nodev", MountOption::NoDev, MountOption::Dev),
+    ("suid", MountOption::Suid, MountOption::NoSuid),
+    ("nosuid", MountOption::NoSuid, MountOption::Suid),
+    ("exec", MountOption::Exec, MountOption::NoExec),
+    ("noexec", MountOption::NoExec, MountOption::Exec),
+    ("atime", MountOption::Atime, MountOption::NoAtime),
+    ("noatime", MountOption::NoAtime, MountOption::Atime),
+    ("sync", MountOption::Sync, MountOption::Async),
+    ("async", MountOption::Async, MountOption::Sync),
+];
+
+fn mount_flag(option: &MountOption) -> Option<&'static (&'static str, MountOption, MountOption)> {
+    MOUNT_FLAGS.iter().find(|(_, flag, _)| flag == option)
+}
+
+impl Options {
+    /// [`DEFAULT_MOUNT_OPTIONS`] less those `-o` overrides, then `-o`, with
+    /// `source`, the first one mounted, as `fsname` and `fuse-crates` as
+    /// `subtype` unless given.
+    pub fn mount_options(&self, source: &Path) -> Vec<MountOption> {
+        let overridden = |default: &MountOption| {
+            self.mount_options.iter().any(|option| {
+                mount_flag(option).is_some_and(|(_, _, opposite)| opposite == default)
+            })
+        };
+        let mut mount_options = DEFAULT_MOUNT_OPTIONS
+            .into_iter()
+            .filter(|default| !overridden(default))
+            .map(|default| match default {
+                MountOption::RO if self.overlay.is_some() || self.control_writes => MountOption::RW,
+                default => default,
+            })
+            .collect::<Vec<_>>();
+        let given = |wanted: fn(&MountOption) -> bool| self.mount_options.iter().any(wanted);
+        if !given(|option| matches!(option, MountOption::FSName(_))) {
+            // libfuse splits options on unescaped commas.
+            let source = source
+                .to_string_lossy()
+                .replace('\\', r"\\")
+                .replace(',', r"\,");
+            mount_options.push(MountOption::FSName(source));
+        }
+        if !given(|option| matches!(option, MountOption::Subtype(_))) {
+            mount_options.push(MountOption::Subtype(env!("CARGO_PKG_NAME").to_owned()));
+        }
+        mount_options.extend(self.mount_options.iter().cloned());
+        mount_options
+    }
+}
+
+fn parse_mount_option(option: &str) -> Result<MountOption> {
+    if let Some((_, flag, _)) = MOUNT_FLAGS.iter().find(|(name, _, _)| *name == option) {
+        return Ok(flag.clone());
+    }
+    Ok(match option.split_once('=') {
+        Some(("fsname", name)) => MountOption::FSName(name.to_owned()),
+        Some(("subtype", name)) => MountOption::Subtype(name.to_owned()),
+        _ => match option {
+            "rw" => bail!("-o rw: the filesystem is read-only, see --overlay"),
+            "ro" => MountOption::RO,
+            "dirsync" => MountOption::DirSync,
+            "allow_other" => MountOption::AllowOther,
+            "allow_root" => MountOption::AllowRoot,
+            "auto_unmount" => MountOption::AutoUnmount,
+            "default_permissions" => MountOption::DefaultPermissions,
+            _ => MountOption::CUSTOM(option.to_owned()),
+        },
+    })
+}
+
+/// Finds the crates.io cache under `$CARGO_HOME/registry/cache`, falling
+/// back to `~/.cargo` when `CARGO_HOME` is not set.
+pub fn default_source() -> Result<PathBuf> {
+    let cargo_home = match std::env::var_os("CARGO_HOME") {
+        Some(cargo_home) => PathBuf::from(cargo_home),
+        None => {
+            PathBuf::from(std::env::var_os("HOME").context("Neither CARGO_HOME nor HOME is set")?)
+                .join(".cargo")
+        }
+    };
+    let cache = cargo_home.join("registry").join("cache");
+    let mut registries = std::fs::read_dir(&cache)
+        .with_context(|| format!("Listing {}", cache.to_string_lossy()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("Listing {}", cache.to_string_lossy()))?;
+    registries.sort();
+    let crates_io = registries.iter().position(|registry| {
+        registry
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("index.crates.io-"))
+    });
+    match (crates_io, registries.len()) {
+        (Some(position), _) => Ok(registries.swap_remove(position)),
+        (None, 1) => Ok(registries.pop().unwrap()),
+        (None, 0) => bail!("No registry cache in {}", cache.to_string_lossy()),
+        (None, _) => bail!(
+            "Several registry caches in {}, pass one explicitly",
+            cache.to_string_lossy()
+        ),
+    }
+}
+
+/// Cargo's extracted sources for the cache directory `source`: given
+/// `registry/cache/<index>`, the `registry/src/<index>` next to it, if any.
+pub fn default_src_dir(source: &Path) -> Option<PathBuf> {
+    let index = source.file_name()?;
+    let registry = source
+        .parent()
+        .filter(|cache| cache.ends_with("cache"))?
+        .parent()?;
+    Some(registry.join("src").join(index)).filter(|src| src.is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options> {
+        Options::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn every_listed_flag_is_parsed() {
+        for (names, value, _) in FLAGS {
+            for name in names.split(", ") {
+                // A value that may not parse, after a flag that is known.
+                let args = match value {
+                    Some(_) => vec![name, "1"],
+                    None => vec![name],
+                };
+                if let Err(e) = parse(&args) {
+                    assert!(!e.to_string().starts_with("Unknown argument"), "{e}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_flag_is_in_the_readme() {
+        let readme = include_str!("../README.md");
+        for (names, _, _) in FLAGS {
+            for name in names.split(", ") {
+                assert!(
+                    readme.contains(&format!("`{name}")),
+                    "{name} is not in README.md"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_flags_point_at_help() {
+        let error = parse(&["--no-such-flag"]).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Unknown argument: --no-such-flag (see --help)"
+        );
+        assert!(parse(&["-h"]).unwrap().help);
+        assert!(parse(&["--help", "mnt"]).unwrap().help);
+    }
+
+    #[test]
+    fn usage_lists_every_flag_in_columns() {
+        let usage = usage();
+        assert!(usage.starts_with(USAGE), "{usage}");
+        assert!(usage.contains(".tar.zst) are not supported"), "{usage}");
+        let (_, flags) = usage.split_once("\nOptions:\n").unwrap();
+        let lines = flags.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), FLAGS.len(), "{usage}");
+        let columns = lines
+            .iter()
+            .zip(FLAGS)
+            .map(|(line, (name, _, help))| {
+                assert!(line.starts_with(&format!("  {name}")), "{line}");
+                line.find(help).unwrap()
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(columns.len(), 1, "{usage}");
+    }
+
+    #[test]
+    fn modes_may_only_take_permissions_away() {
+        let max = AttrPolicy::MAX_MODE;
+        assert_eq!(parse_mode("555", max).unwrap(), 0o555);
+        assert_eq!(parse_mode("0o440", max).unwrap(), 0o440);
+        assert_eq!(parse_mode("0", max).unwrap(), 0);
+        for granting in ["644", "0o755", "777", "1555"] {
+            let error = parse_mode(granting, max).err().unwrap();
+            assert!(
+                error.to_string().contains("grants more than 555"),
+                "{error}"
+            );
+        }
+        for garbage in ["", "8", "rwx", "0x1ff"] {
+            let error = parse_mode(garbage, max).err().unwrap();
+            assert!(
+                error.to_string().ends_with("is not an octal mode"),
+                "{error}"
+            );
+        }
+        assert!(parse(&["--dir-mode", "0o755"]).is_err());
+    }
+
+    #[test]
+    fn the_control_dir_is_renamed_or_left_out() {
+        let control_dir = |args: &[&str]| parse(args).unwrap().control_dir;
+        assert_eq!(control_dir(&[]).as_deref(), Some(".fuse-crates"));
+        assert_eq!(
+            control_dir(&["--control-dir", "_fc"]).as_deref(),
+            Some("_fc")
+        );
+        assert_eq!(control_dir(&["--no-control-dir"]), None);
+        assert_eq!(control_dir(&["--control-socket", "/run/fc.sock"]), None);
+        assert_eq!(
+            control_dir(&["--control-socket", "/run/fc.sock", "--control-dir", "_fc"]),
+            None
+        );
+        for bad in ["", ".", "..", "a/b"] {
+            assert!(parse(&["--control-dir", bad]).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::sha256;
+
+/// A `.crate` file, the inode of the directory its crate was put in, which
+/// a replaced archive gets anew, and the index of the entry in it.
+pub type EntryKey = (PathBuf, u64, usize);
+
+/// Fully decompressed contents of recently read files, so that reading a
+/// file piece by piece does not stream the archive from the start for
+/// every piece.
+///
+/// Contents are stored once per sha256, so identical files in different
+/// archives (typically across versions of a crate) share one buffer. At
+/// most `capacity` bytes of distinct contents are kept; the least recently
+/// used entries are evicted first, and contents go with the last entry
+/// referring to them. Files larger than that are never cached.
+pub struct ContentCache {
+    capacity: u64,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// Distinct contents among the entries.
+    pub blobs: usize,
+    pub bytes: u64,
+    pub shared_bytes: u64,
+}
+
+#[derive(Default)]
+struct State {
+    /// Digest of the contents and when the entry was last used.
+    entries: HashMap<EntryKey, (String, u64)>,
+    /// Contents by digest, with the number of entries referring to them.
+    blobs: HashMap<String, (Arc<Vec<u8>>, usize)>,
+    bytes: u64,
+    /// What the entries sharing contents with another would take up besides.
+    shared_bytes: u64,
+    tick: u64,
+}
+
+impl ContentCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            state: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn fits(&self, size: u64) -> bool {
+        size <= self.capacity
+    }
+
+    pub fn get(&self, key: &EntryKey) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        let Some((digest, last_used)) = state.entries.get_mut(key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        *last_used = tick;
+        let digest = digest.clone();
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(state.blobs[&digest].0.clone())
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.state.lock().unwrap();
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: state.entries.len(),
+            blobs: state.blobs.len(),
+            bytes: state.bytes,
+            shared_bytes: state.shared_bytes,
+        }
+    }
+
+    /// Forgets every entry, keeping the counts of hits and misses.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = State {
+            tick: state.tick,
+            ..State::default()
+        };
+    }
+
+    pub fn insert(&self, key: EntryKey, data: Arc<Vec<u8>>) {
+        let size = data.len() as u64;
+        if !self.fits(size) {
+            return;
+        }
+        let digest = sha256::hex_digest(&data[..]).unwrap();
+        let mut state = self.state.lock().unwrap();
+        if let Some((replaced, _)) = state.entries.remove(&key) {
+            state.release(&replaced);
+        }
+        if !state.blobs.contains_key(&digest) {
+            while state.bytes + size > self.capacity {
+                let oldest = state
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(key, _)| key.clone());
+                let Some(oldest) = oldest else { break };
+                let (evicted, _) = state.entries.remove(&oldest).unwrap();
+                state.release(&evicted);
+            }
+        }
+        match state.blobs.get_mut(&digest) {
+            Some((_, references)) => {
+                *references += 1;
+                state.shared_bytes += size;
+            }
+            None => {
+                state.blobs.insert(digest.clone(), (data, 1));
+                state.bytes += size;
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.entries.insert(key, (digest, tick));
+    }
+}
+
+impl State {
+    /// Drops one entry's reference to `digest`, and the contents with the
+    /// last one.
+    fn release(&mut self, digest: &str) {
+        let (data, references) = self.blobs.get_mut(digest).unwrap();
+        let size = data.len() as u64;
+        *references -= 1;
+        if *references == 0 {
+            self.blobs.remove(digest);
+            self.bytes -= size;
+        } else {
+            self.shared_bytes -= size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(stem: &str) -> EntryKey {
+        (PathBuf::from(format!("{stem}.crate")), 2, 0)
+    }
+
+    fn insert(cache: &ContentCache, stem: &str, data: &[u8]) {
+        cache.insert(key(stem), Arc::new(data.to_vec()));
+    }
+
+    #[test]
+    fn identical_contents_are_stored_once() {
+        let cache = ContentCache::new(100);
+        insert(&cache, "serde-1.0.190", b"same");
+        insert(&cache, "serde-1.0.203", b"same");
+        insert(&cache, "serde-1.0.204", b"else");
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.blobs), (3, 2));
+        assert_eq!((stats.bytes, stats.shared_bytes), (8, 4));
+        let (old, new) = (
+            cache.get(&key("serde-1.0.190")).unwrap(),
+            cache.get(&key("serde-1.0.203")).unwrap(),
+        );
+        assert!(Arc::ptr_eq(&old, &new));
+        assert_eq!(*cache.get(&key("serde-1.0.204")).unwrap(), b"else");
+    }
+
+    #[test]
+    fn shared_contents_go_with_the_last_entry_referring_to_them() {
+        let cache = ContentCache::new(10);
+        insert(&cache, "a", b"12345");
+        insert(&cache, "b", b"12345");
+        insert(&cache, "c", b"abcde");
+        // Evicting `a` frees nothing while `b` shares its contents, so `b`
+        // goes too.
+        insert(&cache, "d", b"vwxyz");
+        assert!(cache.get(&key("a")).is_none());
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("c")).is_some());
+        assert!(cache.get(&key("d")).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.blobs), (2, 2));
+        assert_eq!((stats.bytes, stats.shared_bytes), (10, 0));
+    }
+
+    #[test]
+    fn new_contents_for_an_entry_release_the_old() {
+        let cache = ContentCache::new(100);
+        insert(&cache, "a", b"old");
+        insert(&cache, "b", b"old");
+        insert(&cache, "a", b"new!");
+        assert_eq!(*cache.get(&key("a")).unwrap(), b"new!");
+        assert_eq!(*cache.get(&key("b")).unwrap(), b"old");
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.blobs), (2, 2));
+        assert_eq!((stats.bytes, stats.shared_bytes), (7, 0));
+    }
+
+    #[test]
+    fn files_larger_than_the_capacity_are_not_cached() {
+        let cache = ContentCache::new(4);
+        insert(&cache, "a", b"12345");
+        assert!(cache.get(&key("a")).is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.misses), (0, 0, 1));
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    time::Duration,
+};
+
+use crate::{access_log::json_escape, content_cache, worker};
+
+/// Name of the directory at the root holding the control files. Lookups
+/// find it, unless a crate has the name; listings of the root only show it
+/// with `--show-control-dir`.
+pub const DIR: &str = ".fuse-crates";
+
+/// Read-only files describing the running filesystem, generated again
+/// when opened if what they describe may have changed.
+#[derive(Clone, Copy, Debug)]
+pub enum ControlFile {
+    Stats,
+    Crates,
+    Version,
+    AccessSummary,
+    LastRescan,
+}
+
+impl ControlFile {
+    pub const ALL: [Self; 5] = [
+        Self::Stats,
+        Self::Crates,
+        Self::Version,
+        Self::AccessSummary,
+        Self::LastRescan,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Stats => "stats",
+            Self::Crates => "crates",
+            Self::Version => "version",
+            Self::AccessSummary => "access-summary.json",
+            Self::LastRescan => "last-rescan.json",
+        }
+    }
+}
+
+/// Files in the control directory that do something when written to, with
+/// `--control-writes`; everything else stays read-only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlAction {
+    DropCaches,
+    Refresh,
+    LogLevel,
+}
+
+/// What was written to a [`ControlAction`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Forget the decompressed and spilled files and close the archives
+    /// kept open between reads.
+    DropCaches,
+    /// Read the archive with this file stem again, as if it was replaced.
+    Refresh(OsString),
+    LogLevel(log::LevelFilter),
+}
+
+impl ControlAction {
+    pub const ALL: [Self; 3] = [Self::DropCaches, Self::Refresh, Self::LogLevel];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DropCaches => "drop-caches",
+            Self::Refresh => "refresh",
+            Self::LogLevel => "log-level",
+        }
+    }
+
+    /// The command `written` is, as `echo` writes it: `1` to
+    /// `drop-caches`, the file stem of a crate to `refresh` and a level
+    /// like `debug` to `log-level`. `None` if it is not one.
+    pub fn parse(self, written: &[u8]) -> Option<Command> {
+        let written = written.trim_ascii();
+        match self {
+            Self::DropCaches => (written == b"1").then_some(Command::DropCaches),
+            Self::Refresh if written.is_empty() || written.contains(&b'/') => None,
+            Self::Refresh => Some(Command::Refresh(OsStr::from_bytes(written).to_owned())),
+            Self::LogLevel => std::str::from_utf8(written)
+                .ok()?
+                .parse()
+                .ok()
+                .map(Command::LogLevel),
+        }
+    }
+
+    /// What reading the file gives: the level logged at for `log-level`,
+    /// nothing for the others.
+    pub fn contents(self) -> Vec<u8> {
+        match self {
+            Self::LogLevel => {
+                format!("{}\n", log::max_level().as_str().to_ascii_lowercase()).into()
+            }
+            Self::DropCaches | Self::Refresh => vec![],
+        }
+    }
+}
+
+pub struct Stats {
+    pub crates: usize,
+    pub deferred: usize,
+    /// Populated crates that `--max-resident-crates` may evict.
+    pub resident: usize,
+    pub inodes: usize,
+    /// Generated entries that an entry of a crate with the same name hides.
+    pub shadowed: usize,
+    pub workers: worker::Stats,
+    /// How long reads waited on `--max-read-bps-per-handle`, over every
+    /// handle so far.
+    pub handle_throttle_waited: Option<Duration>,
+    /// Entries and inodes the kernel was told to forget.
+    pub invalidations_sent: u64,
+}
+
+/// `key value` lines.
+pub fn stats(stats: &Stats) -> Vec<u8> {
+    let worker::Stats {
+        bytes_served,
+        archives_opened,
+        decompressions_started,
+        content_cache,
+        throttle,
+    } = stats.workers;
+    let content_cache::Stats {
+        hits,
+        misses,
+        entries,
+        blobs,
+        bytes,
+        shared_bytes,
+    } = content_cache;
+    let mut text = String::new();
+    writeln!(text, "crates {}", stats.crates).unwrap();
+    writeln!(text, "crates_deferred {}", stats.deferred).unwrap();
+    writeln!(text, "crates_resident {}", stats.resident).unwrap();
+    writeln!(text, "inodes {}", stats.inodes).unwrap();
+    writeln!(text, "virtual_entries_shadowed {}", stats.shadowed).unwrap();
+    writeln!(text, "bytes_served {bytes_served}").unwrap();
+    writeln!(text, "archives_opened {archives_opened}").unwrap();
+    writeln!(text, "decompressions_started {decompressions_started}").unwrap();
+    writeln!(text, "content_cache_hits {hits}").unwrap();
+    writeln!(text, "content_cache_misses {misses}").unwrap();
+    if hits + misses > 0 {
+        let rate = hits as f64 / (hits + misses) as f64;
+        writeln!(text, "content_cache_hit_rate {rate:.3}").unwrap();
+    }
+    writeln!(text, "content_cache_files {entries}").unwrap();
+    writeln!(text, "content_cache_distinct_files {blobs}").unwrap();
+    writeln!(text, "content_cache_bytes {bytes}").unwrap();
+    writeln!(text, "content_cache_deduplicated_bytes {shared_bytes}").unwrap();
+    if let Some(throttle) = throttle {
+        let waited = throttle.waited.as_secs_f64();
+        writeln!(text, "throttle_waited_seconds {waited:.3}").unwrap();
+        writeln!(text, "throttle_tokens {}", throttle.tokens).unwrap();
+    }
+    if let Some(waited) = stats.handle_throttle_waited {
+        let waited = waited.as_secs_f64();
+        writeln!(text, "handle_throttle_waited_seconds {waited:.3}").unwrap();
+    }
+    writeln!(text, "invalidations_sent {}", stats.invalidations_sent).unwrap();
+    text.into_bytes()
+}
+
+/// `<directory in the mount>\t<archive>` lines, as given.
+pub fn crates<'a>(crates: impl IntoIterator<Item = (&'a Path, &'a Path)>) -> Vec<u8> {
+    let mut text = vec![];
+    for (dir, archive) in crates {
+        text.extend_from_slice(dir.as_os_str().as_bytes());
+        text.push(b'\t');
+        text.extend_from_slice(archive.as_os_str().as_bytes());
+        text.push(b'\n');
+    }
+    text
+}
+
+/// What was read from a crate, counted as for `--access-log`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CrateAccess {
+    /// Processes that read through an open file, once per file.
+    pub reads: u64,
+    pub bytes: u64,
+}
+
+/// A JSON object with a `{"reads":...,"bytes":...}` object per crate.
+pub fn access_summary(summary: &BTreeMap<String, CrateAccess>) -> Vec<u8> {
+    let mut text = String::from("{");
+    for (n, (krate, access)) in summary.iter().enumerate() {
+        if n > 0 {
+            text.push(',');
+        }
+        write!(
+            text,
+            r#""{}":{{"reads":{},"bytes":{}}}"#,
+            json_escape(krate),
+            access.reads,
+            access.bytes
+        )
+        .unwrap();
+    }
+    text.push_str("}\n");
+    text.into_bytes()
+}
+
+pub fn version() -> Vec<u8> {
+    format!(
+        "{} {} ({}, {}-{})\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        },
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_commands_of_each_action_parse() {
+        assert_eq!(
+            ControlAction::DropCaches.parse(b"1\n"),
+            Some(Command::DropCaches)
+        );
+        assert_eq!(ControlAction::DropCaches.parse(b"3\n"), None);
+        assert_eq!(
+            ControlAction::Refresh.parse(b" serde-1.0.193\n"),
+            Some(Command::Refresh("serde-1.0.193".into()))
+        );
+        assert_eq!(ControlAction::Refresh.parse(b"\n"), None);
+        assert_eq!(ControlAction::Refresh.parse(b"../serde-1.0.193\n"), None);
+        assert_eq!(
+            ControlAction::LogLevel.parse(b"DEBUG\n"),
+            Some(Command::LogLevel(log::LevelFilter::Debug))
+        );
+        assert_eq!(ControlAction::LogLevel.parse(b"loud\n"), None);
+        assert_eq!(ControlAction::LogLevel.parse(b"\xff\n"), None);
+    }
+}
@@ -0,0 +1,248 @@
+//! `--control-socket`: the control files, served on a Unix socket rather
+//! than in the mount. A client writes the name of one, such as `stats`,
+//! on a line of its own, or nothing for `stats`, and reads its contents
+//! until the server closes the connection.
+
+use std::{
+    ffi::OsStr,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{fs::FileTypeExt, net::UnixListener, net::UnixStream},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use fuser::Filesystem;
+use log::warn;
+
+use crate::CargoCacheFs;
+
+/// A filesystem the session thread serves while the socket asks it for
+/// control files.
+pub struct Shared(pub Arc<Mutex<CargoCacheFs>>);
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, CargoCacheFs> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Forwards each `Filesystem` method to the shared filesystem.
+macro_rules! delegate {
+    ($(fn $name:ident(&mut self $(, $arg:ident: $ty:ty)*);)*) => {
+        $(fn $name(&mut self $(, $arg: $ty)*) {
+            self.lock().$name($($arg),*)
+        })*
+    };
+}
+
+impl Filesystem for Shared {
+    fn init(
+        &mut self,
+        req: &fuser::Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        self.lock().init(req, config)
+    }
+
+    delegate! {
+        fn destroy(&mut self);
+        fn getattr(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr);
+        fn forget(&mut self, req: &fuser::Request<'_>, ino: u64, nlookup: u64);
+        fn access(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            mask: i32,
+            reply: fuser::ReplyEmpty
+        );
+        fn opendir(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            flags: i32,
+            reply: fuser::ReplyOpen
+        );
+        fn releasedir(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            flags: i32,
+            reply: fuser::ReplyEmpty
+        );
+        fn readdir(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            reply: fuser::ReplyDirectory
+        );
+        fn readdirplus(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            reply: fuser::ReplyDirectoryPlus
+        );
+        fn lookup(
+            &mut self,
+            req: &fuser::Request<'_>,
+            parent: u64,
+            name: &OsStr,
+            reply: fuser::ReplyEntry
+        );
+        fn readlink(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData);
+        fn statfs(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs);
+        fn getxattr(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            name: &OsStr,
+            size: u32,
+            reply: fuser::ReplyXattr
+        );
+        fn listxattr(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            size: u32,
+            reply: fuser::ReplyXattr
+        );
+        fn open(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen);
+        fn lseek(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            whence: i32,
+            reply: fuser::ReplyLseek
+        );
+        fn release(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            flags: i32,
+            lock_owner: Option<u64>,
+            flush: bool,
+            reply: fuser::ReplyEmpty
+        );
+        fn read(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            size: u32,
+            flags: i32,
+            lock_owner: Option<u64>,
+            reply: fuser::ReplyData
+        );
+        fn write(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            data: &[u8],
+            write_flags: u32,
+            flags: i32,
+            lock_owner: Option<u64>,
+            reply: fuser::ReplyWrite
+        );
+        fn setattr(
+            &mut self,
+            req: &fuser::Request<'_>,
+            ino: u64,
+            mode: Option<u32>,
+            uid: Option<u32>,
+            gid: Option<u32>,
+            size: Option<u64>,
+            atime: Option<fuser::TimeOrNow>,
+            mtime: Option<fuser::TimeOrNow>,
+            ctime: Option<SystemTime>,
+            fh: Option<u64>,
+            crtime: Option<SystemTime>,
+            chgtime: Option<SystemTime>,
+            bkuptime: Option<SystemTime>,
+            flags: Option<u32>,
+            reply: fuser::ReplyAttr
+        );
+        fn create(
+            &mut self,
+            req: &fuser::Request<'_>,
+            parent: u64,
+            name: &OsStr,
+            mode: u32,
+            umask: u32,
+            flags: i32,
+            reply: fuser::ReplyCreate
+        );
+        fn mkdir(
+            &mut self,
+            req: &fuser::Request<'_>,
+            parent: u64,
+            name: &OsStr,
+            mode: u32,
+            umask: u32,
+            reply: fuser::ReplyEntry
+        );
+        fn unlink(
+            &mut self,
+            req: &fuser::Request<'_>,
+            parent: u64,
+            name: &OsStr,
+            reply: fuser::ReplyEmpty
+        );
+    }
+}
+
+/// Listens at `path`, replacing a socket left there by an earlier run, and
+/// answers with the control files of `fs` from a thread of its own.
+pub fn serve(path: &Path, fs: Arc<Mutex<CargoCacheFs>>) -> Result<()> {
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Removing the old {}", path.display()))?;
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Listening on {}", path.display()))?;
+    thread::Builder::new()
+        .name("control-socket".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let answered = stream.and_then(|stream| answer(stream, &fs));
+                if let Err(e) = answered {
+                    warn!("[control-socket] {e}");
+                }
+            }
+        })
+        .context("Spawning the control socket thread")?;
+    Ok(())
+}
+
+fn answer(mut stream: UnixStream, fs: &Mutex<CargoCacheFs>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut name = String::new();
+    BufReader::new((&stream).take(256)).read_line(&mut name)?;
+    let name = match name.trim() {
+        "" => "stats",
+        name => name,
+    };
+    let contents = fs
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .control_file(name);
+    match contents {
+        Some(contents) => stream.write_all(&contents),
+        None => {
+            warn!("[control-socket] no control file named {name:?}");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+/// `--include` and `--exclude`, matched against the stems of archives.
+///
+/// With no include every crate is mounted; otherwise only the crates
+/// matching one. Excludes are applied afterwards.
+#[derive(Clone, Debug, Default)]
+pub struct CrateFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl CrateFilter {
+    pub fn admits(&self, stem: &OsStr) -> bool {
+        let stem = stem.as_bytes();
+        let matches = |pattern: &String| glob(pattern.as_bytes(), stem);
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
+
+/// Shell-style matching of the whole of `name`: `*` is any run of bytes,
+/// `?` any one byte, everything else itself.
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and the name position it is matching up to.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some(&byte) if byte == b'?' || byte == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => (),
+        }
+        match backtrack {
+            Some((star, matched)) => {
+                p = star + 1;
+                n = matched + 1;
+                backtrack = Some((star, matched + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
@@ -0,0 +1,399 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::Path,
+};
+
+use fuser::FileType;
+
+use crate::{CargoCacheFs, BLKSIZE};
+
+#[derive(Debug)]
+pub enum Inconsistency {
+    /// The inode table key and `attrs.ino` disagree.
+    InoMismatch {
+        key: u64,
+        ino: u64,
+    },
+    DanglingChild {
+        parent: u64,
+        child: u64,
+    },
+    Orphan {
+        ino: u64,
+    },
+    MultipleParents {
+        ino: u64,
+        parents: Vec<u64>,
+    },
+    /// Listed by one parent but recording another.
+    WrongParent {
+        ino: u64,
+        parent: u64,
+        recorded: u64,
+    },
+    ChildrenOnNonDirectory {
+        ino: u64,
+    },
+    DuplicateName {
+        parent: u64,
+        name: String,
+    },
+    LinkCount {
+        ino: u64,
+        nlink: u32,
+        expected: u32,
+    },
+    Blocks {
+        ino: u64,
+        size: u64,
+        blocks: u64,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InoMismatch { key, ino } => {
+                write!(f, "inode 0x{key:016x} carries attrs for 0x{ino:016x}")
+            }
+            Self::DanglingChild { parent, child } => write!(
+                f,
+                "inode 0x{parent:016x} lists missing child 0x{child:016x}"
+            ),
+            Self::Orphan { ino } => write!(f, "inode 0x{ino:016x} has no parent"),
+            Self::MultipleParents { ino, parents } => {
+                write!(f, "inode 0x{ino:016x} is listed by parents {parents:x?}")
+            }
+            Self::WrongParent {
+                ino,
+                parent,
+                recorded,
+            } => write!(
+                f,
+                "inode 0x{ino:016x} is listed by 0x{parent:016x} but records parent 0x{recorded:016x}"
+            ),
+            Self::ChildrenOnNonDirectory { ino } => {
+                write!(f, "inode 0x{ino:016x} has children but is not a directory")
+            }
+            Self::DuplicateName { parent, name } => {
+                write!(f, "inode 0x{parent:016x} has several children named {name}")
+            }
+            Self::LinkCount {
+                ino,
+                nlink,
+                expected,
+            } => write!(
+                f,
+                "inode 0x{ino:016x} has nlink {nlink}, expected {expected}"
+            ),
+            Self::Blocks { ino, size, blocks } => {
+                write!(f, "inode 0x{ino:016x} has {blocks} blocks for {size} bytes")
+            }
+        }
+    }
+}
+
+impl CargoCacheFs {
+    /// Validates the invariants of the inode table.
+    pub(crate) fn check_consistency(&self) -> Vec<Inconsistency> {
+        let mut found = vec![];
+        let mut parents = BTreeMap::<u64, Vec<u64>>::new();
+        for (&key, inode) in &self.inodes {
+            if inode.attrs.ino != key {
+                found.push(Inconsistency::InoMismatch {
+                    key,
+                    ino: inode.attrs.ino,
+                });
+            }
+            if !inode.children.is_empty() && inode.attrs.kind != FileType::Directory {
+                found.push(Inconsistency::ChildrenOnNonDirectory { ino: key });
+            }
+            let mut names = HashSet::new();
+            for &child in &inode.children {
+                parents.entry(child).or_default().push(key);
+                let Some(child_inode) = self.inodes.get(&child) else {
+                    found.push(Inconsistency::DanglingChild { parent: key, child });
+                    continue;
+                };
+                if child_inode.parent != key {
+                    found.push(Inconsistency::WrongParent {
+                        ino: child,
+                        parent: key,
+                        recorded: child_inode.parent,
+                    });
+                }
+                let name = child_inode.name.as_os_str();
+                if !names.insert(name) {
+                    found.push(Inconsistency::DuplicateName {
+                        parent: key,
+                        name: name.to_string_lossy().into_owned(),
+                    });
+                }
+            }
+        }
+        // Hard links are inodes of their own reading the same archive
+        // entry, so a file's link count is the names of all of them.
+        let mut names = HashMap::<(&Path, usize), usize>::new();
+        for (ino, inode) in &self.inodes {
+            if let Some(archive) = &inode.krate_path {
+                let listed = parents.get(ino).map_or(0, Vec::len);
+                *names.entry((archive, inode.entry_index)).or_default() += listed;
+            }
+        }
+        for (&ino, inode) in &self.inodes {
+            let inode_parents = parents.remove(&ino).unwrap_or_default();
+            let expected = match &inode.krate_path {
+                Some(archive) => names[&(&**archive, inode.entry_index)],
+                None if self.virtual_inodes.contains(&ino) => 1,
+                None => inode_parents.len(),
+            };
+            // Generated entries are found by name only.
+            if ino != fuser::FUSE_ROOT_ID && !self.virtual_inodes.contains(&ino) {
+                match inode_parents.len() {
+                    0 => found.push(Inconsistency::Orphan { ino }),
+                    1 => (),
+                    _ if inode.attrs.kind == FileType::Directory => {
+                        found.push(Inconsistency::MultipleParents {
+                            ino,
+                            parents: inode_parents.clone(),
+                        })
+                    }
+                    _ => (),
+                }
+            }
+            match inode.attrs.kind {
+                FileType::Directory if inode.attrs.nlink < 2 => {
+                    found.push(Inconsistency::LinkCount {
+                        ino,
+                        nlink: inode.attrs.nlink,
+                        expected: 2,
+                    })
+                }
+                FileType::RegularFile if inode.attrs.nlink as usize != expected => {
+                    found.push(Inconsistency::LinkCount {
+                        ino,
+                        nlink: inode.attrs.nlink,
+                        expected: expected as u32,
+                    })
+                }
+                _ => (),
+            }
+            if inode.attrs.kind == FileType::RegularFile
+                && inode.attrs.blocks != inode.attrs.size.div_ceil(BLKSIZE.into())
+            {
+                found.push(Inconsistency::Blocks {
+                    ino,
+                    size: inode.attrs.size,
+                    blocks: inode.attrs.blocks,
+                });
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use super::*;
+    use crate::testing::{self, populated, Fixture, TempDir};
+
+    /// The inodes of `one-0.1.0/{Cargo.toml,src/lib.rs}`.
+    struct Tree {
+        krate: u64,
+        toml: u64,
+        src: u64,
+        lib: u64,
+    }
+
+    /// What `check_consistency` finds once `corrupt` has been applied to a
+    /// consistent table.
+    fn check(corrupt: impl FnOnce(&mut CargoCacheFs, &Tree)) -> (Tree, Vec<Inconsistency>) {
+        let cache = TempDir::new("cache");
+        Fixture::new("one-0.1.0")
+            .file("Cargo.toml", b"[package]\n")
+            .file("src/lib.rs", b"// lib\n")
+            .write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let tree = Tree {
+            krate: fs.find("one-0.1.0").unwrap(),
+            toml: fs.find("one-0.1.0/Cargo.toml").unwrap(),
+            src: fs.find("one-0.1.0/src").unwrap(),
+            lib: fs.find("one-0.1.0/src/lib.rs").unwrap(),
+        };
+        let found = fs.check_consistency();
+        assert!(found.is_empty(), "{found:?}");
+        corrupt(&mut fs, &tree);
+        let found = fs.check_consistency();
+        (tree, found)
+    }
+
+    fn inode(fs: &mut CargoCacheFs, ino: u64) -> &mut crate::Inode {
+        fs.inodes.get_mut(&ino).unwrap()
+    }
+
+    #[test]
+    fn hard_links_count_every_name() {
+        let cache = TempDir::new("cache");
+        Fixture::new("linked-0.1.0")
+            .file("src/lib.rs", b"// lib\n")
+            .hard_link("src/again.rs", "src/lib.rs")
+            .hard_link("copy.rs", "src/lib.rs")
+            .file("copy.rs", b"// replaced\n")
+            .write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let nlink = |fs: &mut CargoCacheFs, path| {
+            let ino = fs.find(path).unwrap();
+            inode(fs, ino).attrs.nlink
+        };
+        assert_eq!(nlink(&mut fs, "linked-0.1.0/src/lib.rs"), 2);
+        assert_eq!(nlink(&mut fs, "linked-0.1.0/src/again.rs"), 2);
+        assert_eq!(nlink(&mut fs, "linked-0.1.0/copy.rs"), 1);
+        let found = fs.check_consistency();
+        assert!(found.is_empty(), "{found:?}");
+        let again = fs.find("linked-0.1.0/src/again.rs").unwrap();
+        inode(&mut fs, again).attrs.nlink = 3;
+        assert!(matches!(
+            fs.check_consistency()[..],
+            [Inconsistency::LinkCount { ino, nlink: 3, expected: 2 }] if ino == again
+        ));
+    }
+
+    #[test]
+    fn ino_mismatch() {
+        let (tree, found) = check(|fs, tree| inode(fs, tree.toml).attrs.ino = 999);
+        assert!(
+            matches!(found[..], [Inconsistency::InoMismatch { key, ino: 999 }] if key == tree.toml),
+            "{found:?}"
+        );
+    }
+
+    #[test]
+    fn dangling_child() {
+        let (_, found) = check(|fs, _| inode(fs, fuser::FUSE_ROOT_ID).children.push(999));
+        assert!(
+            matches!(
+                found[..],
+                [Inconsistency::DanglingChild {
+                    parent: fuser::FUSE_ROOT_ID,
+                    child: 999
+                }]
+            ),
+            "{found:?}"
+        );
+    }
+
+    #[test]
+    fn orphan() {
+        let (tree, found) = check(|fs, tree| {
+            inode(fs, tree.krate)
+                .children
+                .retain(|&child| child != tree.toml)
+        });
+        // Nothing links to it any more either.
+        assert!(
+            matches!(
+                found[..],
+                [
+                    Inconsistency::Orphan { ino },
+                    Inconsistency::LinkCount { ino: unlinked, nlink: 1, expected: 0 },
+                ] if ino == tree.toml && unlinked == tree.toml
+            ),
+            "{found:?}"
+        );
+    }
+
+    #[test]
+    fn multiple_parents() {
+        let (tree, found) =
+            check(|fs, tree| inode(fs, fuser::FUSE_ROOT_ID).children.push(tree.src));
+        assert!(
+            matches!(
+                &found[..],
+                [
+                    Inconsistency::WrongParent { ino, parent: fuser::FUSE_ROOT_ID, recorded },
+                    Inconsistency::MultipleParents { ino: listed, parents },
+                ] if *ino == tree.src && *recorded == tree.krate && *listed == tree.src
+                    && parents[..] == [fuser::FUSE_ROOT_ID, tree.krate]
+            ),
+            "{found:?}"
+        );
+    }
+
+    #[test]
+    fn wrong_parent() {
+        let (tree, found) = check(|fs, tree| inode(fs, tree.lib).parent = tree.krate);
+        assert!(
+            matches!(
+                found[..],
+                [Inconsistency::WrongParent { ino, parent, recorded }]
+                    if ino == tree.lib && parent == tree.src && recorded == tree.krate
+            ),
+            "{found:?}"
+        );
+    }
+
+    #[test]
+    fn children_on_non_directory() {
+        let (tree, found) = check(|fs, tree| {
+            inode(fs, tree.src).children.clear();
+            inode(fs, tree.toml).children.push(tree.lib);
+            inode(fs, tree.lib).parent = tree.toml;
+        });
+        assert!(
+            matches!(found[..], [Inconsistency::ChildrenOnNonDirectory { ino }] if ino == tree.toml),
+            "{found:?}"
+        );
+    }
+
+    #[test]
+    fn duplicate_name() {
+        let (tree, found) =
+            check(|fs, tree| inode(fs, tree.src).name = OsString::from("Cargo.toml"));
+        assert!(
+            matches!(
+                &found[..],
+                [Inconsistency::DuplicateName { parent, name }]
+                    if *parent == tree.krate && name == "Cargo.toml"
+            ),
+            "{found:?}"
+        );
+    }
+
+    #[test]
+    fn link_count() {
+        let (tree, found) = check(|fs, tree| {
+            inode(fs, tree.toml).attrs.nlink = 2;
+            inode(fs, tree.src).attrs.nlink = 1;
+        });
+        let mut counts = found
+            .iter()
+            .map(|inconsistency| match *inconsistency {
+                Inconsistency::LinkCount {
+                    ino,
+                    nlink,
+                    expected,
+                } => (ino, nlink, expected),
+                _ => panic!("{found:?}"),
+            })
+            .collect::<Vec<_>>();
+        counts.sort();
+        let mut expected = vec![(tree.toml, 2, 1), (tree.src, 1, 2)];
+        expected.sort();
+        assert_eq!(counts, expected);
+    }
+
+    #[test]
+    fn blocks() {
+        let (tree, found) = check(|fs, tree| inode(fs, tree.toml).attrs.blocks += 1);
+        assert!(
+            matches!(
+                found[..],
+                [Inconsistency::Blocks { ino, size: 10, blocks: 2 }] if ino == tree.toml
+            ),
+            "{found:?}"
+        );
+    }
+}
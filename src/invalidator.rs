@@ -0,0 +1,349 @@
+//! Tells the kernel to drop what it caches of entries and inodes that
+//! changed, through notifications written to the FUSE connection.
+//!
+//! fuser 0.12 has no notifier of its own, nor a way to get at the
+//! connection it reads requests from; the kernel names the connection of
+//! each open `/dev/fuse` in its `fdinfo`, and that is the device of the
+//! mount.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::Write,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::MetadataExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+
+/// `fuse_notify_code` in the kernel's `fuse.h`.
+const FUSE_NOTIFY_INVAL_INODE: i32 = 2;
+const FUSE_NOTIFY_INVAL_ENTRY: i32 = 3;
+
+/// What the kernel is told to drop.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Notification {
+    /// The entry `name` in directory `parent`, and the dentries under it
+    /// if it is a directory.
+    Entry { parent: u64, name: OsString },
+    /// The attributes of `ino` and its cached data from `offset`, for
+    /// `len` bytes or to the end if `len` is 0.
+    Inode { ino: u64, offset: i64, len: i64 },
+}
+
+impl Notification {
+    /// The message written to `/dev/fuse`: a `fuse_out_header` with the
+    /// notification code where a reply has its error, then the
+    /// `fuse_notify_inval_*_out` of the notification.
+    pub fn encode(&self) -> Vec<u8> {
+        let (code, mut body) = match self {
+            Self::Entry { parent, name } => {
+                let mut body = parent.to_ne_bytes().to_vec();
+                body.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+                body.extend_from_slice(&0u32.to_ne_bytes());
+                body.extend_from_slice(name.as_bytes());
+                body.push(0);
+                (FUSE_NOTIFY_INVAL_ENTRY, body)
+            }
+            Self::Inode { ino, offset, len } => {
+                let mut body = ino.to_ne_bytes().to_vec();
+                body.extend_from_slice(&offset.to_ne_bytes());
+                body.extend_from_slice(&len.to_ne_bytes());
+                (FUSE_NOTIFY_INVAL_INODE, body)
+            }
+        };
+        let mut message = ((16 + body.len()) as u32).to_ne_bytes().to_vec();
+        message.extend_from_slice(&code.to_ne_bytes());
+        message.extend_from_slice(&0u64.to_ne_bytes());
+        message.append(&mut body);
+        message
+    }
+}
+
+/// Notifications waiting to be sent, each once however often it was
+/// asked for, in the order they were first asked for.
+#[derive(Default)]
+pub struct Queue {
+    order: VecDeque<Notification>,
+    queued: HashSet<Notification>,
+}
+
+impl Queue {
+    /// Whether `notification` was not queued already.
+    pub fn push(&mut self, notification: Notification) -> bool {
+        if !self.queued.insert(notification.clone()) {
+            return false;
+        }
+        self.order.push_back(notification);
+        true
+    }
+
+    /// Every queued notification, leaving the queue empty.
+    pub fn take(&mut self) -> Vec<Notification> {
+        self.queued.clear();
+        self.order.drain(..).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Sends kernel cache invalidations for a mounted filesystem.
+///
+/// Notifications are queued and written by a thread of their own, since
+/// one written while a request on the same directory is being answered
+/// waits for that answer. Those asked for while a notification is still
+/// queued are sent once, and those asked for before
+/// [`Invalidator::connect`] or after the filesystem is unmounted are
+/// dropped, as there is nothing the kernel caches then.
+#[derive(Clone, Default)]
+pub struct Invalidator(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    queued: Condvar,
+    /// Notifications queued while connected, sent yet or not.
+    asked: AtomicU64,
+    /// Notifications written to the connection.
+    sent: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    connection: Option<Arc<File>>,
+    queue: Queue,
+}
+
+impl Invalidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts sending notifications to the filesystem mounted at
+    /// `mountpoint` by this process, e.g. with [`fuser::spawn_mount2`].
+    pub fn connect(&self, mountpoint: &Path) -> Result<()> {
+        let dev = std::fs::metadata(mountpoint)
+            .with_context(|| format!("Reading {}", mountpoint.display()))?
+            .dev();
+        // The kernel's own encoding of the device, as `fdinfo` shows it.
+        let (major, minor) = unsafe { (libc::major(dev), libc::minor(dev)) };
+        let connection = (u64::from(major) << 20) | u64::from(minor);
+        let fd = find_connection(connection)?;
+        // Its own descriptor, so that it stays valid, if disconnected, for
+        // as long as it is written to after the session closes its own.
+        let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("Duplicating /dev/fuse");
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        self.0.state.lock().unwrap().connection = Some(Arc::new(file));
+        let shared = self.0.clone();
+        thread::Builder::new()
+            .name("fuse-crates-invalidator".to_owned())
+            .spawn(move || send(&shared))
+            .context("Spawning the invalidator")?;
+        Ok(())
+    }
+
+    /// Drops what is queued and stops sending, as when unmounted.
+    pub fn disconnect(&self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.connection = None;
+        state.queue.take();
+        self.0.queued.notify_all();
+    }
+
+    /// The entry `name` in directory `parent`, after it was removed or
+    /// replaced, or added where the kernel may remember it was missing.
+    pub fn invalidate_entry(&self, parent: u64, name: &OsStr) {
+        self.queue(Notification::Entry {
+            parent,
+            name: name.to_owned(),
+        });
+    }
+
+    /// The attributes of `ino` and its data from `offset`, for `len`
+    /// bytes or to the end if `len` is 0.
+    pub fn invalidate_inode(&self, ino: u64, offset: i64, len: i64) {
+        self.queue(Notification::Inode { ino, offset, len });
+    }
+
+    /// Everything below the entry `name` in `parent`, the inode `ino`: the
+    /// dentries under a directory go with its own, and `ino` itself is
+    /// invalidated for those holding it open.
+    pub fn invalidate_subtree(&self, parent: u64, name: &OsStr, ino: u64) {
+        self.invalidate_entry(parent, name);
+        self.invalidate_inode(ino, 0, 0);
+    }
+
+    /// How many notifications were queued while connected, however many
+    /// of them were written yet.
+    pub fn asked(&self) -> u64 {
+        self.0.asked.load(Ordering::Relaxed)
+    }
+
+    /// How many notifications were written to the connection.
+    pub fn sent(&self) -> u64 {
+        self.0.sent.load(Ordering::Relaxed)
+    }
+
+    fn queue(&self, notification: Notification) {
+        let mut state = self.0.state.lock().unwrap();
+        if state.connection.is_some() && state.queue.push(notification) {
+            self.0.asked.fetch_add(1, Ordering::Relaxed);
+            self.0.queued.notify_all();
+        }
+    }
+}
+
+/// The open `/dev/fuse` of this process whose `fdinfo` names `connection`,
+/// or the only one if the kernel names none.
+fn find_connection(connection: u64) -> Result<RawFd> {
+    let mut devices = vec![];
+    for entry in std::fs::read_dir("/proc/self/fd").context("Listing /proc/self/fd")? {
+        let Ok(fd) = entry?.file_name().to_string_lossy().parse::<RawFd>() else {
+            continue;
+        };
+        if std::fs::read_link(format!("/proc/self/fd/{fd}"))
+            .ok()
+            .as_deref()
+            != Some(Path::new("/dev/fuse"))
+        {
+            continue;
+        }
+        let info = std::fs::read_to_string(format!("/proc/self/fdinfo/{fd}")).unwrap_or_default();
+        let named = info.lines().find_map(|line| {
+            line.strip_prefix("fuse_connection:")?
+                .trim()
+                .parse::<u64>()
+                .ok()
+        });
+        match named {
+            Some(named) if named == connection => return Ok(fd),
+            Some(_) => {}
+            None => devices.push(fd),
+        }
+    }
+    match devices[..] {
+        [fd] => Ok(fd),
+        [] => bail!("No /dev/fuse open for connection {connection}"),
+        _ => bail!("No telling which /dev/fuse is connection {connection}"),
+    }
+}
+
+/// Writes what is queued until disconnected.
+fn send(shared: &Shared) {
+    loop {
+        let (connection, batch) = {
+            let mut state = shared.state.lock().unwrap();
+            while state.connection.is_some() && state.queue.is_empty() {
+                state = shared.queued.wait(state).unwrap();
+            }
+            let Some(connection) = state.connection.clone() else {
+                return;
+            };
+            (connection, state.queue.take())
+        };
+        for notification in batch {
+            match (&*connection).write(&notification.encode()) {
+                Ok(_) => {
+                    shared.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                // Nothing cached to drop.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                    shared.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENODEV | libc::ENOTCONN)) => {
+                    debug!("[invalidate] unmounted, dropping what is queued");
+                    let mut state = shared.state.lock().unwrap();
+                    state.connection = None;
+                    state.queue.take();
+                    return;
+                }
+                Err(e) => warn!("[invalidate] {notification:?}: {e}"),
+            }
+        }
+        debug!(
+            "[invalidate] fd {} {} sent",
+            connection.as_raw_fd(),
+            shared.sent.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(parent: u64, name: &str) -> Notification {
+        Notification::Entry {
+            parent,
+            name: name.into(),
+        }
+    }
+
+    #[test]
+    fn each_notification_is_queued_once_in_order() {
+        let mut queue = Queue::default();
+        assert!(queue.push(entry(1, "serde-1.0.193")));
+        assert!(queue.push(Notification::Inode {
+            ino: 7,
+            offset: 0,
+            len: 0
+        }));
+        assert!(!queue.push(entry(1, "serde-1.0.193")));
+        assert!(queue.push(entry(2, "serde-1.0.193")));
+        let taken = queue.take();
+        assert_eq!(taken.len(), 3);
+        assert_eq!(taken[0], entry(1, "serde-1.0.193"));
+        assert_eq!(taken[2], entry(2, "serde-1.0.193"));
+        assert!(queue.is_empty());
+        // Sent, so asked for again.
+        assert!(queue.push(entry(1, "serde-1.0.193")));
+    }
+
+    #[test]
+    fn messages_are_laid_out_as_the_kernel_reads_them() {
+        let message = entry(0x11, "abc").encode();
+        assert_eq!(message.len(), 16 + 16 + 4);
+        assert_eq!(message[..4], 36u32.to_ne_bytes());
+        assert_eq!(message[4..8], FUSE_NOTIFY_INVAL_ENTRY.to_ne_bytes());
+        assert_eq!(message[8..16], [0; 8]);
+        assert_eq!(message[16..24], 0x11u64.to_ne_bytes());
+        assert_eq!(message[24..28], 3u32.to_ne_bytes());
+        assert_eq!(&message[32..], b"abc\0");
+        let message = Notification::Inode {
+            ino: 5,
+            offset: 4096,
+            len: -1,
+        }
+        .encode();
+        assert_eq!(message.len(), 16 + 24);
+        assert_eq!(message[4..8], FUSE_NOTIFY_INVAL_INODE.to_ne_bytes());
+        assert_eq!(message[24..32], 4096i64.to_ne_bytes());
+        assert_eq!(message[32..], (-1i64).to_ne_bytes());
+    }
+
+    #[test]
+    fn nothing_is_queued_until_connected() {
+        let invalidator = Invalidator::new();
+        invalidator.invalidate_subtree(1, OsStr::new("serde-1.0.193"), 7);
+        assert!(invalidator.0.state.lock().unwrap().queue.is_empty());
+        assert_eq!(invalidator.asked(), 0);
+        invalidator.disconnect();
+        assert_eq!(invalidator.sent(), 0);
+    }
+}
@@ -0,0 +1,4225 @@
+//! Cargo's registry cache as a read-only FUSE filesystem: every `.crate`
+//! archive in the cache directory shows up as the directory it would be
+//! extracted to.
+//!
+//! [`CargoCacheFs`] is the filesystem, for mounting with [`fuser`]; [`run`]
+//! is the whole `fuse-crates` command. With the `test-fixtures` feature,
+//! `testing` builds archives to test it with.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs::{File, Permissions},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, MetadataExt, PermissionsExt},
+    },
+    panic,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use control::{ControlAction, ControlFile};
+use fuser::{FileAttr, FileType, Filesystem, MountOption};
+pub use invalidator::Invalidator;
+use libc::O_TRUNC;
+use log::{error, info, warn};
+use name_mapper::{NameContext, NameMapper};
+use rescan::ArchiveStamp;
+use scan::{EntryKind, Scan, ScannedEntry};
+use violation::Violation;
+pub use virtual_file::VirtualContent;
+use virtual_file::VirtualFile;
+
+mod access_log;
+mod archive;
+mod archive_cache;
+mod cli;
+mod content_cache;
+mod control;
+mod control_socket;
+mod filter;
+mod fsck;
+mod invalidator;
+mod name_mapper;
+mod overlay;
+mod preflight;
+mod rescan;
+mod scan;
+mod self_test;
+mod sha256;
+mod signal;
+mod source;
+mod spill;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod testing;
+mod throttle;
+mod verify;
+mod violation;
+mod virtual_file;
+mod worker;
+
+const BLKSIZE: u32 = 512;
+const SCRATCH_SIZE: usize = 64 * 1024;
+/// 0001-01-01T00:00:00Z
+const MIN_MTIME: i64 = -62_135_596_800;
+/// 9999-12-31T23:59:59Z
+const MAX_MTIME: i64 = 253_402_300_799;
+/// Extended attributes of every file in a crate, under `user.fuse_crates.`.
+const XATTRS: [&str; 4] = ["crate_name", "crate_version", "archive_path", "sha256"];
+const XATTR_PREFIX: &str = "user.fuse_crates.";
+/// Longest `--ttl` given to answers that can still change: listings of the
+/// cache directory and crates not populated yet.
+const VOLATILE_TTL: Duration = Duration::from_secs(1);
+
+/// What [`run`] leaves for the program to print and exit with.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Served until unmounted or signalled.
+    Unmounted,
+    /// `--help`: the usage.
+    Usage(String),
+    /// `--list-only`: the stems of the archives, sorted.
+    Archives(Vec<OsString>),
+    /// `--fsck`: what does not add up in the populated filesystem, if
+    /// anything.
+    Inconsistencies(Vec<String>),
+    /// `--strict`: the violations found, as JSON lines, for which nothing
+    /// was mounted.
+    Violations(Vec<String>),
+    /// A preflight check failed, as logged, with this exit code.
+    Refused(i32),
+    /// `--self-test-exit`: whether the self-test passed.
+    SelfTested(bool),
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Unmounted | Self::Usage(_) | Self::Archives(_) | Self::SelfTested(true) => 0,
+            Self::Inconsistencies(found) => i32::from(!found.is_empty()),
+            Self::Violations(_) | Self::SelfTested(false) => 1,
+            Self::Refused(code) => *code,
+        }
+    }
+}
+
+/// Runs `fuse-crates` with `args` (without the program name): mounts the
+/// cache directories or archives and serves them until unmounted or
+/// signalled.
+///
+/// Nothing is printed; what `--help`, `--list-only`, `--fsck` and
+/// `--strict` report is in the [`Outcome`], with the exit code.
+pub fn run<I: IntoIterator<Item = OsString>>(args: I) -> Result<Outcome> {
+    let options = cli::Options::parse(args)?;
+    if options.help {
+        return Ok(Outcome::Usage(cli::usage()));
+    }
+    let paths = match options.sources.is_empty() {
+        true => vec![cli::default_source()?],
+        false => options.sources.clone(),
+    };
+    let sources = source::resolve(&paths)?;
+    let mount_options = options.mount_options(&paths[0]);
+    log::debug!("[mount] {mount_options:?}");
+    if options.fsck {
+        let mut fs = CargoCacheFs::with_options(sources, &options, None, None)?;
+        if let Err(errno) = fs.populate() {
+            anyhow::bail!("Population failed with errno {errno}");
+        }
+        fs.populate_all_deferred();
+        let inconsistencies = fs.check_consistency();
+        info!("[fsck] {} inconsistencies found", inconsistencies.len());
+        let inconsistencies = inconsistencies.iter().map(ToString::to_string).collect();
+        return Ok(Outcome::Inconsistencies(inconsistencies));
+    }
+    if options.list_only {
+        let mut fs = CargoCacheFs::with_options(sources, &options, None, None)?;
+        let mut stems = fs
+            .list_archives()?
+            .into_iter()
+            .map(|(path, _)| archive::stem(&path).unwrap().to_owned())
+            .collect::<Vec<_>>();
+        stems.sort();
+        return Ok(Outcome::Archives(stems));
+    }
+    let Some(mountpoint) = options.mountpoint.as_deref() else {
+        anyhow::bail!("{} (see --help)", cli::USAGE);
+    };
+    let checked = paths.iter().map(PathBuf::as_path);
+    if let Err(e) = preflight::run(mountpoint, checked, &options.preflight) {
+        error!("{e}");
+        return Ok(Outcome::Refused(e.exit_code()));
+    }
+    // Before any thread is spawned, fork() only keeps the calling one.
+    if options.daemonize && unsafe { libc::daemon(1, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Daemonizing");
+    }
+    let access_log = options
+        .access_log
+        .as_deref()
+        .map(access_log::AccessLog::open)
+        .transpose()?;
+    let spill = options
+        .spill_dir
+        .clone()
+        .map(|dir| spill::SpillCache::new(dir, options.spill_size))
+        .transpose()
+        .context("Creating --spill-dir")?;
+    let mut fs = CargoCacheFs::with_options(sources.clone(), &options, access_log, spill)?;
+    if options.strict {
+        if let Err(errno) = fs.populate() {
+            anyhow::bail!("Population failed with errno {errno}");
+        }
+        fs.populate_all_deferred();
+        if !fs.violations.is_empty() {
+            error!(
+                "[strict] {} violations found, not mounting",
+                fs.violations.len()
+            );
+            let violations = fs.violations.iter().map(Violation::to_json).collect();
+            return Ok(Outcome::Violations(violations));
+        }
+    }
+    signal::install().context("Installing signal handlers")?;
+    let invalidator = fs.invalidator();
+    let mounted = match &options.control_socket {
+        Some(socket) => {
+            let fs = Arc::new(Mutex::new(fs));
+            control_socket::serve(socket, fs.clone())?;
+            fuser::spawn_mount2(control_socket::Shared(fs), mountpoint, &mount_options)
+        }
+        None => fuser::spawn_mount2(fs, mountpoint, &mount_options),
+    };
+    let session = mounted.with_context(|| {
+        let needs_fuse_conf = mount_options
+            .iter()
+            .any(|option| matches!(option, MountOption::AllowOther | MountOption::AllowRoot));
+        match needs_fuse_conf && unsafe { libc::geteuid() } != 0 {
+            true => format!(
+                "Mounting {} (allow_other and allow_root need user_allow_other in /etc/fuse.conf)",
+                mountpoint.display()
+            ),
+            false => format!("Mounting {}", mountpoint.display()),
+        }
+    })?;
+    if let Err(e) = invalidator.connect(mountpoint) {
+        warn!("[invalidate] {e:#}, the kernel caches refreshed crates until they expire");
+    }
+    if options.self_test {
+        let report = self_test::run(mountpoint, &sources, &options.filter)?;
+        for failure in &report.failures {
+            error!("[self-test] {failure}");
+        }
+        info!(
+            "[self-test] {} crates listed, {} files checked, {} failures",
+            report.crates_listed,
+            report.files_checked,
+            report.failures.len()
+        );
+        if options.self_test_exit {
+            unmount(session, mountpoint)?;
+            return Ok(Outcome::SelfTested(report.is_success()));
+        }
+    }
+    while !session.guard.is_finished() {
+        if let Some(signal) = signal::received() {
+            info!("[shutdown] {}, unmounting", signal::name(signal));
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    unmount(session, mountpoint)?;
+    Ok(Outcome::Unmounted)
+}
+
+/// Unmounts, unless the mount is already gone, and waits for the session
+/// to wind down.
+fn unmount(session: fuser::BackgroundSession, mountpoint: &Path) -> Result<()> {
+    let guard = {
+        let session = session;
+        session.guard
+    };
+    match guard.join() {
+        Ok(result) => result.context("Serving the filesystem")?,
+        Err(_) => anyhow::bail!("The session thread panicked"),
+    }
+    if preflight::is_fuse_mount(mountpoint).unwrap_or(false) {
+        anyhow::bail!("{} is still mounted", mountpoint.display());
+    }
+    Ok(())
+}
+
+/// Converts a raw tar mtime to a `SystemTime`, clamping it to
+/// [`MIN_MTIME`, `MAX_MTIME`].
+///
+/// GNU base-256 headers can carry negative times, which the tar crate hands
+/// back as the two's complement `u64`. Returns whether clamping was needed.
+fn system_time_from_tar(raw: u64) -> (SystemTime, bool) {
+    let seconds = raw as i64;
+    let clamped = seconds.clamp(MIN_MTIME, MAX_MTIME);
+    let time = if clamped >= 0 {
+        UNIX_EPOCH + Duration::from_secs(clamped as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(clamped.unsigned_abs())
+    };
+    (time, clamped != seconds)
+}
+
+/// Replies with the length of `value` when asked for it with a size of 0,
+/// as getxattr(2) and listxattr(2) do.
+fn reply_xattr(value: &[u8], size: u32, reply: fuser::ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32)
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE)
+    } else {
+        reply.data(value)
+    }
+}
+
+/// Orders versions by semver precedence: numeric components compared as
+/// numbers, a pre-release before its release, build metadata ignored.
+/// Anything that is not quite semver still gets a stable order.
+fn compare_versions(a: &OsStr, b: &OsStr) -> std::cmp::Ordering {
+    fn split(version: &OsStr) -> (Vec<&[u8]>, Option<Vec<&[u8]>>) {
+        let version = version.as_bytes();
+        let version = version.split(|&b| b == b'+').next().unwrap();
+        let (core, pre) = match version.iter().position(|&b| b == b'-') {
+            Some(dash) => (&version[..dash], Some(&version[dash + 1..])),
+            None => (version, None),
+        };
+        fn dotted(part: &[u8]) -> Vec<&[u8]> {
+            part.split(|&b| b == b'.').collect()
+        }
+        (dotted(core), pre.map(dotted))
+    }
+    fn compare_ids(a: &[&[u8]], b: &[&[u8]]) -> std::cmp::Ordering {
+        for (a, b) in a.iter().zip(b) {
+            let numeric = |id: &[u8]| {
+                std::str::from_utf8(id)
+                    .ok()
+                    .and_then(|id| id.parse::<u64>().ok())
+            };
+            // Numeric identifiers sort before alphanumeric ones.
+            let ordering = match (numeric(a), numeric(b)) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => a.cmp(b),
+            };
+            if ordering.is_ne() {
+                return ordering;
+            }
+        }
+        a.len().cmp(&b.len())
+    }
+    let (a_core, a_pre) = split(a);
+    let (b_core, b_pre) = split(b);
+    compare_ids(&a_core, &b_core).then_with(|| match (a_pre, b_pre) {
+        (None, None) => std::cmp::Ordering::Equal,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (Some(a), Some(b)) => compare_ids(&a, &b),
+    })
+}
+
+/// Splits the version off a `name-version` stem, e.g. `tokio-util` from
+/// `tokio-util-0.7.8`. Stems without a version are returned unchanged.
+fn crate_name_of(stem: &OsStr) -> &OsStr {
+    let bytes = stem.as_encoded_bytes();
+    let split = bytes
+        .windows(2)
+        .rposition(|pair| pair[0] == b'-' && pair[1].is_ascii_digit());
+    match split {
+        // SAFETY: the split is right before an ASCII `-`.
+        Some(split) => unsafe { OsStr::from_encoded_bytes_unchecked(&bytes[..split]) },
+        None => stem,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct AttrPolicy {
+    uid: u32,
+    gid: u32,
+    file_mode: u16,
+    dir_mode: u16,
+}
+
+impl AttrPolicy {
+    /// Upper bound for the mode overrides: read and execute, never write.
+    const MAX_MODE: u16 = 0o555;
+}
+
+impl Default for AttrPolicy {
+    fn default() -> Self {
+        Self {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            file_mode: Self::MAX_MODE,
+            dir_mode: Self::MAX_MODE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+enum PrefixPolicy {
+    /// Use the paths stored in the archive as they are.
+    #[default]
+    TrustArchive,
+    /// Replace the top-level directory with the `.crate` file stem.
+    TrustFilename,
+    /// Skip the crate entirely.
+    Reject,
+}
+
+impl std::str::FromStr for PrefixPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "trust-archive" => Ok(Self::TrustArchive),
+            "trust-filename" => Ok(Self::TrustFilename),
+            "reject" => Ok(Self::Reject),
+            _ => anyhow::bail!("Unknown prefix policy {s}"),
+        }
+    }
+}
+
+/// Which crates `--populate-budget` spends its time on first.
+#[derive(Clone, Debug, Default)]
+enum PopulateOrder {
+    /// Most recently modified `.crate` files first.
+    #[default]
+    Mtime,
+    /// Smallest `.crate` files first, so that the most crates fit.
+    SizeAscending,
+    /// The listed stems in order, then the rest by mtime.
+    Prefetch(Vec<OsString>),
+}
+
+impl std::str::FromStr for PopulateOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mtime" => Ok(Self::Mtime),
+            "size" => Ok(Self::SizeAscending),
+            _ => anyhow::bail!("Unknown populate order {s} (use --prefetch for a list)"),
+        }
+    }
+}
+
+impl PopulateOrder {
+    fn sort(&self, crates: &mut [(PathBuf, std::fs::Metadata)]) {
+        let newest_first = |metadata: &std::fs::Metadata| {
+            std::cmp::Reverse(metadata.modified().unwrap_or(UNIX_EPOCH))
+        };
+        match self {
+            Self::Mtime => crates.sort_by_key(|(_, metadata)| newest_first(metadata)),
+            Self::SizeAscending => crates.sort_by_key(|(_, metadata)| metadata.len()),
+            Self::Prefetch(stems) => crates.sort_by_key(|(path, metadata)| {
+                let listed = archive::stem(path)
+                    .and_then(|stem| stems.iter().position(|listed| listed == stem));
+                (listed.unwrap_or(usize::MAX), newest_first(metadata))
+            }),
+        }
+    }
+}
+
+struct Inode {
+    attrs: FileAttr,
+    children: Vec<u64>,
+    /// The last component of the path; the rest is found through `parent`.
+    name: OsString,
+    parent: u64,
+    /// Shared by every inode of the crate.
+    krate_path: Option<Arc<Path>>,
+    entry_index: usize,
+    link_target: Option<PathBuf>,
+    /// The extracted copy in `--src-dir`, read instead of the archive.
+    disk_path: Option<PathBuf>,
+    /// The copy in `--overlay`, read instead of both.
+    overlay_path: Option<PathBuf>,
+}
+
+/// State of one `open`/`opendir`, until the matching release.
+struct Handle {
+    ino: u64,
+    /// `--max-read-bps-per-handle` limiter.
+    throttle: Option<Arc<throttle::RateLimiter>>,
+    cursor: Arc<Mutex<Option<worker::Cursor>>>,
+    /// The entry of an archive the open file reads.
+    pinned: Option<Pinned>,
+    /// What a control file held when it was opened.
+    control: Option<Arc<[u8]>>,
+    /// Opened with `O_APPEND`.
+    append: bool,
+    /// The uid of each process that read through the handle and the bytes
+    /// it was served, added to `access-summary.json` and logged with
+    /// `--access-log` on release. One record per process and open file,
+    /// when it is closed rather than at its first read, is what a build
+    /// reading each file once makes anyway, and is the first time the bytes
+    /// read are known; it also needs no set of every process and file seen,
+    /// which would grow for as long as the filesystem is mounted.
+    reads: HashMap<u32, (u32, Arc<AtomicU64>)>,
+}
+
+/// What an open file of a crate reads, as it was when it was opened.
+///
+/// A crate whose archive is replaced while mounted gets a new directory
+/// and new inodes with [`CargoCacheFs::refresh`], which new lookups find;
+/// a handle opened before keeps reading the old archive through the one it
+/// opened with it, however long it stays open, and what is cached of that
+/// goes by the inode of the old directory; one reading an extracted copy
+/// in `--src-dir` rather than the archive keeps reading that, and its
+/// attributes are those of the file it reads. Handles of a crate that is
+/// gone and those of files dropped from one they no longer find fail with
+/// `ESTALE`.
+struct Pinned {
+    krate_path: Arc<Path>,
+    /// The inode of the crate's directory.
+    krate: u64,
+    entry_index: usize,
+    /// Those of the file when it was opened.
+    attrs: FileAttr,
+    disk_path: Option<PathBuf>,
+    archive: Arc<Mutex<Option<File>>>,
+}
+
+impl Handle {
+    /// The count of the bytes served to the process of `req` through the
+    /// handle.
+    fn served(&mut self, req: &fuser::Request<'_>) -> Arc<AtomicU64> {
+        let (_, served) = self
+            .reads
+            .entry(req.pid())
+            .or_insert((req.uid(), Arc::default()));
+        served.clone()
+    }
+}
+
+/// The read of `size` bytes at `offset` of what `handle` pinned.
+fn read_job(
+    handle: &Handle,
+    served: Arc<AtomicU64>,
+    offset: i64,
+    size: u32,
+    reply: fuser::ReplyData,
+) -> worker::ReadJob {
+    let pinned = handle.pinned.as_ref().unwrap();
+    worker::ReadJob {
+        ino: handle.ino,
+        krate_path: pinned.krate_path.to_path_buf(),
+        krate: pinned.krate,
+        entry_index: pinned.entry_index,
+        disk_path: pinned.disk_path.clone(),
+        file_size: pinned.attrs.size,
+        offset,
+        size,
+        reply,
+        served,
+        throttle: handle.throttle.clone(),
+        cursor: handle.cursor.clone(),
+        archive: pinned.archive.clone(),
+    }
+}
+
+/// What a generated file holds.
+enum VirtualSource {
+    Control(ControlFile),
+    Action(ControlAction),
+    Custom(Box<dyn VirtualContent>),
+}
+
+/// What [`CargoCacheFs::add_virtual`] adds.
+enum VirtualNode {
+    Dir,
+    File(VirtualSource),
+    Symlink(PathBuf),
+}
+
+/// The filesystem of a cache directory, of a single archive, or of several
+/// of either, each under a directory of its own.
+///
+/// Crates are found when the filesystem is mounted and populated the first
+/// time they are accessed, and archives added to, removed from or replaced
+/// in the directories are picked up while mounted. Mount it read-only, e.g.
+/// with [`MountOption::RO`]:
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let fs = fuse_crates::CargoCacheFs::builder("/home/me/.cargo/registry/cache/index.crates.io-6f17d22bba15001f")
+///     .ttl(std::time::Duration::from_secs(60))
+///     .build()?;
+/// fuser::mount2(fs, "/mnt/crates", &[fuser::MountOption::RO])?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CargoCacheFs {
+    sources: Vec<source::Source>,
+    inodes: BTreeMap<u64, Inode>,
+    next_inode: u64,
+    attr_policy: AttrPolicy,
+    workers: worker::WorkerPool,
+    access_log: Option<access_log::AccessLog>,
+    /// What the handles released so far read, by crate.
+    access_summary: BTreeMap<String, control::CrateAccess>,
+    /// `--prefix-policy`, `--src-dir` and `--verify`, shared with the
+    /// threads scanning archives.
+    scan_config: Arc<scan::Config>,
+    shard_levels: usize,
+    group_by_name: bool,
+    /// Crates under shard or name directories, by the directory of their
+    /// source and their stem, which is also looked up there.
+    crate_aliases: HashMap<(u64, OsString), u64>,
+    populate_budget: Option<Duration>,
+    populate_order: PopulateOrder,
+    /// Threads scanning archives during `populate`.
+    jobs: usize,
+    /// Crate directories left for on-demand population, with their parent.
+    deferred: HashMap<u64, (PathBuf, u64)>,
+    /// `--max-resident-crates`.
+    max_resident_crates: Option<usize>,
+    /// Populated crate directories that can be deferred again, with their
+    /// archive, parent and when they were last used.
+    resident: HashMap<u64, (PathBuf, u64, u64)>,
+    resident_tick: u64,
+    /// How many entries the kernel was given for each inode and has not
+    /// forgotten yet.
+    lookups: HashMap<u64, u64>,
+    /// Every crate directory, with its parent, by archive.
+    crate_dirs: HashMap<PathBuf, (u64, u64)>,
+    /// What each archive was when it was listed, to tell one that was
+    /// replaced since.
+    archive_stamps: HashMap<PathBuf, ArchiveStamp>,
+    /// What the last rescans changed, for `last-rescan.json`.
+    rescans: rescan::History,
+    /// Directories between the root and the crates, with `--shard-root`.
+    shard_dirs: HashSet<u64>,
+    /// `--ttl`.
+    ttl: Duration,
+    filter: filter::CrateFilter,
+    /// What is served for crates that failed `--verify`.
+    verification_reports: HashMap<u64, Vec<u8>>,
+    /// `/.fuse-crates`, 0 until populated.
+    control_dir: u64,
+    /// What generated files hold.
+    virtual_files: HashMap<u64, (VirtualSource, VirtualFile)>,
+    /// Generated entries by the path in the mount of the directory they
+    /// are in, be it a crate's or a generated one, which has no children
+    /// of its own.
+    virtual_entries: HashMap<PathBuf, Vec<u64>>,
+    virtual_inodes: HashSet<u64>,
+    /// Unset by `--no-control-dir`: the root then has none of the
+    /// generated entries, whatever they are.
+    generated_root: bool,
+    /// Generated entries hidden by an entry of a crate with the same name,
+    /// which were warned about.
+    shadowed: HashSet<u64>,
+    /// Changes whenever a crate directory may have come or gone, for the
+    /// `crates` control file.
+    crates_version: u64,
+    /// When the sources last changed before the filesystem was created:
+    /// the mtime of generated files, which must not depend on when they
+    /// were generated so that two mounts of the same sources are alike.
+    generated_mtime: SystemTime,
+    show_control_dir: bool,
+    /// `--icase`.
+    icase: bool,
+    violations: Vec<Violation>,
+    name_mapper: Box<dyn NameMapper>,
+    /// SHA-256 of archives, from `--verify` or the first `getxattr` asking
+    /// for it.
+    archive_digests: HashMap<PathBuf, String>,
+    shutdown_timeout: Duration,
+    max_read_bps_per_handle: Option<u64>,
+    /// What the limiters of released handles waited.
+    released_throttle_waited: Duration,
+    /// `--overlay`, without which the filesystem is read-only.
+    overlay: Option<overlay::Overlay>,
+    handles: HashMap<u64, Handle>,
+    /// Tells the kernel what to forget of crates refreshed while mounted.
+    invalidator: Invalidator,
+    next_fh: u64,
+}
+
+/// Options of a [`CargoCacheFs`], from [`CargoCacheFs::builder`]. Unset
+/// options default to those of the `fuse-crates` command.
+pub struct Builder {
+    sources: Vec<PathBuf>,
+    options: cli::Options,
+    virtual_entries: Vec<(PathBuf, VirtualNode)>,
+}
+
+impl Builder {
+    /// How long the kernel may cache names and attributes.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.options.ttl = ttl;
+        self
+    }
+
+    /// The owner of every file and directory.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.options.attr_policy.uid = uid;
+        self
+    }
+
+    /// The group of every file and directory.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.options.attr_policy.gid = gid;
+        self
+    }
+
+    /// Puts versions under a directory named after the crate
+    /// (`serde/1.0.193`) instead of side by side (`serde-1.0.193`).
+    pub fn group_by_name(mut self, group_by_name: bool) -> Self {
+        self.options.group_by_name = group_by_name;
+        self
+    }
+
+    /// Populates every crate when mounted instead of on first access.
+    pub fn eager(mut self, eager: bool) -> Self {
+        self.options.populate_budget = (!eager).then_some(Duration::ZERO);
+        self
+    }
+
+    /// Also mounts `path`, a cache directory or an archive. With several,
+    /// each is put under a directory named after it.
+    pub fn source<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.sources.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a file at `path` from the root with the bytes of `content`,
+    /// and the directories on the way to it that no source has. A crate
+    /// entry with the same path hides it.
+    pub fn add_virtual_file<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        content: impl VirtualContent + 'static,
+    ) -> Self {
+        let source = VirtualSource::Custom(Box::new(content));
+        self.virtual_entries
+            .push((path.as_ref().to_path_buf(), VirtualNode::File(source)));
+        self
+    }
+
+    /// Adds a symbolic link to `target` as [`Self::add_virtual_file`] adds
+    /// a file.
+    pub fn add_virtual_symlink<P: AsRef<Path>, T: AsRef<Path>>(
+        mut self,
+        path: P,
+        target: T,
+    ) -> Self {
+        let target = target.as_ref().to_path_buf();
+        self.virtual_entries
+            .push((path.as_ref().to_path_buf(), VirtualNode::Symlink(target)));
+        self
+    }
+
+    /// Where the control directory is in the root, `.fuse-crates` by
+    /// default. `None` leaves the root to the crates, without the entries
+    /// added there with [`Self::add_virtual_file`] either.
+    pub fn control_dir(mut self, name: Option<&str>) -> Self {
+        self.options.control_dir = name.map(str::to_owned);
+        self
+    }
+
+    /// Adds `drop-caches`, `refresh` and `log-level` to the control
+    /// directory, which act when written to; mount read-write for them.
+    pub fn control_writes(mut self, writes: bool) -> Self {
+        self.options.control_writes = writes;
+        self
+    }
+
+    /// Fails if a path is neither a directory nor an archive, or is given
+    /// twice, and if an added entry has no name, is added twice or would be
+    /// under a file.
+    pub fn build(self) -> Result<CargoCacheFs> {
+        let sources = source::resolve(&self.sources)?;
+        let mut fs = CargoCacheFs::with_options(sources, &self.options, None, None)?;
+        for (path, node) in self.virtual_entries {
+            fs.add_virtual(&path, node)?;
+        }
+        Ok(fs)
+    }
+}
+
+impl CargoCacheFs {
+    /// The filesystem of `path`, a cache directory or an archive, with the
+    /// default options.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::builder(path).build()
+    }
+
+    pub fn builder<P: AsRef<Path>>(path: P) -> Builder {
+        Builder {
+            sources: vec![path.as_ref().to_path_buf()],
+            options: cli::Options::default(),
+            virtual_entries: vec![],
+        }
+    }
+
+    fn with_options(
+        sources: Vec<source::Source>,
+        options: &cli::Options,
+        access_log: Option<access_log::AccessLog>,
+        spill: Option<spill::SpillCache>,
+    ) -> Result<Self> {
+        // Extracted crates are looked for next to a lone source only.
+        let cache_dir = match &sources[..] {
+            [source] if source.is_archive => source.path.parent(),
+            [source] => Some(source.path.as_path()),
+            _ => None,
+        };
+        let src_dir = match options.use_src_dir {
+            true => options
+                .src_dir
+                .clone()
+                .or_else(|| cache_dir.and_then(cli::default_src_dir)),
+            false => None,
+        };
+        let verifier = match options.verify {
+            true => Some(verify::Verifier::new(
+                options.checksums.as_deref(),
+                src_dir.clone(),
+                options.verify_cache.clone().or_else(verify::default_memo),
+            )?),
+            false => None,
+        };
+        let overlay = options
+            .overlay
+            .clone()
+            .map(overlay::Overlay::new)
+            .transpose()
+            .context("Creating --overlay")?;
+        let generated_mtime = sources
+            .iter()
+            .filter_map(|source| std::fs::metadata(&source.path).ok()?.modified().ok())
+            .max()
+            .unwrap_or(UNIX_EPOCH);
+        let mut fs = Self {
+            sources,
+            inodes: BTreeMap::new(),
+            next_inode: fuser::FUSE_ROOT_ID + 1,
+            attr_policy: options.attr_policy,
+            workers: worker::WorkerPool::new(
+                options.io_threads,
+                archive_cache::ArchiveCache::new(options.max_open_archives),
+                options.verify_reads,
+                options.content_cache_size,
+                spill,
+                options.max_read_bps,
+            ),
+            access_log,
+            access_summary: BTreeMap::new(),
+            scan_config: Arc::new(scan::Config {
+                prefix_policy: options.prefix_policy,
+                src_dir,
+                verifier,
+            }),
+            shard_levels: options.shard_levels,
+            group_by_name: options.group_by_name,
+            crate_aliases: HashMap::new(),
+            populate_budget: options.populate_budget,
+            populate_order: options.populate_order.clone(),
+            jobs: options.jobs,
+            deferred: HashMap::new(),
+            max_resident_crates: options.max_resident_crates,
+            resident: HashMap::new(),
+            resident_tick: 0,
+            lookups: HashMap::new(),
+            crate_dirs: HashMap::new(),
+            archive_stamps: HashMap::new(),
+            rescans: rescan::History::default(),
+            shard_dirs: HashSet::new(),
+            ttl: options.ttl,
+            filter: options.filter.clone(),
+            verification_reports: HashMap::new(),
+            control_dir: 0,
+            virtual_files: HashMap::new(),
+            virtual_entries: HashMap::new(),
+            virtual_inodes: HashSet::new(),
+            generated_root: options.control_dir.is_some(),
+            shadowed: HashSet::new(),
+            crates_version: 0,
+            generated_mtime,
+            show_control_dir: options.show_control_dir,
+            icase: options.icase,
+            violations: vec![],
+            name_mapper: options.name_mapping.mapper(),
+            archive_digests: HashMap::new(),
+            shutdown_timeout: options.shutdown_timeout,
+            max_read_bps_per_handle: options.max_read_bps_per_handle,
+            released_throttle_waited: Duration::ZERO,
+            overlay,
+            handles: HashMap::new(),
+            invalidator: Invalidator::new(),
+            next_fh: 1,
+        };
+        let control_dir = options.control_dir.as_deref().unwrap_or(control::DIR);
+        fs.add_control_dir(control_dir, options.control_writes)?;
+        Ok(fs)
+    }
+
+    fn open_handle(&mut self, ino: u64, throttle: Option<Arc<throttle::RateLimiter>>) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(
+            fh,
+            Handle {
+                ino,
+                throttle,
+                cursor: Arc::default(),
+                pinned: None,
+                control: None,
+                append: false,
+                reads: HashMap::new(),
+            },
+        );
+        fh
+    }
+
+    /// Whether `pinned` is of the crate as it is listed now.
+    fn is_current(&self, pinned: &Pinned) -> bool {
+        self.crate_dirs
+            .get(&*pinned.krate_path)
+            .is_some_and(|&(_, krate)| krate == pinned.krate)
+    }
+
+    /// Pins the crate file open through `fh` to the archive it is in now.
+    fn pin(&mut self, fh: u64) {
+        let ino = self.handles[&fh].ino;
+        let inode = &self.inodes[&ino];
+        let Some(krate_path) = inode
+            .krate_path
+            .clone()
+            .filter(|_| inode.attrs.kind == FileType::RegularFile)
+        else {
+            return;
+        };
+        let Some(&(_, krate)) = self.crate_dirs.get(&*krate_path) else {
+            return;
+        };
+        // Opened now rather than at the first read, so that the handle
+        // reads what it was opened on whatever happens to the archive.
+        let archive = match inode.disk_path {
+            Some(_) => None,
+            None => match self.workers.checkout(&krate_path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    let archive = krate_path.to_string_lossy();
+                    warn!("[open] ino 0x{ino:016x} {archive}: {e}");
+                    None
+                }
+            },
+        };
+        self.handles.get_mut(&fh).unwrap().pinned = Some(Pinned {
+            krate_path,
+            krate,
+            entry_index: inode.entry_index,
+            attrs: inode.attrs,
+            disk_path: inode.disk_path.clone(),
+            archive: Arc::new(Mutex::new(archive)),
+        });
+    }
+
+    /// What `getattr` answers for `ino`, through the open file `fh` when
+    /// the kernel names one: a handle whose archive was replaced since it
+    /// was opened has the attributes of the file it still reads, as `fstat`
+    /// does for a file replaced on any other filesystem.
+    fn attrs_for(&self, ino: u64, fh: Option<u64>) -> Option<FileAttr> {
+        let handle = fh
+            .and_then(|fh| self.handles.get(&fh))
+            .filter(|handle| handle.ino == ino);
+        match handle.and_then(|handle| self.replaced_pin(handle)) {
+            Some(pinned) => Some(pinned.attrs),
+            None => self.attrs(ino),
+        }
+    }
+
+    /// What `handle` reads if the archive it was opened on was replaced
+    /// since.
+    fn replaced_pin<'a>(&self, handle: &'a Handle) -> Option<&'a Pinned> {
+        handle.pinned.as_ref().filter(|pinned| {
+            !self.is_current(pinned) && self.crate_dirs.contains_key(&*pinned.krate_path)
+        })
+    }
+
+    /// The crate `ino` is in, as named in access records.
+    fn accessed_crate(&self, ino: u64) -> Option<String> {
+        let inode = self.inodes.get(&ino)?;
+        let stem = archive::stem(inode.krate_path.as_deref()?)?;
+        Some(stem.to_string_lossy().into_owned())
+    }
+
+    /// Adds what was read through `handle` to `access-summary.json` and
+    /// writes an `--access-log` record for each process that read through
+    /// it.
+    fn log_reads(&mut self, handle: Handle) {
+        let Some(krate) = self.accessed_crate(handle.ino) else {
+            return;
+        };
+        let summary = self.access_summary.entry(krate.clone()).or_default();
+        for (_, served) in handle.reads.values() {
+            summary.reads += 1;
+            summary.bytes += served.load(Ordering::Relaxed);
+        }
+        let Some(access_log) = &self.access_log else {
+            return;
+        };
+        let path = self
+            .path_in_crate(handle.ino)
+            .map(|(_, path)| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for (pid, (uid, served)) in handle.reads {
+            let record = access_log::AccessRecord {
+                uid,
+                pid,
+                krate: krate.clone(),
+                path: path.clone(),
+            };
+            access_log.record(record, served.load(Ordering::Relaxed));
+        }
+    }
+
+    /// `access-summary.json`: what [`Self::log_reads`] added up, and what
+    /// the handles still open have read so far.
+    fn access_summary(&self) -> BTreeMap<String, control::CrateAccess> {
+        let mut summary = self.access_summary.clone();
+        for handle in self
+            .handles
+            .values()
+            .filter(|handle| !handle.reads.is_empty())
+        {
+            let Some(krate) = self.accessed_crate(handle.ino) else {
+                continue;
+            };
+            let access = summary.entry(krate).or_default();
+            for (_, served) in handle.reads.values() {
+                access.reads += 1;
+                access.bytes += served.load(Ordering::Relaxed);
+            }
+        }
+        summary
+    }
+
+    fn dir_attr(&self, ino: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            perm: Self::DIR_ATTR_TEMPLATE.perm & self.attr_policy.dir_mode,
+            uid: self.attr_policy.uid,
+            gid: self.attr_policy.gid,
+            ..Self::DIR_ATTR_TEMPLATE
+        }
+    }
+
+    fn file_attr(&self, ino: u64, size: u64, mtime: SystemTime) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(BLKSIZE.into()),
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            perm: Self::FIL_ATTR_TEMPLATE.perm & self.attr_policy.file_mode,
+            uid: self.attr_policy.uid,
+            gid: self.attr_policy.gid,
+            ..Self::FIL_ATTR_TEMPLATE
+        }
+    }
+
+    /// Inode numbers are never reused, so one the kernel still holds after
+    /// its crate was evicted finds nothing rather than another file.
+    fn next_inode(&mut self) -> Result<u64> {
+        let ret = self.next_inode;
+        self.next_inode = ret
+            .checked_add(1)
+            .context("Inode numbers exhausted, refusing to wrap around")?;
+
+        Ok(ret)
+    }
+
+    fn insert_inode(&mut self, ino: u64, inode: Inode) {
+        if let Some(previous) = self.inodes.insert(ino, inode) {
+            let inode = &self.inodes[&ino];
+            debug_assert_eq!(
+                (previous.parent, &previous.name),
+                (inode.parent, &inode.name),
+                "inode 0x{ino:016x} was already mapped to a different path"
+            );
+        }
+    }
+
+    /// Puts what [`scan::scan_crate`] found in the inode table.
+    fn apply_scan(
+        &mut self,
+        crate_file_path: &Path,
+        scan: Scan,
+        parent: u64,
+        crate_inode: u64,
+    ) -> Result<()> {
+        for violation in scan.violations {
+            self.violation(violation);
+        }
+        if scan.rejected {
+            self.remove_subtree(parent, crate_inode);
+            return Ok(());
+        }
+        if let Some(digest) = scan.digest {
+            self.archive_digests
+                .insert(crate_file_path.to_path_buf(), digest);
+        }
+        if let Some(problem) = scan.verification_failed {
+            return self.add_verification_report(crate_file_path, crate_inode, problem);
+        }
+        let crate_name = archive::stem(crate_file_path).unwrap();
+        let krate = crate_name.to_string_lossy();
+        let archive = Arc::<Path>::from(crate_file_path);
+        // Hard links name their target by its path in the archive.
+        let mut by_archive_path = HashMap::new();
+        // Every name of a linked entry, by the entry's index in the archive.
+        let mut links = HashMap::<usize, Vec<u64>>::new();
+        for entry in scan.entries {
+            let ScannedEntry {
+                entry_index,
+                archive_path,
+                path: entry_path,
+                kind,
+                mtime,
+                mode,
+            } = entry;
+            let is_dir = matches!(kind, EntryKind::Directory);
+            let mut components = entry_path
+                .components()
+                .map(|component| component.as_os_str())
+                .collect::<Vec<_>>();
+            // Entries outside the crate directory are kept under it, with
+            // their archive path, so no archive reaches into another crate.
+            let mut last_inode = crate_inode;
+            if components.first() == Some(&crate_name) && (is_dir || components.len() > 1) {
+                components.remove(0);
+            }
+            // Directory entries only make sure the directory exists.
+            let name = match is_dir {
+                true => None,
+                false => match components.pop() {
+                    Some(name) => Some(name),
+                    None => continue,
+                },
+            };
+            let duplicate = |fs: &mut Self| {
+                fs.violation(Violation::DuplicateEntry {
+                    krate: krate.clone().into_owned(),
+                    path: entry_path.to_string_lossy().into_owned(),
+                })
+            };
+            // As when extracting, a later entry replaces an earlier one,
+            // unless that was a directory and this is not.
+            let mut replace = |fs: &mut Self, parent: u64, existing: u64| {
+                by_archive_path.retain(|_, ino| *ino != existing);
+                if let Some(names) = links.get_mut(&fs.inodes[&existing].entry_index) {
+                    names.retain(|ino| *ino != existing);
+                    fs.set_nlink(names);
+                }
+                fs.remove_subtree(parent, existing);
+            };
+            for component in components {
+                if let Some(existing) = self.child_named(last_inode, component) {
+                    if self.inodes[&existing].attrs.kind != FileType::Directory {
+                        duplicate(self);
+                        replace(self, last_inode, existing);
+                    }
+                }
+                last_inode = self.child_dir(last_inode, component)?;
+            }
+            let Some(name) = name else { continue };
+            if let Some(existing) = self.child_named(last_inode, name) {
+                duplicate(self);
+                if self.inodes[&existing].attrs.kind == FileType::Directory {
+                    continue;
+                }
+                replace(self, last_inode, existing);
+            }
+            let (file_size, link_target, disk_path) = match kind {
+                EntryKind::Directory => continue,
+                EntryKind::HardLink(target) => {
+                    let Some(&target_inode) = by_archive_path.get(&target) else {
+                        self.violation(Violation::DanglingLink {
+                            krate: krate.clone().into_owned(),
+                            path: entry_path.to_string_lossy().into_owned(),
+                            target: target.to_string_lossy().into_owned(),
+                        });
+                        continue;
+                    };
+                    let new_inode = self.next_inode()?;
+                    self.link(target_inode, new_inode, last_inode, name, &mut links);
+                    continue;
+                }
+                EntryKind::File {
+                    size, disk_path, ..
+                } => (size, None, disk_path),
+                EntryKind::Symlink(target) => (0, Some(target), None),
+            };
+            let new_inode = self.next_inode()?;
+            let mut attrs = self.file_attr(new_inode, file_size, mtime);
+            // Keep the archive's execute bits, but never its write bits.
+            if let Some(mode) = mode {
+                attrs.perm = mode as u16 & AttrPolicy::MAX_MODE & self.attr_policy.file_mode;
+            }
+            if let Some(target) = &link_target {
+                attrs.kind = FileType::Symlink;
+                attrs.size = target.as_os_str().len() as u64;
+                attrs.blocks = 0;
+            }
+            let new_inode_object = Inode {
+                attrs,
+                children: vec![],
+                name: name.to_os_string(),
+                parent: last_inode,
+                krate_path: Some(archive.clone()),
+                entry_index,
+                link_target,
+                disk_path,
+                overlay_path: None,
+            };
+            self.insert_inode(new_inode, new_inode_object);
+            self.inodes
+                .get_mut(&last_inode)
+                .unwrap()
+                .children
+                .push(new_inode);
+            by_archive_path.insert(archive_path, new_inode);
+        }
+        self.apply_overlay(crate_file_path, crate_inode)?;
+        self.inherit_mtimes(crate_inode);
+        self.resident_tick += 1;
+        self.resident.insert(
+            crate_inode,
+            (crate_file_path.to_path_buf(), parent, self.resident_tick),
+        );
+        Ok(())
+    }
+
+    /// Creates the control directory, `/.fuse-crates` unless named
+    /// otherwise, and its files, with those written to for `--control-writes`
+    /// if `writes`. Listings of the root only show it with
+    /// `--show-control-dir`.
+    fn add_control_dir(&mut self, name: &str, writes: bool) -> Result<()> {
+        self.control_dir = self.add_virtual(Path::new(name), VirtualNode::Dir)?;
+        let files = ControlFile::ALL.map(VirtualSource::Control);
+        let actions = ControlAction::ALL.map(VirtualSource::Action);
+        for source in files
+            .into_iter()
+            .chain(actions.into_iter().filter(|_| writes))
+        {
+            let name = Path::new(name).join(match &source {
+                VirtualSource::Control(file) => file.name(),
+                VirtualSource::Action(action) => action.name(),
+                VirtualSource::Custom(_) => unreachable!(),
+            });
+            self.add_virtual(&name, VirtualNode::File(source))?;
+        }
+        Ok(())
+    }
+
+    /// Adds a generated entry at `path` in the mount, along with the
+    /// generated directories on the way to it that are not there yet.
+    /// Lookups and listings consult generated entries after those of the
+    /// crates, whose entry wins if it has the same name.
+    fn add_virtual(&mut self, path: &Path, node: VirtualNode) -> Result<u64> {
+        let mut names = vec![];
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                _ => anyhow::bail!("{} is not a path from the root", path.display()),
+            }
+        }
+        let Some((name, dirs)) = names.split_last() else {
+            anyhow::bail!("A generated entry needs a name");
+        };
+        let mut dir = PathBuf::new();
+        let mut parent = fuser::FUSE_ROOT_ID;
+        for &component in dirs {
+            parent = match self.virtual_child(&dir, component) {
+                Some(ino) if self.inodes[&ino].attrs.kind == FileType::Directory => ino,
+                Some(_) => anyhow::bail!("{} is a generated file", dir.join(component).display()),
+                None => self.insert_virtual(&dir, component, parent, VirtualNode::Dir)?,
+            };
+            dir.push(component);
+        }
+        if self.virtual_child(&dir, name).is_some() {
+            anyhow::bail!("{} is generated already", path.display());
+        }
+        self.insert_virtual(&dir, name, parent, node)
+    }
+
+    fn virtual_child(&self, dir: &Path, name: &OsStr) -> Option<u64> {
+        self.virtual_entries
+            .get(dir)?
+            .iter()
+            .copied()
+            .find(|ino| self.inodes[ino].name == name)
+    }
+
+    fn insert_virtual(
+        &mut self,
+        dir: &Path,
+        name: &OsStr,
+        parent: u64,
+        node: VirtualNode,
+    ) -> Result<u64> {
+        let ino = self.next_inode()?;
+        let (attrs, link_target) = match &node {
+            VirtualNode::Dir => (self.dir_attr(ino), None),
+            VirtualNode::File(_) => (self.file_attr(ino, 0, self.generated_mtime), None),
+            VirtualNode::Symlink(target) => {
+                let mut attrs = self.file_attr(ino, 0, self.generated_mtime);
+                attrs.kind = FileType::Symlink;
+                attrs.size = target.as_os_str().len() as u64;
+                attrs.blocks = 0;
+                (attrs, Some(target.clone()))
+            }
+        };
+        let inode = Inode {
+            attrs,
+            children: vec![],
+            name: name.to_os_string(),
+            parent,
+            krate_path: None,
+            entry_index: 0,
+            link_target,
+            disk_path: None,
+            overlay_path: None,
+        };
+        self.insert_inode(ino, inode);
+        if let VirtualNode::File(source) = node {
+            self.virtual_files.insert(ino, (source, VirtualFile::new()));
+        }
+        self.virtual_entries
+            .entry(dir.to_path_buf())
+            .or_default()
+            .push(ino);
+        self.virtual_inodes.insert(ino);
+        Ok(ino)
+    }
+
+    /// The generated entries in directory `ino`.
+    fn virtual_entries_in(&self, ino: u64) -> &[u64] {
+        if ino == fuser::FUSE_ROOT_ID && !self.generated_root {
+            return &[];
+        }
+        self.virtual_entries
+            .get(&self.mount_path(ino))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// The generated entries in directory `ino` that no entry of a crate
+    /// hides.
+    fn virtual_children(&self, ino: u64) -> impl Iterator<Item = u64> + '_ {
+        self.virtual_entries_in(ino)
+            .iter()
+            .copied()
+            .filter(move |child| self.child_named(ino, &self.inodes[child].name).is_none())
+    }
+
+    /// [`Self::virtual_children`] as listings show them.
+    fn listed_virtual_children(&self, ino: u64) -> impl Iterator<Item = u64> + '_ {
+        self.virtual_children(ino)
+            .filter(|&child| child != self.control_dir || self.show_control_dir)
+    }
+
+    /// Warns, once for each, of the generated entries in directory `ino`
+    /// that an entry of a crate hides.
+    fn note_shadowed(&mut self, op: &str, ino: u64) {
+        let shadowed = self
+            .virtual_entries_in(ino)
+            .iter()
+            .copied()
+            .filter(|child| self.child_named(ino, &self.inodes[child].name).is_some())
+            .collect::<Vec<_>>();
+        for child in shadowed {
+            if self.shadowed.insert(child) {
+                warn!(
+                    "[{op}] {} is in the crate, hiding the generated entry",
+                    self.mount_path(child).display()
+                );
+            }
+        }
+    }
+
+    /// Creates the directory of each source when there are several; a lone
+    /// one has its crates in the root. They list crates like shard
+    /// directories do.
+    fn add_source_dirs(&mut self) -> Result<()> {
+        for index in 0..self.sources.len() {
+            let top = match self.sources[index].dir_name.clone() {
+                Some(name) => {
+                    let dir = self.child_dir(fuser::FUSE_ROOT_ID, &name)?;
+                    self.shard_dirs.insert(dir);
+                    dir
+                }
+                None => fuser::FUSE_ROOT_ID,
+            };
+            self.sources[index].top = top;
+        }
+        Ok(())
+    }
+
+    /// What `ino`, a generated file, holds now.
+    fn virtual_snapshot(&self, ino: u64) -> Arc<[u8]> {
+        let (source, contents) = &self.virtual_files[&ino];
+        contents.snapshot(self.virtual_version(source), || {
+            self.virtual_contents(source)
+        })
+    }
+
+    /// What the contents of `source` are generated from, as far as it can
+    /// be told without generating them.
+    fn virtual_version(&self, source: &VirtualSource) -> Option<u64> {
+        match source {
+            VirtualSource::Control(ControlFile::Crates) => Some(self.crates_version),
+            VirtualSource::Control(ControlFile::Version) => Some(0),
+            VirtualSource::Control(ControlFile::LastRescan) => Some(self.rescans.version()),
+            VirtualSource::Control(ControlFile::Stats | ControlFile::AccessSummary) => None,
+            VirtualSource::Action(ControlAction::LogLevel) => None,
+            VirtualSource::Action(ControlAction::DropCaches | ControlAction::Refresh) => Some(0),
+            VirtualSource::Custom(content) => content.version(),
+        }
+    }
+
+    fn virtual_contents(&self, source: &VirtualSource) -> Vec<u8> {
+        match source {
+            VirtualSource::Control(file) => self.control_contents(*file),
+            VirtualSource::Action(action) => action.contents(),
+            VirtualSource::Custom(content) => content.contents(),
+        }
+    }
+
+    /// What the control file named `name` holds now.
+    fn control_file(&self, name: &str) -> Option<Vec<u8>> {
+        let file = ControlFile::ALL
+            .into_iter()
+            .find(|file| file.name() == name)?;
+        Some(self.control_contents(file))
+    }
+
+    fn control_contents(&self, file: ControlFile) -> Vec<u8> {
+        // Crates dropped as corrupt or rejected keep their entry here but
+        // lose their inode.
+        let mut crates = self
+            .crate_dirs
+            .iter()
+            .filter(|(_, (_, ino))| self.inodes.contains_key(ino))
+            .map(|(archive, &(_, ino))| (self.mount_path(ino), archive.as_path()))
+            .collect::<Vec<_>>();
+        match file {
+            ControlFile::Stats => control::stats(&control::Stats {
+                crates: crates.len(),
+                deferred: self.deferred.len(),
+                resident: self.resident.len(),
+                inodes: self.inodes.len(),
+                shadowed: self.shadowed.len(),
+                workers: self.workers.stats(),
+                handle_throttle_waited: self.max_read_bps_per_handle.map(|_| {
+                    self.handles
+                        .values()
+                        .filter_map(|handle| handle.throttle.as_ref())
+                        .map(|throttle| throttle.waited())
+                        .sum::<Duration>()
+                        + self.released_throttle_waited
+                }),
+                invalidations_sent: self.invalidator.sent(),
+            }),
+            ControlFile::Crates => {
+                crates.sort();
+                control::crates(
+                    crates
+                        .iter()
+                        .map(|(dir, archive)| (dir.as_path(), *archive)),
+                )
+            }
+            ControlFile::Version => control::version(),
+            ControlFile::AccessSummary => control::access_summary(&self.access_summary()),
+            ControlFile::LastRescan => self.rescans.json(),
+        }
+    }
+
+    /// The attributes of `ino`, with the current size for control files
+    /// and the current entries for directories.
+    fn attrs(&self, ino: u64) -> Option<FileAttr> {
+        let inode = self.inodes.get(&ino)?;
+        let mut attrs = inode.attrs;
+        if let Some((source, contents)) = self.virtual_files.get(&ino) {
+            attrs.size = contents.size(self.virtual_version(source), || {
+                self.virtual_contents(source)
+            });
+            attrs.blocks = attrs.size.div_ceil(u64::from(BLKSIZE));
+        }
+        if attrs.kind == FileType::Directory {
+            let generated = self.listed_virtual_children(ino).collect::<Vec<_>>();
+            let subdirs = inode
+                .children
+                .iter()
+                .chain(&generated)
+                .filter(|child| self.inodes[child].attrs.kind == FileType::Directory)
+                .count() as u32;
+            // `find` takes nlink - 2 as the number of subdirectories, and 1
+            // as not knowing, which is the case until a crate is populated.
+            attrs.nlink = match self.deferred.contains_key(&ino) {
+                true => 1,
+                false => 2 + subdirs,
+            };
+            attrs.size = (inode.children.len() + generated.len()) as u64;
+        }
+        if self.writable(ino) {
+            attrs.perm |= 0o200;
+        }
+        Some(attrs)
+    }
+
+    /// Leaves the directory of a crate that failed `--verify` with nothing
+    /// but a `VERIFICATION_FAILED` file saying why.
+    fn add_verification_report(
+        &mut self,
+        crate_file_path: &Path,
+        crate_inode: u64,
+        problem: String,
+    ) -> Result<()> {
+        let report = format!(
+            "{} failed verification: {problem}\n",
+            crate_file_path.to_string_lossy()
+        );
+        self.violation(Violation::ChecksumMismatch {
+            krate: archive::stem(crate_file_path)
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            detail: problem,
+        });
+        let mtime = std::fs::metadata(crate_file_path)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(UNIX_EPOCH);
+        let new_inode = self.next_inode()?;
+        let inode = Inode {
+            attrs: self.file_attr(new_inode, report.len() as u64, mtime),
+            children: vec![],
+            name: "VERIFICATION_FAILED".into(),
+            parent: crate_inode,
+            krate_path: None,
+            entry_index: 0,
+            link_target: None,
+            disk_path: None,
+            overlay_path: None,
+        };
+        self.insert_inode(new_inode, inode);
+        self.inodes
+            .get_mut(&crate_inode)
+            .unwrap()
+            .children
+            .push(new_inode);
+        self.verification_reports
+            .insert(new_inode, report.into_bytes());
+        self.inherit_mtimes(crate_inode);
+        Ok(())
+    }
+
+    /// Adds `path` in `parent` as another name for the data of `target`.
+    /// The new inode reads the same archive entry, and every name of that
+    /// entry counts the others in its link count.
+    fn link(
+        &mut self,
+        target: u64,
+        new_inode: u64,
+        parent: u64,
+        name: &OsStr,
+        links: &mut HashMap<usize, Vec<u64>>,
+    ) {
+        let target_inode = &self.inodes[&target];
+        let inode = Inode {
+            attrs: FileAttr {
+                ino: new_inode,
+                ..target_inode.attrs
+            },
+            children: vec![],
+            name: name.to_os_string(),
+            parent,
+            krate_path: target_inode.krate_path.clone(),
+            entry_index: target_inode.entry_index,
+            link_target: target_inode.link_target.clone(),
+            disk_path: target_inode.disk_path.clone(),
+            overlay_path: None,
+        };
+        let names = links
+            .entry(target_inode.entry_index)
+            .or_insert_with(|| vec![target]);
+        self.insert_inode(new_inode, inode);
+        self.inodes
+            .get_mut(&parent)
+            .unwrap()
+            .children
+            .push(new_inode);
+        names.push(new_inode);
+        self.set_nlink(names);
+    }
+
+    /// Gives each of `names`, the inodes of one archive entry, their count
+    /// as link count.
+    fn set_nlink(&mut self, names: &[u64]) {
+        for ino in names {
+            self.inodes.get_mut(ino).unwrap().attrs.nlink = names.len() as u32;
+        }
+    }
+
+    /// Gives synthesized directories the newest mtime found below them.
+    fn inherit_mtimes(&mut self, ino: u64) -> SystemTime {
+        let inode = &self.inodes[&ino];
+        if inode.attrs.kind != FileType::Directory {
+            return inode.attrs.mtime;
+        }
+        let mut mtime = UNIX_EPOCH;
+        for child in inode.children.clone() {
+            mtime = mtime.max(self.inherit_mtimes(child));
+        }
+        let attrs = &mut self.inodes.get_mut(&ino).unwrap().attrs;
+        (attrs.mtime, attrs.ctime, attrs.crtime) = (mtime, mtime, mtime);
+        mtime
+    }
+
+    /// The value of `user.fuse_crates.<name>` on `ino`, or `None` if it
+    /// does not have it.
+    fn xattr(&mut self, ino: u64, name: &str) -> Result<Option<Vec<u8>>, libc::c_int> {
+        let Some(archive) = self.inodes[&ino].krate_path.clone() else {
+            return Ok(None);
+        };
+        let stem = archive::stem(&archive).unwrap();
+        let krate = crate_name_of(stem).len();
+        let stem = stem.as_encoded_bytes();
+        let value = match name {
+            "crate_name" => stem[..krate].to_vec(),
+            "crate_version" => stem.get(krate + 1..).unwrap_or_default().to_vec(),
+            "archive_path" => archive.as_os_str().as_encoded_bytes().to_vec(),
+            "sha256" => {
+                if !self.archive_digests.contains_key(&*archive) {
+                    let digest = std::fs::File::open(&archive)
+                        .and_then(sha256::hex_digest)
+                        .map_err(|e| {
+                            warn!("[getxattr] hashing {}: {e}", archive.to_string_lossy());
+                            e.raw_os_error().unwrap_or(libc::EIO)
+                        })?;
+                    self.archive_digests.insert(archive.to_path_buf(), digest);
+                }
+                self.archive_digests[&*archive].clone().into_bytes()
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    fn violation(&mut self, violation: Violation) {
+        warn!("{violation}");
+        self.violations.push(violation);
+    }
+
+    /// Populates a crate, dropping it entirely if its archive cannot be
+    /// read to the end, so that a half-read archive neither shows up as a
+    /// complete directory nor leaves entries linked elsewhere in the tree.
+    fn try_populate_crate(&mut self, archive: &Path, parent: u64, ino: u64) -> bool {
+        let scan = scan::scan_crate(archive, &self.scan_config);
+        self.try_apply_scan(archive, scan, parent, ino)
+    }
+
+    fn try_apply_scan(
+        &mut self,
+        archive: &Path,
+        scan: Result<Scan>,
+        parent: u64,
+        ino: u64,
+    ) -> bool {
+        let first_new_inode = self.next_inode;
+        let Err(error) = scan.and_then(|scan| self.apply_scan(archive, scan, parent, ino)) else {
+            return true;
+        };
+        self.violation(Violation::CorruptCrate {
+            krate: archive::stem(archive)
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            error: format!("{error:#}"),
+        });
+        // Inode numbers are handed out in order, so everything the crate
+        // added is at or above `first_new_inode`.
+        self.inodes.retain(|&ino, _| ino < first_new_inode);
+        for inode in self.inodes.values_mut() {
+            inode.children.retain(|&child| child < first_new_inode);
+        }
+        self.remove_subtree(parent, ino);
+        false
+    }
+
+    fn child_dir(&mut self, parent: u64, name: &OsStr) -> Result<u64> {
+        if let Some(child) = self.child_named(parent, name) {
+            return Ok(child);
+        }
+        let new_inode = self.next_inode()?;
+        let new_inode_object = Inode {
+            attrs: self.dir_attr(new_inode),
+            children: vec![],
+            krate_path: None,
+            entry_index: 0,
+            link_target: None,
+            disk_path: None,
+            overlay_path: None,
+            name: name.to_os_string(),
+            parent,
+        };
+        self.insert_inode(new_inode, new_inode_object);
+        self.inodes
+            .get_mut(&parent)
+            .unwrap()
+            .children
+            .push(new_inode);
+        Ok(new_inode)
+    }
+
+    fn remove_subtree(&mut self, parent: u64, ino: u64) {
+        self.inodes
+            .get_mut(&parent)
+            .unwrap()
+            .children
+            .retain(|child| *child != ino);
+        self.crate_aliases.retain(|_, alias| *alias != ino);
+        self.crates_version += 1;
+        self.deferred.remove(&ino);
+        self.resident.remove(&ino);
+        let mut pending = vec![ino];
+        while let Some(ino) = pending.pop() {
+            if let Some(inode) = self.inodes.remove(&ino) {
+                pending.extend(inode.children);
+            }
+            self.verification_reports.remove(&ino);
+        }
+    }
+
+    fn child_named(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        self.inodes
+            .get(&parent)?
+            .children
+            .iter()
+            .copied()
+            .find(|child| self.inodes[child].name == name)
+    }
+
+    /// `--icase`: the child of `parent` named `name` but for case, or the
+    /// first by name of those that are.
+    fn icase_child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        let mut matches = self.inodes[&parent]
+            .children
+            .iter()
+            .map(|child| (self.inodes[child].name.as_os_str(), *child))
+            .filter(|(candidate, _)| name_mapper::eq_ignore_case(candidate, name))
+            .collect::<Vec<_>>();
+        matches.sort_unstable();
+        if matches.len() > 1 {
+            warn!(
+                "[lookup] par 0x{parent:016x} name {} matches {} names but for case, taking {}",
+                name.to_string_lossy(),
+                matches.len(),
+                matches[0].0.to_string_lossy()
+            );
+        }
+        matches.first().map(|&(_, child)| child)
+    }
+
+    /// Whether `--overlay` may let `ino` change: anything but the
+    /// directories listing crates and the generated files.
+    fn writable(&self, ino: u64) -> bool {
+        self.control_action(ino).is_some()
+            || self.overlay.is_some()
+                && !self.lists_crates(ino)
+                && !self.virtual_inodes.contains(&ino)
+                && !self.verification_reports.contains_key(&ino)
+    }
+
+    /// What writing to `ino` does, if it is one of the control files
+    /// `--control-writes` adds.
+    fn control_action(&self, ino: u64) -> Option<ControlAction> {
+        match self.virtual_files.get(&ino) {
+            Some((VirtualSource::Action(action), _)) => Some(*action),
+            _ => None,
+        }
+    }
+
+    /// Does what `written` to the control file of `action` asks for.
+    fn perform(&mut self, action: ControlAction, written: &[u8]) -> Result<(), libc::c_int> {
+        let Some(command) = action.parse(written) else {
+            let written = String::from_utf8_lossy(written);
+            warn!("[write] {} {:?} => EINVAL", action.name(), written.trim());
+            return Err(libc::EINVAL);
+        };
+        match command {
+            control::Command::DropCaches => {
+                self.workers.drop_caches();
+                info!("[control] caches dropped");
+            }
+            control::Command::Refresh(stem) => {
+                let archives = self
+                    .crate_dirs
+                    .keys()
+                    .filter(|archive| archive::stem(archive) == Some(&*stem))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if archives.is_empty() {
+                    warn!("[write] refresh {} => ENOENT", stem.to_string_lossy());
+                    return Err(libc::ENOENT);
+                }
+                let (started, took) = (SystemTime::now(), Instant::now());
+                let asked = self.invalidator.asked();
+                let mut delta = rescan::Delta::default();
+                for archive in archives {
+                    self.remove_crate(&archive);
+                    match std::fs::metadata(&archive) {
+                        Ok(metadata) => {
+                            if let Err(e) = self.add_crate(archive.clone(), &metadata) {
+                                error!("[refresh] {e:#}");
+                                return Err(libc::ENOSPC);
+                            }
+                            info!("[refresh] {} read again", archive.to_string_lossy());
+                            delta.refreshed.push((archive, rescan::Reason::Asked));
+                        }
+                        Err(e) => {
+                            info!("[refresh] {}: {e}", archive.to_string_lossy());
+                            delta.removed.push(archive);
+                        }
+                    }
+                }
+                self.evict_crates();
+                self.record_rescan(rescan::Report {
+                    started,
+                    took: took.elapsed(),
+                    delta,
+                    invalidations: self.invalidator.asked() - asked,
+                });
+            }
+            control::Command::LogLevel(level) => {
+                log::set_max_level(level);
+                info!(
+                    "[control] logging at {}",
+                    level.as_str().to_ascii_lowercase()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `ino` is in the mount.
+    fn mount_path(&self, mut ino: u64) -> PathBuf {
+        let mut names = vec![];
+        while let Some(inode) = self.inodes.get(&ino).filter(|_| ino != fuser::FUSE_ROOT_ID) {
+            names.push(inode.name.as_os_str());
+            ino = inode.parent;
+        }
+        names.iter().rev().collect()
+    }
+
+    /// The topmost directory `ino` is in below those listing crates, which
+    /// is its crate directory unless the archive put it elsewhere, and its
+    /// path from there.
+    fn path_in_crate(&self, mut ino: u64) -> Option<(u64, PathBuf)> {
+        let mut names = vec![];
+        loop {
+            let inode = self.inodes.get(&ino)?;
+            if self.lists_crates(inode.parent) {
+                return Some((ino, names.iter().rev().collect()));
+            }
+            names.push(inode.name.as_os_str());
+            ino = inode.parent;
+        }
+    }
+
+    /// The file stem of the crate `ino` is in and its path in the crate
+    /// directory, `None` outside of one.
+    fn crate_relative_path(&self, ino: u64) -> Option<(OsString, PathBuf)> {
+        let (dir, path) = self.path_in_crate(ino)?;
+        let (archive, _) = self
+            .crate_dirs
+            .iter()
+            .find(|(_, &(_, crate_dir))| crate_dir == dir)?;
+        Some((archive::stem(archive)?.to_os_string(), path))
+    }
+
+    /// Puts the files `--overlay` holds for a crate over those of its
+    /// archive, and hides those deleted through the mount.
+    fn apply_overlay(&mut self, crate_file_path: &Path, crate_inode: u64) -> Result<()> {
+        let Some(overlay) = &self.overlay else {
+            return Ok(());
+        };
+        let stem = archive::stem(crate_file_path).unwrap();
+        let changes = match overlay.changes(stem) {
+            Ok(changes) => changes,
+            Err(e) => {
+                warn!("[overlay] reading {}: {e}", stem.to_string_lossy());
+                return Ok(());
+            }
+        };
+        for change in changes {
+            let path = match &change {
+                overlay::Change::Dir(path, _)
+                | overlay::Change::File(path, _, _)
+                | overlay::Change::Whiteout(path) => path,
+            };
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            // Under a directory that was deleted or is not one anymore.
+            let mut dir = Some(crate_inode);
+            for component in parent {
+                dir = dir
+                    .and_then(|dir| self.child_named(dir, component))
+                    .filter(|dir| self.inodes[dir].attrs.kind == FileType::Directory);
+            }
+            let Some(dir) = dir else { continue };
+            let existing = self.child_named(dir, name);
+            match &change {
+                overlay::Change::Whiteout(_) => {
+                    if let Some(child) = existing {
+                        self.remove_subtree(dir, child);
+                    }
+                }
+                overlay::Change::Dir(_, metadata) => {
+                    if existing.is_none() {
+                        let ino = self.child_dir(dir, name)?;
+                        self.inodes.get_mut(&ino).unwrap().attrs.perm =
+                            metadata.mode() as u16 & 0o7777;
+                    }
+                }
+                overlay::Change::File(_, copy, metadata) => match existing {
+                    Some(child) if self.inodes[&child].attrs.kind == FileType::RegularFile => {
+                        self.inodes.get_mut(&child).unwrap().overlay_path = Some(copy.clone());
+                        self.overlay_attrs(child, metadata);
+                    }
+                    Some(_) => (),
+                    None => {
+                        self.add_overlay_file(dir, name, copy.clone(), metadata)?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a file that only exists in `--overlay`.
+    fn add_overlay_file(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        copy: PathBuf,
+        metadata: &std::fs::Metadata,
+    ) -> Result<u64> {
+        let new_inode = self.next_inode()?;
+        let inode = Inode {
+            attrs: self.file_attr(new_inode, 0, UNIX_EPOCH),
+            children: vec![],
+            name: name.to_os_string(),
+            parent,
+            krate_path: None,
+            entry_index: 0,
+            link_target: None,
+            disk_path: None,
+            overlay_path: Some(copy),
+        };
+        self.insert_inode(new_inode, inode);
+        self.inodes
+            .get_mut(&parent)
+            .unwrap()
+            .children
+            .push(new_inode);
+        self.overlay_attrs(new_inode, metadata);
+        Ok(new_inode)
+    }
+
+    /// Takes the size, times and mode of `ino` from its copy in `--overlay`.
+    fn overlay_attrs(&mut self, ino: u64, metadata: &std::fs::Metadata) {
+        let attrs = &mut self.inodes.get_mut(&ino).unwrap().attrs;
+        attrs.size = metadata.len();
+        attrs.blocks = attrs.size.div_ceil(BLKSIZE.into());
+        attrs.perm = metadata.mode() as u16 & 0o7777;
+        if let Ok(mtime) = metadata.modified() {
+            (attrs.mtime, attrs.ctime) = (mtime, mtime);
+        }
+        if let Ok(atime) = metadata.accessed() {
+            attrs.atime = atime;
+        }
+    }
+
+    /// The copy of `ino` in `--overlay`, made from the archive the first
+    /// time it is opened for writing, and emptied with `truncate`.
+    fn copy_up(&mut self, ino: u64, truncate: bool) -> Result<PathBuf, libc::c_int> {
+        let inode = self.inodes.get(&ino).ok_or(libc::ENOENT)?;
+        if inode.attrs.kind != FileType::RegularFile {
+            return Err(libc::EPERM);
+        }
+        let errno = |e: std::io::Error| {
+            warn!("[overlay] ino 0x{ino:016x} => {e}");
+            e.raw_os_error().unwrap_or(libc::EIO)
+        };
+        let copy = match &inode.overlay_path {
+            Some(copy) if truncate => {
+                let copy = copy.clone();
+                File::options()
+                    .write(true)
+                    .open(&copy)
+                    .and_then(|file| file.set_len(0))
+                    .map_err(errno)?;
+                copy
+            }
+            Some(copy) => return Ok(copy.clone()),
+            None => {
+                let (Some(overlay), Some(archive)) = (&self.overlay, &inode.krate_path) else {
+                    return Err(libc::EROFS);
+                };
+                let (stem, path) = self.crate_relative_path(ino).ok_or(libc::EROFS)?;
+                let source = (&**archive, inode.entry_index, inode.disk_path.as_deref());
+                let (perm, mtime) = (inode.attrs.perm | 0o200, inode.attrs.mtime);
+                let copy = overlay
+                    .copy_up(&stem, &path, source, truncate)
+                    .map_err(errno)?;
+                let file = File::options().write(true).open(&copy).map_err(errno)?;
+                file.set_permissions(Permissions::from_mode(perm.into()))
+                    .and_then(|()| match truncate {
+                        true => Ok(()),
+                        false => file.set_modified(mtime),
+                    })
+                    .map_err(errno)?;
+                self.inodes.get_mut(&ino).unwrap().overlay_path = Some(copy.clone());
+                copy
+            }
+        };
+        let metadata = std::fs::metadata(&copy).map_err(errno)?;
+        self.overlay_attrs(ino, &metadata);
+        Ok(copy)
+    }
+
+    /// Where a new `name` in `parent` goes in `--overlay`, clearing any
+    /// earlier deletion of it.
+    fn overlay_child(&mut self, parent: u64, name: &OsStr) -> Result<PathBuf, libc::c_int> {
+        self.populate_deferred(parent)?;
+        let Some(inode) = self.inodes.get(&parent) else {
+            return Err(libc::ENOENT);
+        };
+        if inode.attrs.kind != FileType::Directory {
+            return Err(libc::ENOTDIR);
+        }
+        if !self.writable(parent) {
+            return Err(libc::EROFS);
+        }
+        if self.child_named(parent, name).is_some() {
+            return Err(libc::EEXIST);
+        }
+        let (stem, path) = self.crate_relative_path(parent).ok_or(libc::EROFS)?;
+        let path = path.join(name);
+        let overlay = self.overlay.as_ref().unwrap();
+        overlay.clear_whiteout(&stem, &path).map_err(|e| {
+            warn!("[overlay] {}: {e}", path.to_string_lossy());
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })?;
+        Ok(overlay.path(&stem, &path))
+    }
+
+    /// The directory a crate lives in: `top`, the root or the directory of
+    /// its source, or with `--shard-root N`, N levels of two-character
+    /// prefixes of the crate name under it (`se/rd/serde-1.0.193`),
+    /// followed with `--group-by-name` by a directory named after the crate
+    /// (`serde/1.0.193`).
+    fn crate_parent(&mut self, top: u64, stem: &OsStr) -> Result<u64> {
+        let name = crate_name_of(stem).to_string_lossy().into_owned();
+        let mut chars = name.chars();
+        let mut parent = top;
+        for _ in 0..self.shard_levels {
+            let shard = chars.by_ref().take(2).collect::<String>();
+            let shard = if shard.is_empty() {
+                "_".to_owned()
+            } else {
+                shard
+            };
+            parent = self.child_dir(parent, OsStr::new(&shard))?;
+            self.shard_dirs.insert(parent);
+        }
+        let krate = crate_name_of(stem);
+        if self.group_by_name && krate != stem {
+            parent = self.child_dir(parent, krate)?;
+            self.shard_dirs.insert(parent);
+        }
+        Ok(parent)
+    }
+
+    /// The archives of every source. Also notes the mtimes they were listed
+    /// at, for [`Self::refresh`].
+    fn list_archives(&mut self) -> Result<Vec<(PathBuf, std::fs::Metadata)>> {
+        let mut crates = vec![];
+        for source in &mut self.sources {
+            let files = source
+                .list()
+                .with_context(|| format!("Listing {}", source.path.to_string_lossy()))?;
+            for file in files {
+                match file {
+                    Ok((path, _))
+                        if !archive::stem(&path).is_some_and(|stem| self.filter.admits(stem)) => {}
+                    Ok((path, metadata)) => crates.push((path, metadata)),
+                    Err(e) => warn!("[populate] listing {}: {e}", source.path.to_string_lossy()),
+                }
+            }
+        }
+        Ok(crates)
+    }
+
+    /// The directory the crates of the source of `archive` are put in.
+    fn source_top(&self, archive: &Path) -> u64 {
+        self.sources
+            .iter()
+            .find(|source| source.holds(archive))
+            .map_or(fuser::FUSE_ROOT_ID, |source| source.top)
+    }
+
+    /// Creates the (still empty) directory of the crate in `archive`.
+    fn add_crate_dir(&mut self, archive: &Path) -> Result<(u64, u64)> {
+        let name = archive::stem(archive).unwrap();
+        let parent = self.crate_parent(self.source_top(archive), name)?;
+        let inode = self.next_inode()?;
+        let krate = crate_name_of(name);
+        let grouped = self.group_by_name && krate != name;
+        let inode_object = Inode {
+            attrs: self.dir_attr(inode),
+            children: vec![],
+            krate_path: None,
+            entry_index: 0,
+            link_target: None,
+            disk_path: None,
+            overlay_path: None,
+            name: match grouped {
+                // `1.0.203` in `serde`
+                true => OsStr::from_bytes(&name.as_bytes()[krate.len() + 1..]).to_os_string(),
+                false => name.to_os_string(),
+            },
+            parent,
+        };
+        let version = inode_object.name.clone();
+        self.insert_inode(inode, inode_object);
+        let position = match grouped {
+            // Newest version first.
+            true => self.inodes[&parent].children.iter().position(|child| {
+                let other = &self.inodes[child].name;
+                compare_versions(&version, other).is_gt()
+            }),
+            false => None,
+        };
+        let siblings = &mut self.inodes.get_mut(&parent).unwrap().children;
+        siblings.insert(position.unwrap_or(siblings.len()), inode);
+        let top = self.source_top(archive);
+        if parent != top {
+            self.crate_aliases.insert((top, name.to_os_string()), inode);
+        }
+        self.crate_dirs
+            .insert(archive.to_path_buf(), (parent, inode));
+        self.crates_version += 1;
+        log::debug!("Crate found: {}", name.to_string_lossy());
+        Ok((parent, inode))
+    }
+
+    /// Picks up archives added to, removed from or replaced in the cache
+    /// directories since they were last listed, as `cargo fetch` does while
+    /// mounted. Crates that are still there as they were keep their inodes;
+    /// a replaced one gets a new directory, see [`Pinned`].
+    fn refresh(&mut self) {
+        let unchanged = self.sources.iter().all(|source| {
+            let mtime = std::fs::metadata(&source.path).and_then(|metadata| metadata.modified());
+            mtime.ok() == source.listed_mtime
+        });
+        if unchanged {
+            return;
+        }
+        let crates = match self.list_archives() {
+            Ok(crates) => crates,
+            Err(e) => {
+                warn!("[refresh] {e:#}");
+                return;
+            }
+        };
+        let present = crates
+            .iter()
+            .map(|(path, metadata)| (path.clone(), rescan::stamp(metadata)))
+            .collect::<HashMap<_, _>>();
+        let delta = rescan::delta(&self.archive_stamps, &present);
+        if delta.is_empty() {
+            return;
+        }
+        let (started, took) = (SystemTime::now(), Instant::now());
+        let asked = self.invalidator.asked();
+        for path in &delta.removed {
+            self.remove_crate(path);
+            info!("[refresh] {} is gone", path.to_string_lossy());
+        }
+        for (path, reason) in &delta.refreshed {
+            self.remove_crate(path);
+            info!("[refresh] {} was {}", path.to_string_lossy(), reason.name());
+        }
+        for (path, metadata) in crates {
+            if self.crate_dirs.contains_key(&path) {
+                continue;
+            }
+            if let Err(e) = self.add_crate(path.clone(), &metadata) {
+                error!("[refresh] {e:#}");
+                return;
+            }
+            if delta.added.contains(&path) {
+                info!("[refresh] {} appeared", path.to_string_lossy());
+            }
+        }
+        self.evict_crates();
+        self.record_rescan(rescan::Report {
+            started,
+            took: took.elapsed(),
+            delta,
+            invalidations: self.invalidator.asked() - asked,
+        });
+    }
+
+    /// Keeps `report` for `last-rescan.json` and logs what it counts.
+    fn record_rescan(&mut self, report: rescan::Report) {
+        info!(
+            "[refresh] {} added, {} removed, {} read again in {:.3}s, {} invalidations",
+            report.delta.added.len(),
+            report.delta.removed.len(),
+            report.delta.refreshed.len(),
+            report.took.as_secs_f64(),
+            report.invalidations
+        );
+        self.rescans.push(report);
+    }
+
+    /// Forgets the crate of `archive`, which is gone or was replaced.
+    fn remove_crate(&mut self, archive: &Path) {
+        let (parent, inode) = self.crate_dirs.remove(archive).unwrap();
+        self.invalidate_crate(parent, inode);
+        self.remove_subtree(parent, inode);
+        self.archive_stamps.remove(archive);
+        self.workers.forget(archive);
+    }
+
+    /// Adds the crate of `archive`, listed after the filesystem was
+    /// populated.
+    fn add_crate(&mut self, archive: PathBuf, metadata: &std::fs::Metadata) -> Result<()> {
+        let (parent, inode) = self.add_crate_dir(&archive)?;
+        // Wherever the kernel remembers the name missing.
+        self.invalidate_crate(parent, inode);
+        self.archive_stamps
+            .insert(archive.clone(), rescan::stamp(metadata));
+        // Only --eager populates new crates right away.
+        match self.populate_budget {
+            None => {
+                self.try_populate_crate(&archive, parent, inode);
+            }
+            Some(_) => {
+                self.deferred.insert(inode, (archive, parent));
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the kernel to forget the directory `inode` of a crate in
+    /// `parent`, under its name and its alias in the root if it has one,
+    /// and everything below it.
+    fn invalidate_crate(&self, parent: u64, inode: u64) {
+        let Some(dir) = self.inodes.get(&inode) else {
+            return;
+        };
+        self.invalidator
+            .invalidate_subtree(parent, &dir.name, inode);
+        for ((top, name), _) in self
+            .crate_aliases
+            .iter()
+            .filter(|(_, &alias)| alias == inode)
+        {
+            self.invalidator.invalidate_entry(*top, name);
+        }
+    }
+
+    /// The [`Invalidator`] of the filesystem, to [`Invalidator::connect`]
+    /// once it is mounted so that crates refreshed while mounted are not
+    /// left in the kernel's caches until they expire.
+    pub fn invalidator(&self) -> Invalidator {
+        self.invalidator.clone()
+    }
+
+    /// Whether `dir` is the root or a shard directory, where crates come
+    /// and go with [`Self::refresh`].
+    fn lists_crates(&self, dir: u64) -> bool {
+        dir == fuser::FUSE_ROOT_ID || self.shard_dirs.contains(&dir)
+    }
+
+    /// How long the kernel may keep what it was told about `ino`.
+    fn ttl(&self, ino: u64) -> Duration {
+        if self.virtual_files.contains_key(&ino) {
+            Duration::ZERO
+        } else if self.lists_crates(ino) || self.deferred.contains_key(&ino) {
+            self.ttl.min(VOLATILE_TTL)
+        } else {
+            self.ttl
+        }
+    }
+
+    /// ENOENT, as an entry with inode 0 so that the kernel remembers the
+    /// name is missing from `parent` for as long as `parent` may be cached.
+    fn reply_negative(&self, parent: u64, reply: fuser::ReplyEntry) {
+        let ttl = self.ttl(parent);
+        if ttl.is_zero() {
+            return reply.error(libc::ENOENT);
+        }
+        let attrs = FileAttr {
+            ino: 0,
+            ..Self::FIL_ATTR_TEMPLATE
+        };
+        reply.entry(&ttl, &attrs, 0)
+    }
+
+    fn populate(&mut self) -> Result<(), libc::c_int> {
+        self.insert_inode(
+            fuser::FUSE_ROOT_ID,
+            Inode {
+                attrs: self.dir_attr(fuser::FUSE_ROOT_ID),
+                children: vec![],
+                krate_path: None,
+                entry_index: 0,
+                link_target: None,
+                disk_path: None,
+                overlay_path: None,
+                name: OsString::new(),
+                parent: fuser::FUSE_ROOT_ID,
+            },
+        );
+        if let Err(e) = self.add_source_dirs() {
+            error!("[populate] {e:#}");
+            return Err(libc::ENOSPC);
+        }
+        let mut crates = self.list_archives().map_err(|e| {
+            error!("[populate] {e:#}");
+            e.downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::raw_os_error)
+                .unwrap_or(libc::EIO)
+        })?;
+        self.populate_order.sort(&mut crates);
+        let started = Instant::now();
+        let mut found = vec![];
+        for (path, metadata) in crates {
+            self.archive_stamps
+                .insert(path.clone(), rescan::stamp(&metadata));
+            match self.add_crate_dir(&path) {
+                Ok((parent, inode)) => found.push((path, parent, inode)),
+                Err(e) => {
+                    error!("[populate] {e:#}");
+                    return Err(libc::ENOSPC);
+                }
+            }
+        }
+        // Archives are scanned on `jobs` threads, taken in order until the
+        // budget is spent, and applied here in that same order so that
+        // inode numbers do not depend on which scan finishes first.
+        let budget = self.populate_budget;
+        let resident = self.max_resident_crates.unwrap_or(usize::MAX);
+        let scan_config = self.scan_config.clone();
+        let scan_config = &*scan_config;
+        let claimed = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        let mut eager = 0;
+        let mut applied = 0;
+        thread::scope(|scope| {
+            for _ in 0..self.jobs.max(1) {
+                let (found, claimed, sender) = (&found, &claimed, sender.clone());
+                scope.spawn(move || loop {
+                    if budget.is_some_and(|budget| started.elapsed() >= budget) {
+                        return;
+                    }
+                    let index = claimed.fetch_add(1, Ordering::Relaxed);
+                    let Some((path, _, _)) = found.get(index).filter(|_| index < resident) else {
+                        return;
+                    };
+                    let scan = panic::catch_unwind(|| scan::scan_crate(path, scan_config))
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("Panicked reading the archive")));
+                    if sender.send((index, scan)).is_err() {
+                        return;
+                    }
+                });
+            }
+            drop(sender);
+            let mut pending = BTreeMap::new();
+            for (index, scan) in receiver {
+                pending.insert(index, scan);
+                while let Some(scan) = pending.remove(&applied) {
+                    let (path, parent, inode) = &found[applied];
+                    applied += 1;
+                    if !self.try_apply_scan(path, scan, *parent, *inode) {
+                        continue;
+                    }
+                    eager += 1;
+                    log::debug!("Crate populated: {}", path.to_string_lossy());
+                }
+            }
+        });
+        for (path, parent, inode) in found.into_iter().skip(applied) {
+            self.deferred.insert(inode, (path, parent));
+        }
+        if self.populate_budget != Some(Duration::ZERO) {
+            info!(
+                "[populate] {eager} crates populated eagerly, {} deferred",
+                self.deferred.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Populates `ino` now if it is a crate directory that was not populated
+    /// at mount time. Fails with EIO if its archive turns out to be corrupt.
+    fn populate_deferred(&mut self, ino: u64) -> Result<(), libc::c_int> {
+        let Some((archive, parent)) = self.deferred.remove(&ino) else {
+            self.touch(ino);
+            return Ok(());
+        };
+        if !self.try_populate_crate(&archive, parent, ino) {
+            return Err(libc::EIO);
+        }
+        log::debug!("Crate populated on demand: {}", archive.to_string_lossy());
+        self.evict_crates();
+        Ok(())
+    }
+
+    /// Marks `ino` as just used, if it is a resident crate directory.
+    fn touch(&mut self, ino: u64) {
+        if let Some((_, _, last_used)) = self.resident.get_mut(&ino) {
+            self.resident_tick += 1;
+            *last_used = self.resident_tick;
+        }
+    }
+
+    /// Defers the least recently used crates again while more than
+    /// `--max-resident-crates` are populated, dropping the inodes of their
+    /// contents. Crates the kernel still holds an inode of or has open are
+    /// kept, and so is the last one used, which is being listed or looked
+    /// into.
+    fn evict_crates(&mut self) {
+        let Some(max) = self.max_resident_crates else {
+            return;
+        };
+        if self.resident.len() <= max {
+            return;
+        }
+        let mut by_age = self
+            .resident
+            .iter()
+            .map(|(&ino, (_, _, last_used))| (*last_used, ino))
+            .collect::<Vec<_>>();
+        by_age.sort_unstable();
+        by_age.pop();
+        let mut excess = self.resident.len() - max;
+        for (_, ino) in by_age {
+            if excess == 0 {
+                break;
+            }
+            let mut contents = HashSet::new();
+            let mut pending = self.inodes[&ino].children.clone();
+            while let Some(child) = pending.pop() {
+                if contents.insert(child) {
+                    pending.extend(&self.inodes[&child].children);
+                }
+            }
+            if contents
+                .iter()
+                .any(|child| self.lookups.contains_key(child))
+                || self
+                    .handles
+                    .values()
+                    .any(|handle| handle.ino == ino || contents.contains(&handle.ino))
+            {
+                continue;
+            }
+            for child in &contents {
+                self.inodes.remove(child);
+            }
+            self.inodes.get_mut(&ino).unwrap().children.clear();
+            let (archive, parent, _) = self.resident.remove(&ino).unwrap();
+            log::debug!(
+                "[evict] {} ({} inodes)",
+                archive.to_string_lossy(),
+                contents.len()
+            );
+            self.deferred.insert(ino, (archive, parent));
+            excess -= 1;
+        }
+    }
+
+    /// Replies with `ino`, which the kernel then holds until it forgets it.
+    fn reply_entry(&mut self, ino: u64, reply: fuser::ReplyEntry) {
+        *self.lookups.entry(ino).or_default() += 1;
+        reply.entry(&self.ttl(ino), &self.attrs(ino).unwrap(), 0)
+    }
+
+    /// The kernel dropping `nlookup` of its references to `ino`. Crates
+    /// may be evicted once it holds none.
+    fn forget_lookups(&mut self, ino: u64, nlookup: u64) {
+        let Some(lookups) = self.lookups.get_mut(&ino) else {
+            return;
+        };
+        *lookups = lookups.saturating_sub(nlookup);
+        if *lookups == 0 {
+            self.lookups.remove(&ino);
+            self.evict_crates();
+        }
+    }
+
+    fn populate_all_deferred(&mut self) {
+        let deferred = self.deferred.keys().copied().collect::<Vec<_>>();
+        for ino in deferred {
+            let _ = self.populate_deferred(ino);
+        }
+    }
+
+    /// What `readdir` and `readdirplus` check and refresh before listing
+    /// `ino` through `fh` from `offset` on.
+    fn prepare_listing(
+        &mut self,
+        op: &str,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<(), libc::c_int> {
+        if offset == 0 && self.lists_crates(ino) {
+            self.refresh();
+        }
+        if let Err(errno) = self.populate_deferred(ino) {
+            error!("[{op}], (0x{ino:016x}) EIO");
+            return Err(errno);
+        }
+        if !self.inodes.contains_key(&ino) {
+            error!("[{op}], (0x{ino:016x}) ENOENT");
+            return Err(libc::ENOENT);
+        }
+        if self.handles.get(&fh).map(|handle| handle.ino) != Some(ino) {
+            error!("[{op}], (0x{ino:016x}) ENOBADF");
+            return Err(libc::EBADF);
+        }
+        if self.inodes.get(&ino).unwrap().attrs.kind != FileType::Directory {
+            return Err(libc::ENOTDIR);
+        }
+        self.note_shadowed(op, ino);
+        Ok(())
+    }
+
+    /// The entries of `ino` from `offset` on, each with the offset of the
+    /// one after it, which is where the kernel resumes once the reply buffer
+    /// is full.
+    fn listing(&self, ino: u64, offset: i64) -> impl Iterator<Item = (i64, u64, FileType, &OsStr)> {
+        let dots = [
+            (ino, FileType::Directory, OsStr::new(".")),
+            (ino, FileType::Directory, OsStr::new("..")),
+        ];
+        let children = self.inodes[&ino]
+            .children
+            .iter()
+            .copied()
+            .chain(self.listed_virtual_children(ino))
+            .map(|child| {
+                let inode = &self.inodes[&child];
+                (child, inode.attrs.kind, inode.name.as_os_str())
+            });
+        dots.into_iter()
+            .chain(children)
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(index, (child, kind, name))| (index as i64 + 1, child, kind, name))
+    }
+
+    const DIR_ATTR_TEMPLATE: FileAttr = FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH, // 1970-01-01 00:00:00
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::Directory,
+        perm: 0o555,
+        nlink: 2,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+        blksize: 512,
+    };
+
+    const FIL_ATTR_TEMPLATE: FileAttr = FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH, // 1970-01-01 00:00:00
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0o444,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+        blksize: BLKSIZE,
+    };
+}
+
+impl Filesystem for CargoCacheFs {
+    fn init(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        // Listings then carry the attributes of their entries, so that
+        // `ls -l` does not look each one up. Older kernels keep readdir.
+        let _ = config.add_capabilities(fuser::consts::FUSE_DO_READDIRPLUS);
+        // --strict populates before mounting.
+        if !self.inodes.contains_key(&fuser::FUSE_ROOT_ID) {
+            self.populate()?;
+        }
+        if cfg!(debug_assertions) {
+            for inconsistency in self.check_consistency() {
+                warn!("[fsck] {inconsistency}");
+            }
+        }
+        info!("Init successful!");
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        self.invalidator.disconnect();
+        self.workers.shutdown(self.shutdown_timeout);
+        for (_, handle) in std::mem::take(&mut self.handles) {
+            self.log_reads(handle);
+        }
+        if let Some(access_log) = &self.access_log {
+            access_log.flush();
+        }
+    }
+
+    fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        // fuser 0.12 drops the fh the kernel sends with `fstat`.
+        match self.attrs_for(ino, None) {
+            Some(attrs) => reply.attr(&self.ttl(ino), &attrs),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn forget(&mut self, _req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        self.forget_lookups(ino, nlookup);
+    }
+
+    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let Some(attrs) = self.attrs(ino) else {
+            warn!("[access] ino 0x{ino:016x} => ENOENT");
+            return reply.error(libc::ENOENT);
+        };
+        // The owner, group or other bits, as the kernel would pick them.
+        let granted = if req.uid() == 0 {
+            // Root reads anything, and executes what anyone may execute.
+            0o6 | u16::from(attrs.perm & 0o111 != 0)
+        } else if req.uid() == attrs.uid {
+            attrs.perm >> 6
+        } else if req.gid() == attrs.gid {
+            attrs.perm >> 3
+        } else {
+            attrs.perm
+        } & 0o7;
+        let wanted = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+        if (mask & libc::W_OK != 0 && !self.writable(ino)) || wanted & !granted != 0 {
+            warn!("[access] ino 0x{ino:016x} mask 0o{mask:o} => EACCES");
+            return reply.error(libc::EACCES);
+        }
+        reply.ok()
+    }
+
+    fn opendir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        flags: i32,
+        reply: fuser::ReplyOpen,
+    ) {
+        if flags
+            & (libc::O_APPEND
+                | libc::O_CREAT
+                | libc::O_EXCL
+                | libc::O_RDWR
+                | libc::O_WRONLY
+                | O_TRUNC)
+            != 0
+        {
+            reply.error(libc::EROFS);
+            warn!(
+                "Opendir failed because flags (0x{:x}) are not correct, ROFS",
+                flags
+                    & (libc::O_APPEND
+                        | libc::O_CREAT
+                        | libc::O_EXCL
+                        | libc::O_RDWR
+                        | libc::O_WRONLY
+                        | O_TRUNC)
+            );
+            return;
+        }
+        if let Err(errno) = self.populate_deferred(ino) {
+            reply.error(errno);
+            warn!("Opendir failed because crate (0x{ino:x}) could not be populated, EIO");
+            return;
+        }
+        if !self.inodes.contains_key(&ino) {
+            reply.error(libc::ENOENT);
+            warn!("Opendir failed because inode (0x{ino:x}) does not exist, NOENT");
+            return;
+        }
+        if self.inodes[&ino].attrs.kind != FileType::Directory {
+            warn!("[opendir] ino 0x{ino:016x} => ENOTDIR");
+            return reply.error(libc::ENOTDIR);
+        }
+        let fh = self.open_handle(ino, None);
+        reply.opened(fh, fuser::consts::FOPEN_KEEP_CACHE)
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.handles.remove(&fh);
+        reply.ok()
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        if let Err(errno) = self.prepare_listing("readdir", ino, fh, offset) {
+            return reply.error(errno);
+        }
+        let ctx = NameContext::of(ino);
+        for (next, child, kind, name) in self.listing(ino, offset) {
+            if reply.add(child, next, kind, self.name_mapper.present(name, ctx)) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectoryPlus,
+    ) {
+        if let Err(errno) = self.prepare_listing("readdirplus", ino, fh, offset) {
+            return reply.error(errno);
+        }
+        let ctx = NameContext::of(ino);
+        let mut listed = vec![];
+        for (next, child, _, name) in self.listing(ino, offset) {
+            // As lookup answers.
+            let attrs = self.attrs(child).unwrap();
+            let name = self.name_mapper.present(name, ctx);
+            if reply.add(child, next, name, &self.ttl(child), &attrs, 0) {
+                break;
+            }
+            // Every entry but `.` and `..` is then held by the kernel, as
+            // after a lookup.
+            if next > 2 {
+                listed.push(child);
+            }
+        }
+        for child in listed {
+            *self.lookups.entry(child).or_default() += 1;
+        }
+        reply.ok()
+    }
+
+    fn lookup(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        if self.lists_crates(parent) {
+            self.refresh();
+        }
+        if let Err(errno) = self.populate_deferred(parent) {
+            warn!(
+                "[lookup] par 0x{parent:016x} name {} => EIO",
+                name.to_string_lossy()
+            );
+            return reply.error(errno);
+        }
+        if !self.inodes.contains_key(&parent) {
+            warn!(
+                "[lookup] par 0x{parent:016x} name {} => ENOENT",
+                name.to_string_lossy()
+            );
+            return reply.error(libc::ENOENT);
+        }
+        if self.inodes.get(&parent).unwrap().attrs.kind != FileType::Directory {
+            warn!(
+                "[lookup] par 0x{parent:016x} name {} => ENOTDIR",
+                name.to_string_lossy()
+            );
+            return reply.error(libc::ENOTDIR);
+        }
+        let ctx = NameContext::of(parent);
+        let Some(name) = self.name_mapper.resolve(name, ctx) else {
+            return self.reply_negative(parent, reply);
+        };
+        self.note_shadowed("lookup", parent);
+        let child = self.inodes[&parent]
+            .children
+            .iter()
+            .find(|child| self.inodes[child].name == *name);
+        if let Some(&child) = child {
+            return self.reply_entry(child, reply);
+        }
+        if let Some(child) = self
+            .icase
+            .then(|| self.icase_child(parent, &name))
+            .flatten()
+        {
+            return self.reply_entry(child, reply);
+        }
+        if let Some(&alias) = self.crate_aliases.get(&(parent, name.to_os_string())) {
+            return self.reply_entry(alias, reply);
+        }
+        let generated = self
+            .virtual_children(parent)
+            .find(|child| self.inodes[child].name == *name);
+        if let Some(child) = generated {
+            return self.reply_entry(child, reply);
+        }
+        self.reply_negative(parent, reply)
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        let Some(inode) = self.inodes.get(&ino) else {
+            warn!("[readlink] ino 0x{ino:016x} => ENOENT");
+            return reply.error(libc::ENOENT);
+        };
+        match &inode.link_target {
+            Some(target) => reply.data(
+                self.name_mapper
+                    .present_target(target.as_os_str())
+                    .as_bytes(),
+            ),
+            None => {
+                warn!("[readlink] ino 0x{ino:016x} => EINVAL");
+                reply.error(libc::EINVAL)
+            }
+        }
+    }
+
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        // Summed on every call, since crates populated on demand keep
+        // adding inodes.
+        let blocks = self
+            .inodes
+            .values()
+            .map(|inode| inode.attrs.size.div_ceil(BLKSIZE.into()))
+            .sum();
+        let files = self.inodes.len() as u64;
+        reply.statfs(blocks, 0, 0, files, 0, BLKSIZE, 255, BLKSIZE)
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        if !self.inodes.contains_key(&ino) {
+            warn!(
+                "[getxattr] ino 0x{ino:016x} name {} => ENOENT",
+                name.to_string_lossy()
+            );
+            return reply.error(libc::ENOENT);
+        }
+        // Nothing carries the POSIX ACL attributes (system.posix_acl_access/
+        // default) that archivers probe: the mode bits are the whole story.
+        let value = match name
+            .to_str()
+            .and_then(|name| name.strip_prefix(XATTR_PREFIX))
+        {
+            Some(name) => self.xattr(ino, name),
+            None => Ok(None),
+        };
+        match value {
+            Ok(Some(value)) => reply_xattr(&value, size, reply),
+            Ok(None) => reply.error(libc::ENODATA),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn listxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        if !self.inodes.contains_key(&ino) {
+            warn!("[listxattr] ino 0x{ino:016x} => ENOENT");
+            return reply.error(libc::ENOENT);
+        }
+        let mut names = vec![];
+        if self.inodes[&ino].krate_path.is_some() {
+            for name in XATTRS {
+                names.extend_from_slice(XATTR_PREFIX.as_bytes());
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+        reply_xattr(&names, size, reply)
+    }
+
+    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let writing = flags
+            & (libc::O_APPEND
+                | libc::O_CREAT
+                | libc::O_EXCL
+                | libc::O_RDWR
+                | libc::O_WRONLY
+                | O_TRUNC)
+            != 0;
+        if writing && !self.writable(ino) {
+            reply.error(libc::EROFS);
+            warn!(
+                "Open failed because flags (0x{:x}) are not correct, ROFS",
+                flags
+                    & (libc::O_APPEND
+                        | libc::O_CREAT
+                        | libc::O_EXCL
+                        | libc::O_RDWR
+                        | libc::O_WRONLY
+                        | O_TRUNC)
+            );
+            return;
+        }
+        if !self.inodes.contains_key(&ino) {
+            reply.error(libc::ENOENT);
+            warn!("Open failed because inode (0x{ino:x}) does not exist, NOENT");
+            return;
+        }
+        if self.inodes[&ino].attrs.kind == FileType::Directory {
+            warn!("[open] ino 0x{ino:016x} => EISDIR");
+            return reply.error(libc::EISDIR);
+        }
+        if writing && self.control_action(ino).is_none() {
+            if let Err(errno) = self.copy_up(ino, flags & O_TRUNC != 0) {
+                return reply.error(errno);
+            }
+        }
+        let throttle = self
+            .max_read_bps_per_handle
+            .map(|bytes_per_second| Arc::new(throttle::RateLimiter::new(bytes_per_second)));
+        let krate = self.inodes[&ino].krate_path.as_ref();
+        if let Some(&(_, krate)) = krate.and_then(|krate| self.crate_dirs.get(&**krate)) {
+            self.touch(krate);
+        }
+        let fh = self.open_handle(ino, throttle);
+        self.pin(fh);
+        if self.virtual_files.contains_key(&ino) {
+            // Read straight from the snapshot, whatever size was reported.
+            self.handles.get_mut(&fh).unwrap().control = Some(self.virtual_snapshot(ino));
+            return reply.opened(fh, fuser::consts::FOPEN_DIRECT_IO);
+        }
+        self.handles.get_mut(&fh).unwrap().append = flags & libc::O_APPEND != 0;
+        // What is in `--overlay` can change under the page cache.
+        if self.inodes[&ino].overlay_path.is_some() {
+            return reply.opened(fh, 0);
+        }
+        reply.opened(fh, fuser::consts::FOPEN_KEEP_CACHE)
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        let Some(handle) = self.handles.get(&fh).filter(|handle| handle.ino == ino) else {
+            warn!("[lseek] ino 0x{ino:016x} fh 0x{fh:016x} => EBADF");
+            return reply.error(libc::EBADF);
+        };
+        let size = match (
+            &handle.control,
+            self.replaced_pin(handle),
+            self.inodes.get(&ino),
+        ) {
+            (Some(contents), _, _) => contents.len() as u64,
+            (None, Some(pinned), _) => pinned.attrs.size,
+            (None, None, Some(inode)) => inode.attrs.size,
+            (None, None, None) => {
+                warn!("[lseek] ino 0x{ino:016x} fh 0x{fh:016x} => ESTALE");
+                return reply.error(libc::ESTALE);
+            }
+        } as i64;
+        // Files are all data, with only the implicit hole at the end.
+        match whence {
+            _ if offset < 0 => {
+                warn!("[lseek] ino 0x{ino:016x} offset {offset} => EINVAL");
+                reply.error(libc::EINVAL)
+            }
+            libc::SEEK_DATA if offset < size => reply.offset(offset),
+            libc::SEEK_HOLE if offset < size => reply.offset(size),
+            libc::SEEK_DATA | libc::SEEK_HOLE => reply.error(libc::ENXIO),
+            // The kernel handles the others itself.
+            _ => {
+                warn!("[lseek] ino 0x{ino:016x} whence {whence} => EINVAL");
+                reply.error(libc::EINVAL)
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if let Some(handle) = self.handles.remove(&fh) {
+            if let Some(throttle) = &handle.throttle {
+                self.released_throttle_waited += throttle.waited();
+            }
+            let current = handle
+                .pinned
+                .as_ref()
+                .filter(|pinned| self.is_current(pinned));
+            if let Some(pinned) = current {
+                if let Some(file) = pinned.archive.lock().unwrap().take() {
+                    self.workers.checkin(pinned.krate_path.to_path_buf(), file);
+                }
+            }
+            self.log_reads(handle);
+        }
+        reply.ok()
+    }
+
+    fn read(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        if self.handles.get(&fh).map(|handle| handle.ino) != Some(ino) {
+            warn!("[read] ino 0x{ino:016x} fh 0x{fh:016x} => EBADF");
+            return reply.error(libc::EBADF);
+        }
+        let handle = &self.handles[&fh];
+        if let Some(contents) = &handle.control {
+            return reply.data(worker::slice(contents, offset, size));
+        }
+        if self.replaced_pin(handle).is_some() {
+            let handle = self.handles.get_mut(&fh).unwrap();
+            let served = handle.served(req);
+            let job = read_job(handle, served, offset, size, reply);
+            return self.workers.submit(job);
+        }
+        if !self.inodes.contains_key(&ino) {
+            warn!("[read] ino 0x{ino:016x} fh 0x{fh:016x} => ESTALE");
+            return reply.error(libc::ESTALE);
+        }
+        if let Some(report) = self.verification_reports.get(&ino) {
+            return reply.data(worker::slice(report, offset, size));
+        }
+        let inode = self.inodes.get(&ino).unwrap();
+        if let Some(copy) = &inode.overlay_path {
+            return match File::open(copy).and_then(|file| worker::read_at(&file, offset, size)) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    warn!("[read] ino 0x{ino:016x} fh 0x{fh:016x} => {e}");
+                    reply.error(e.raw_os_error().unwrap_or(libc::EIO))
+                }
+            };
+        }
+        if inode.krate_path.is_none() {
+            if inode.attrs.kind == FileType::Directory {
+                warn!("[read] ino 0x{ino:016x} fh 0x{fh:016x} => EISDIR");
+                return reply.error(libc::EISDIR);
+            }
+            warn!("[read] ino 0x{ino:016x} fh 0x{fh:016x} => EINVAL");
+            return reply.error(libc::EINVAL);
+        }
+        if inode.attrs.kind == FileType::Symlink {
+            warn!("[read] ino 0x{ino:016x} fh 0x{fh:016x} => EINVAL");
+            return reply.error(libc::EINVAL);
+        }
+
+        let handle = self.handles.get_mut(&fh).unwrap();
+        let served = handle.served(req);
+        if inode.attrs.size == 0 {
+            return reply.data(&[]);
+        }
+        if handle.pinned.is_none() {
+            warn!("[read] ino 0x{ino:016x} fh 0x{fh:016x} => EBADF");
+            return reply.error(libc::EBADF);
+        }
+        let job = read_job(handle, served, offset, size, reply);
+        self.workers.submit(job);
+    }
+
+    fn write(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        let Some(handle) = self.handles.get(&fh).filter(|handle| handle.ino == ino) else {
+            warn!("[write] ino 0x{ino:016x} fh 0x{fh:016x} => EBADF");
+            return reply.error(libc::EBADF);
+        };
+        if let Some(action) = self.control_action(ino) {
+            return match self.perform(action, data) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(errno) => reply.error(errno),
+            };
+        }
+        let append = handle.append;
+        let copy = match self.copy_up(ino, false) {
+            Ok(copy) => copy,
+            Err(errno) => return reply.error(errno),
+        };
+        let written = File::options().write(true).open(copy).and_then(|file| {
+            let offset = match append {
+                true => file.metadata()?.len(),
+                false => offset as u64,
+            };
+            file.write_all_at(data, offset)?;
+            file.metadata()
+        });
+        match written {
+            Ok(metadata) => {
+                self.overlay_attrs(ino, &metadata);
+                reply.written(data.len() as u32)
+            }
+            Err(e) => {
+                warn!("[write] ino 0x{ino:016x} fh 0x{fh:016x} => {e}");
+                reply.error(e.raw_os_error().unwrap_or(libc::EIO))
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        if !self.writable(ino) {
+            warn!("[setattr] ino 0x{ino:016x} => EROFS");
+            return reply.error(libc::EROFS);
+        }
+        let Some(attrs) = self.attrs(ino) else {
+            warn!("[setattr] ino 0x{ino:016x} => ENOENT");
+            return reply.error(libc::ENOENT);
+        };
+        // Truncated on the way to being written to, with nothing to lose.
+        if self.control_action(ino).is_some() && size.is_none_or(|size| size == 0) {
+            return reply.attr(&self.ttl(ino), &attrs);
+        }
+        // Only the data and mode of files are kept, and only by the owner.
+        if attrs.kind != FileType::RegularFile
+            || uid.is_some_and(|uid| uid != attrs.uid)
+            || gid.is_some_and(|gid| gid != attrs.gid)
+        {
+            warn!("[setattr] ino 0x{ino:016x} => EPERM");
+            return reply.error(libc::EPERM);
+        }
+        let copy = match self.copy_up(ino, size == Some(0)) {
+            Ok(copy) => copy,
+            Err(errno) => return reply.error(errno),
+        };
+        let time = |time| match time {
+            fuser::TimeOrNow::SpecificTime(time) => time,
+            fuser::TimeOrNow::Now => SystemTime::now(),
+        };
+        let changed = File::options().write(true).open(copy).and_then(|file| {
+            if let Some(size) = size {
+                file.set_len(size)?;
+            }
+            if let Some(mode) = mode {
+                file.set_permissions(Permissions::from_mode(mode & 0o7777))?;
+            }
+            let mut times = std::fs::FileTimes::new();
+            if let Some(atime) = atime {
+                times = times.set_accessed(time(atime));
+            }
+            if let Some(mtime) = mtime {
+                times = times.set_modified(time(mtime));
+            }
+            file.set_times(times)?;
+            file.metadata()
+        });
+        match changed {
+            Ok(metadata) => {
+                self.overlay_attrs(ino, &metadata);
+                reply.attr(&self.ttl(ino), &self.attrs(ino).unwrap())
+            }
+            Err(e) => {
+                warn!("[setattr] ino 0x{ino:016x} => {e}");
+                reply.error(e.raw_os_error().unwrap_or(libc::EIO))
+            }
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        let Some(name) = self.name_mapper.resolve(name, NameContext::of(parent)) else {
+            return reply.error(libc::EINVAL);
+        };
+        let copy = match self.overlay_child(parent, &name) {
+            Ok(copy) => copy,
+            Err(errno) => {
+                warn!(
+                    "[create] par 0x{parent:016x} name {} => {}",
+                    name.to_string_lossy(),
+                    std::io::Error::from_raw_os_error(errno)
+                );
+                return reply.error(errno);
+            }
+        };
+        let created = std::fs::create_dir_all(copy.parent().unwrap())
+            .and_then(|()| File::create(&copy))
+            .and_then(|file| {
+                file.set_permissions(Permissions::from_mode(mode & !umask & 0o7777))?;
+                file.metadata()
+            });
+        let ino =
+            match created.map(|metadata| self.add_overlay_file(parent, &name, copy, &metadata)) {
+                Ok(Ok(ino)) => ino,
+                Ok(Err(e)) => {
+                    error!("[create] {e:#}");
+                    return reply.error(libc::ENOSPC);
+                }
+                Err(e) => {
+                    warn!(
+                        "[create] par 0x{parent:016x} name {} => {e}",
+                        name.to_string_lossy()
+                    );
+                    return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+                }
+            };
+        let fh = self.open_handle(ino, None);
+        self.handles.get_mut(&fh).unwrap().append = flags & libc::O_APPEND != 0;
+        *self.lookups.entry(ino).or_default() += 1;
+        reply.created(&self.ttl(ino), &self.attrs(ino).unwrap(), 0, fh, 0)
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        let Some(name) = self.name_mapper.resolve(name, NameContext::of(parent)) else {
+            return reply.error(libc::EINVAL);
+        };
+        let copy = match self.overlay_child(parent, &name) {
+            Ok(copy) => copy,
+            Err(errno) => {
+                warn!(
+                    "[mkdir] par 0x{parent:016x} name {} => {}",
+                    name.to_string_lossy(),
+                    std::io::Error::from_raw_os_error(errno)
+                );
+                return reply.error(errno);
+            }
+        };
+        let perm = mode & !umask & 0o7777;
+        let made = std::fs::create_dir_all(&copy)
+            .and_then(|()| std::fs::set_permissions(&copy, Permissions::from_mode(perm)));
+        if let Err(e) = made {
+            warn!(
+                "[mkdir] par 0x{parent:016x} name {} => {e}",
+                name.to_string_lossy()
+            );
+            return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+        let ino = match self.child_dir(parent, &name) {
+            Ok(ino) => ino,
+            Err(e) => {
+                error!("[mkdir] {e:#}");
+                return reply.error(libc::ENOSPC);
+            }
+        };
+        let attrs = &mut self.inodes.get_mut(&ino).unwrap().attrs;
+        attrs.perm = perm as u16;
+        (attrs.mtime, attrs.ctime) = (SystemTime::now(), SystemTime::now());
+        self.reply_entry(ino, reply)
+    }
+
+    fn unlink(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let Some(name) = self.name_mapper.resolve(name, NameContext::of(parent)) else {
+            return reply.error(libc::ENOENT);
+        };
+        if let Err(errno) = self.populate_deferred(parent) {
+            return reply.error(errno);
+        }
+        if !self.writable(parent) {
+            warn!(
+                "[unlink] par 0x{parent:016x} name {} => EROFS",
+                name.to_string_lossy()
+            );
+            return reply.error(libc::EROFS);
+        }
+        let Some(child) = self.child_named(parent, &name) else {
+            warn!(
+                "[unlink] par 0x{parent:016x} name {} => ENOENT",
+                name.to_string_lossy()
+            );
+            return reply.error(libc::ENOENT);
+        };
+        if self.inodes[&child].attrs.kind == FileType::Directory {
+            warn!(
+                "[unlink] par 0x{parent:016x} name {} => EISDIR",
+                name.to_string_lossy()
+            );
+            return reply.error(libc::EISDIR);
+        }
+        let Some((stem, path)) = self.crate_relative_path(child) else {
+            warn!(
+                "[unlink] par 0x{parent:016x} name {} => EROFS",
+                name.to_string_lossy()
+            );
+            return reply.error(libc::EROFS);
+        };
+        let inode = &self.inodes[&child];
+        let overlay = self.overlay.as_ref().unwrap();
+        // The archive still has the file: a whiteout hides it from then on.
+        let removed = match &inode.overlay_path {
+            Some(copy) => std::fs::remove_file(copy),
+            None => Ok(()),
+        }
+        .and_then(|()| match inode.krate_path {
+            Some(_) => overlay.whiteout(&stem, &path),
+            None => Ok(()),
+        });
+        if let Err(e) = removed {
+            warn!(
+                "[unlink] par 0x{parent:016x} name {} => {e}",
+                name.to_string_lossy()
+            );
+            return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+        self.remove_subtree(parent, child);
+        reply.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{populated, Corruption, Fixture, TempDir};
+
+    fn cache() -> TempDir {
+        let cache = TempDir::new("cache");
+        Fixture::new("one-0.1.0")
+            .file("Cargo.toml", b"[package]\n")
+            .file("src/lib.rs", b"// lib\n")
+            .write_to(cache.path());
+        cache
+    }
+
+    #[test]
+    fn inode_numbers_do_not_wrap_around() {
+        let cache = cache();
+        let mut fs = populated(cache.path(), &testing::options());
+        fs.next_inode = u64::MAX;
+        let error = fs.next_inode().unwrap_err();
+        assert!(error.to_string().contains("exhausted"), "{error}");
+        assert_eq!(fs.next_inode, u64::MAX);
+    }
+
+    #[test]
+    fn exhaustion_drops_the_crate_being_populated() {
+        let cache = cache();
+        let mut fs = populated(cache.path(), &testing::options());
+        let krate = fs
+            .child_named(fuser::FUSE_ROOT_ID, OsStr::new("one-0.1.0"))
+            .unwrap();
+        // Enough for `Cargo.toml`, not for `src`.
+        fs.next_inode = u64::MAX - 1;
+        assert_eq!(fs.populate_deferred(krate), Err(libc::EIO));
+        assert!(fs.names(fuser::FUSE_ROOT_ID).is_empty());
+        assert!(matches!(
+            fs.violations.last(),
+            Some(Violation::CorruptCrate { error, .. }) if error.contains("exhausted")
+        ));
+        assert!(fs.check_consistency().is_empty());
+    }
+
+    /// `a` is the newest and largest, `b` the oldest, `c` the smallest.
+    fn three_crates() -> TempDir {
+        let cache = TempDir::new("cache");
+        for (stem, len, age) in [
+            ("a-1.0.0", 4000, 0),
+            ("b-1.0.0", 2000, 20),
+            ("c-1.0.0", 10, 10),
+        ] {
+            let archive = Fixture::new(stem)
+                .file("Cargo.toml", &testing::noise(len, len as u64))
+                .write_to(cache.path());
+            File::options()
+                .write(true)
+                .open(archive)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(3600 + age))
+                .unwrap();
+        }
+        cache
+    }
+
+    fn sorted_stems(cache: &Path, order: PopulateOrder) -> Vec<String> {
+        let mut crates = ["a-1.0.0", "b-1.0.0", "c-1.0.0"]
+            .map(|stem| {
+                let path = cache.join(format!("{stem}.crate"));
+                let metadata = std::fs::metadata(&path).unwrap();
+                (path, metadata)
+            })
+            .to_vec();
+        order.sort(&mut crates);
+        crates
+            .iter()
+            .map(|(path, _)| archive::stem(path).unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn populate_orders() {
+        let cache = three_crates();
+        let stems = |order| sorted_stems(cache.path(), order);
+        assert_eq!(
+            stems(PopulateOrder::Mtime),
+            ["a-1.0.0", "c-1.0.0", "b-1.0.0"]
+        );
+        assert_eq!(
+            stems(PopulateOrder::SizeAscending),
+            ["c-1.0.0", "b-1.0.0", "a-1.0.0"]
+        );
+        let listed = vec!["b-1.0.0".into(), "missing-1.0.0".into()];
+        assert_eq!(
+            stems(PopulateOrder::Prefetch(listed)),
+            ["b-1.0.0", "a-1.0.0", "c-1.0.0"]
+        );
+    }
+
+    fn stats(fs: &CargoCacheFs) -> String {
+        String::from_utf8(fs.control_contents(ControlFile::Stats)).unwrap()
+    }
+
+    #[test]
+    fn a_spent_budget_defers_crates_until_they_are_used() {
+        let cache = three_crates();
+        let mut fs = populated(cache.path(), &testing::options());
+        assert_eq!(fs.deferred.len(), 3);
+        assert!(stats(&fs).contains("crates_deferred 3\n"), "{}", stats(&fs));
+        let toml = fs.find("b-1.0.0/Cargo.toml").unwrap();
+        assert_eq!(fs.inodes[&toml].attrs.size, 2000);
+        assert_eq!(fs.deferred.len(), 2);
+        assert!(stats(&fs).contains("crates_deferred 2\n"), "{}", stats(&fs));
+    }
+
+    #[test]
+    fn crates_within_the_budget_are_populated_at_mount() {
+        let cache = three_crates();
+        for budget in [None, Some(Duration::from_secs(3600))] {
+            let options = cli::Options {
+                populate_budget: budget,
+                ..testing::options()
+            };
+            let fs = populated(cache.path(), &options);
+            assert!(fs.deferred.is_empty(), "{budget:?}");
+            assert!(stats(&fs).contains("crates_deferred 0\n"), "{}", stats(&fs));
+            let krate = fs
+                .child_named(fuser::FUSE_ROOT_ID, OsStr::new("a-1.0.0"))
+                .unwrap();
+            assert_eq!(fs.names(krate), ["Cargo.toml"]);
+        }
+    }
+
+    #[test]
+    fn each_problem_is_collected_as_its_violation() {
+        let regular = tar::EntryType::Regular;
+        let cases = [
+            (
+                "corrupt-crate",
+                Fixture::new("bad-0.1.0").file("Cargo.toml", &testing::noise(4096, 1)),
+            ),
+            (
+                "prefix-mismatch",
+                Fixture::new("bad-0.1.0").raw(b"other-0.1.0/Cargo.toml", regular, b""),
+            ),
+            (
+                "path-traversal",
+                Fixture::new("bad-0.1.0").raw(b"bad-0.1.0/../escaped", regular, b""),
+            ),
+            (
+                "duplicate-entry",
+                Fixture::new("bad-0.1.0")
+                    .file("Cargo.toml", b"first")
+                    .duplicate("Cargo.toml", b"second"),
+            ),
+            (
+                "mtime-out-of-range",
+                Fixture::new("bad-0.1.0").file_at("Cargo.toml", b"", i64::MAX as u64),
+            ),
+            (
+                "size-mismatch",
+                Fixture::new("bad-0.1.0").file("Cargo.toml", &[b'x'; 4096]),
+            ),
+            (
+                "checksum-mismatch",
+                Fixture::new("bad-0.1.0").file("Cargo.toml", b""),
+            ),
+            (
+                "dangling-link",
+                Fixture::new("bad-0.1.0").hard_link("link", "missing"),
+            ),
+            (
+                "unsupported-entry",
+                Fixture::new("bad-0.1.0").raw(b"bad-0.1.0/fifo", tar::EntryType::Fifo, b""),
+            ),
+        ];
+        for (kind, fixture) in cases {
+            let cache = TempDir::new("cache");
+            let archive = cache.path().join("bad-0.1.0.crate");
+            let mut options = testing::options();
+            match kind {
+                // Cut off inside the data, and in the middle of the
+                // deflate stream for a corrupt crate.
+                "corrupt-crate" => {
+                    fixture.write_corrupt_to(cache.path(), Corruption::TruncatedGzip);
+                }
+                "size-mismatch" => {
+                    let tar = fixture.into_tar();
+                    testing::write_gzip(&archive, &tar[..512 + 100]);
+                }
+                "checksum-mismatch" => {
+                    fixture.write_to(cache.path());
+                    let checksums = cache.path().join("SHA256SUMS");
+                    std::fs::write(&checksums, format!("{:064} bad-0.1.0.crate\n", 0)).unwrap();
+                    options.verify = true;
+                    options.checksums = Some(checksums);
+                }
+                _ => {
+                    fixture.write_to(cache.path());
+                }
+            }
+            let mut fs = populated(cache.path(), &options);
+            fs.populate_all_deferred();
+            let kinds = fs
+                .violations
+                .iter()
+                .map(Violation::kind)
+                .collect::<Vec<_>>();
+            assert_eq!(kinds, [kind], "{:?}", fs.violations);
+        }
+    }
+
+    #[test]
+    fn damaged_archives_are_left_out_as_corrupt() {
+        for corruption in [Corruption::TruncatedGzip, Corruption::BadChecksum(1)] {
+            let cache = TempDir::new("cache");
+            Fixture::new("bad-0.1.0")
+                .file("Cargo.toml", b"toml")
+                .file("src/lib.rs", &testing::noise(4096, 1))
+                .write_corrupt_to(cache.path(), corruption);
+            Fixture::new("good-0.1.0")
+                .file("Cargo.toml", b"toml")
+                .write_to(cache.path());
+            let mut fs = populated(cache.path(), &testing::options());
+            fs.populate_all_deferred();
+            assert!(
+                matches!(
+                    &fs.violations[..],
+                    [Violation::CorruptCrate { krate, .. }] if krate == "bad-0.1.0"
+                ),
+                "{corruption:?}: {:?}",
+                fs.violations
+            );
+            assert_eq!(
+                fs.names(fuser::FUSE_ROOT_ID),
+                ["good-0.1.0"],
+                "{corruption:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn current_directory_components_are_dropped() {
+        let cache = TempDir::new("cache");
+        Fixture::new("dots-0.1.0")
+            .raw(b"./", tar::EntryType::Directory, b"")
+            .raw(b"./dots-0.1.0/Cargo.toml", tar::EntryType::Regular, b"toml")
+            .raw(
+                b"dots-0.1.0/./src/./lib.rs",
+                tar::EntryType::Regular,
+                b"lib",
+            )
+            .raw(b"dots-0.1.0/src/.", tar::EntryType::Directory, b"")
+            .raw(b"dots-0.1.0/./", tar::EntryType::Directory, b"")
+            .write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let krate = fs.find("dots-0.1.0").unwrap();
+        assert_eq!(fs.names(fuser::FUSE_ROOT_ID), ["dots-0.1.0"]);
+        assert_eq!(fs.names(krate), ["Cargo.toml", "src"]);
+        let src = fs.find("dots-0.1.0/src").unwrap();
+        assert_eq!(fs.names(src), ["lib.rs"]);
+        let lib = fs.find("dots-0.1.0/src/lib.rs").unwrap();
+        assert_eq!(fs.inodes[&lib].attrs.size, 3);
+        assert!(fs.violations.is_empty(), "{:?}", fs.violations);
+        assert!(fs.check_consistency().is_empty());
+    }
+
+    #[test]
+    fn later_duplicates_replace_earlier_entries_but_not_directories() {
+        let cache = TempDir::new("cache");
+        Fixture::new("twice-0.1.0")
+            .file("file", b"first")
+            .file("file", b"second!")
+            .raw(b"twice-0.1.0/dir/", tar::EntryType::Directory, b"")
+            .file("dir/inner", b"inner")
+            .file("dir", b"not a directory")
+            .file("over", b"a file")
+            .raw(b"twice-0.1.0/over/", tar::EntryType::Directory, b"")
+            .file("over/below", b"below a file")
+            .write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let krate = fs.find("twice-0.1.0").unwrap();
+        assert_eq!(fs.names(krate), ["file", "dir", "over"]);
+        let size = |fs: &mut CargoCacheFs, path: &str| {
+            let ino = fs.find(&format!("twice-0.1.0/{path}")).unwrap();
+            (fs.inodes[&ino].attrs.kind, fs.inodes[&ino].attrs.size)
+        };
+        assert_eq!(size(&mut fs, "file"), (FileType::RegularFile, 7));
+        assert_eq!(size(&mut fs, "dir/inner"), (FileType::RegularFile, 5));
+        // A directory replaces a file, so what is below it is kept.
+        assert_eq!(size(&mut fs, "over").0, FileType::Directory);
+        assert_eq!(size(&mut fs, "over/below"), (FileType::RegularFile, 12));
+        let duplicated = fs
+            .violations
+            .iter()
+            .map(|violation| match violation {
+                Violation::DuplicateEntry { krate, path } => format!("{krate}: {path}"),
+                other => panic!("{other}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            duplicated,
+            [
+                "twice-0.1.0: twice-0.1.0/file",
+                "twice-0.1.0: twice-0.1.0/dir",
+                "twice-0.1.0: twice-0.1.0/over",
+            ]
+        );
+        assert!(fs.check_consistency().is_empty());
+    }
+
+    #[test]
+    fn entries_of_one_archive_stay_out_of_another_crate() {
+        let cache = TempDir::new("cache");
+        Fixture::new("serde-1.0.193")
+            .file("src/lib.rs", b"// serde")
+            .write_to(cache.path());
+        Fixture::new("evil-1.0.0")
+            .raw(
+                b"serde-1.0.193/src/lib.rs",
+                tar::EntryType::Regular,
+                b"// evil",
+            )
+            .raw(b"serde-1.0.193/src/lib.rs/", tar::EntryType::Directory, b"")
+            .raw(b"README", tar::EntryType::Regular, b"stray")
+            .write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        assert_eq!(
+            fs.names(fuser::FUSE_ROOT_ID),
+            ["evil-1.0.0", "serde-1.0.193"]
+        );
+        let serde = fs.find("serde-1.0.193/src/lib.rs").unwrap();
+        assert_eq!(fs.inodes[&serde].attrs.size, 8);
+        assert_eq!(fs.inodes[&serde].attrs.kind, FileType::RegularFile);
+        let evil = fs.find("evil-1.0.0").unwrap();
+        assert_eq!(fs.names(evil), ["serde-1.0.193", "README"]);
+        let inner = fs.find("evil-1.0.0/serde-1.0.193/src/lib.rs").unwrap();
+        assert_eq!(fs.inodes[&inner].attrs.kind, FileType::Directory);
+        assert!(fs.check_consistency().is_empty());
+    }
+
+    #[test]
+    fn tar_mtimes_are_clamped() {
+        let seconds = |secs: i64| system_time_from_tar(secs as u64);
+        assert_eq!(seconds(0), (UNIX_EPOCH, false));
+        assert_eq!(seconds(-1), (UNIX_EPOCH - Duration::from_secs(1), false));
+        let min = UNIX_EPOCH - Duration::from_secs(MIN_MTIME.unsigned_abs());
+        assert_eq!(seconds(MIN_MTIME), (min, false));
+        assert_eq!(seconds(MIN_MTIME - 1), (min, true));
+        assert_eq!(seconds(i64::MIN), (min, true));
+        let max = UNIX_EPOCH + Duration::from_secs(MAX_MTIME as u64);
+        assert_eq!(seconds(MAX_MTIME), (max, false));
+        assert_eq!(seconds(MAX_MTIME + 1), (max, true));
+        assert_eq!(seconds(i64::MAX), (max, true));
+    }
+
+    #[test]
+    fn out_of_range_mtimes_are_clamped_and_reported_once() {
+        let cache = TempDir::new("cache");
+        Fixture::new("times-0.1.0")
+            .file_at("epoch", b"", 0)
+            .file_at("before", b"", -1i64 as u64)
+            .file_at("far", b"", i64::MAX as u64)
+            .file_at("farther", b"", i64::MAX as u64)
+            .write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let mut mtime = |name: &str| {
+            let ino = fs.find(&format!("times-0.1.0/{name}")).unwrap();
+            fs.inodes[&ino].attrs.mtime
+        };
+        assert_eq!(mtime("epoch"), UNIX_EPOCH);
+        assert_eq!(mtime("before"), UNIX_EPOCH - Duration::from_secs(1));
+        let max = UNIX_EPOCH + Duration::from_secs(MAX_MTIME as u64);
+        assert_eq!(mtime("far"), max);
+        assert_eq!(mtime("farther"), max);
+        let clamped = fs
+            .violations
+            .iter()
+            .filter_map(|violation| match violation {
+                Violation::MtimeOutOfRange { krate, path } => Some((krate.as_str(), path.as_str())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(clamped, [("times-0.1.0", "times-0.1.0/far")]);
+    }
+
+    #[test]
+    fn forgotten_crates_are_evicted_beyond_max_resident_crates() {
+        let cache = TempDir::new("cache");
+        let stems = ["a-1.0.0", "b-1.0.0", "c-1.0.0", "d-1.0.0", "e-1.0.0"];
+        for stem in stems {
+            Fixture::new(stem)
+                .file("Cargo.toml", b"[package]\n")
+                .file("src/lib.rs", b"// lib\n")
+                .write_to(cache.path());
+        }
+        let options = cli::Options {
+            max_resident_crates: Some(2),
+            ..testing::options()
+        };
+        let mut fs = populated(cache.path(), &options);
+        let unpopulated = fs.inodes.len();
+        // Each looked up as the kernel would, and held on to.
+        let mut held = vec![];
+        for stem in stems {
+            let lib = fs.find(&format!("{stem}/src/lib.rs")).unwrap();
+            *fs.lookups.entry(lib).or_default() += 2;
+            held.push(lib);
+        }
+        assert_eq!(fs.resident.len(), 5);
+        assert_eq!(fs.inodes.len(), unpopulated + 5 * 3);
+        for &lib in &held {
+            fs.forget_lookups(lib, 1);
+        }
+        assert_eq!(fs.resident.len(), 5);
+        for &lib in &held {
+            fs.forget_lookups(lib, 1);
+        }
+        assert_eq!(fs.resident.len(), 2);
+        assert_eq!(fs.inodes.len(), unpopulated + 2 * 3);
+        assert_eq!(fs.deferred.len(), 3);
+        assert!(fs.check_consistency().is_empty());
+        // Populated again when next used, under inode numbers never
+        // handed out before.
+        let lib = fs.find("a-1.0.0/src/lib.rs").unwrap();
+        assert!(lib > *held.iter().max().unwrap());
+        assert_eq!(fs.resident.len(), 2);
+        assert_eq!(fs.inodes.len(), unpopulated + 2 * 3);
+    }
+
+    #[test]
+    fn paths_with_repeated_components_resolve_to_their_own_entries() {
+        let cache = TempDir::new("cache");
+        let files = [
+            "src/lib.rs",
+            "src/src/lib.rs",
+            "src/src/src/lib.rs",
+            "lib/lib/lib",
+            "a/b/a/b",
+            "b/a/b/a",
+        ];
+        let mut krate = Fixture::new("repeat-0.1.0");
+        // Each of a different size, to tell them apart.
+        for (i, path) in files.iter().enumerate() {
+            krate = krate.file(path, &vec![b'x'; i + 1]);
+        }
+        krate.write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let krate = fs.find("repeat-0.1.0").unwrap();
+        for (i, path) in files.iter().enumerate() {
+            let ino = fs.find(&format!("repeat-0.1.0/{path}")).unwrap();
+            assert_eq!(fs.inodes[&ino].attrs.size, i as u64 + 1, "{path}");
+            assert_eq!(fs.inodes[&ino].entry_index, i, "{path}");
+            assert_eq!(fs.path_in_crate(ino), Some((krate, PathBuf::from(path))));
+            assert_eq!(
+                fs.mount_path(ino),
+                Path::new("repeat-0.1.0").join(path),
+                "{path}"
+            );
+        }
+        // Only a name per inode, and one archive path for all of them.
+        let mut contents = vec![];
+        let mut pending = fs.inodes[&krate].children.clone();
+        while let Some(ino) = pending.pop() {
+            pending.extend(&fs.inodes[&ino].children);
+            contents.push(&fs.inodes[&ino]);
+        }
+        let mut names = HashSet::new();
+        for path in files {
+            let mut parent = PathBuf::new();
+            for name in Path::new(path) {
+                parent.push(name);
+                names.insert(parent.clone());
+            }
+        }
+        assert_eq!(contents.len(), names.len());
+        let name_bytes = contents.iter().map(|inode| inode.name.len()).sum::<usize>();
+        let expected = names
+            .iter()
+            .map(|path| path.file_name().unwrap().len())
+            .sum::<usize>();
+        assert_eq!(name_bytes, expected);
+        let archives = contents
+            .iter()
+            .filter_map(|inode| inode.krate_path.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(archives.len(), files.len());
+        assert!(archives
+            .iter()
+            .all(|archive| Arc::ptr_eq(archive, archives[0])));
+    }
+
+    #[test]
+    fn refreshing_drops_archives_that_are_gone() {
+        let cache = cache();
+        let other = Fixture::new("two-0.2.0")
+            .file("Cargo.toml", b"[package]\n")
+            .write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let one = fs.find("one-0.1.0").unwrap();
+        let two = fs.find("two-0.2.0/Cargo.toml").unwrap();
+        std::fs::remove_file(other).unwrap();
+        Fixture::new("three-0.3.0")
+            .file("Cargo.toml", b"[package]\n")
+            .write_to(cache.path());
+        // As if listed from the root again.
+        fs.refresh();
+        assert_eq!(fs.names(fuser::FUSE_ROOT_ID), ["one-0.1.0", "three-0.3.0"]);
+        assert_eq!(fs.find("one-0.1.0"), Some(one));
+        assert_eq!(fs.find("two-0.2.0"), None);
+        assert!(!fs.inodes.contains_key(&two));
+        assert!(fs.find("three-0.3.0/Cargo.toml").is_some());
+        assert!(fs.check_consistency().is_empty());
+        let report = String::from_utf8(fs.control_file("last-rescan.json").unwrap()).unwrap();
+        let archive = |stem| cache.path().join(format!("{stem}.crate"));
+        assert!(report.contains(&format!(
+            r#""added":["{}"],"removed":["{}"],"refreshed":[],"invalidations":0}}]"#,
+            archive("three-0.3.0").display(),
+            archive("two-0.2.0").display()
+        )));
+    }
+
+    #[test]
+    fn refreshing_gives_replaced_archives_new_inodes() {
+        let cache = cache();
+        let staging = TempDir::new("staging");
+        let mut fs = populated(cache.path(), &testing::options());
+        let dir = fs.find("one-0.1.0").unwrap();
+        let toml = fs.find("one-0.1.0/Cargo.toml").unwrap();
+        let replacement = Fixture::new("one-0.1.0")
+            .file("Cargo.toml", b"[package]\nname = \"one\"\n")
+            .write_to(staging.path());
+        std::fs::rename(replacement, cache.path().join("one-0.1.0.crate")).unwrap();
+        fs.refresh();
+        assert_eq!(fs.names(fuser::FUSE_ROOT_ID), ["one-0.1.0"]);
+        assert_ne!(fs.find("one-0.1.0"), Some(dir));
+        let replaced = fs.find("one-0.1.0/Cargo.toml").unwrap();
+        assert!(!fs.inodes.contains_key(&toml));
+        assert_eq!(fs.inodes[&replaced].attrs.size, 23);
+        assert!(fs.check_consistency().is_empty());
+        // Nothing changed since.
+        fs.refresh();
+        assert_eq!(fs.find("one-0.1.0/Cargo.toml"), Some(replaced));
+    }
+
+    #[test]
+    fn handles_of_replaced_archives_keep_the_attributes_they_were_opened_with() {
+        let cache = cache();
+        let staging = TempDir::new("staging");
+        let mut fs = populated(cache.path(), &testing::options());
+        let toml = fs.find("one-0.1.0/Cargo.toml").unwrap();
+        let fh = fs.open_handle(toml, None);
+        fs.pin(fh);
+        let size = fs.attrs_for(toml, Some(fh)).unwrap().size;
+        let replacement = Fixture::new("one-0.1.0")
+            .file("Cargo.toml", b"[package]\nname = \"one\"\n")
+            .write_to(staging.path());
+        std::fs::rename(replacement, cache.path().join("one-0.1.0.crate")).unwrap();
+        fs.refresh();
+        assert_eq!(fs.attrs_for(toml, None), None);
+        let opened = fs.attrs_for(toml, Some(fh)).unwrap();
+        assert_eq!((opened.ino, opened.size), (toml, size));
+        // Through another inode, the handle is left out.
+        let replaced = fs.find("one-0.1.0/Cargo.toml").unwrap();
+        assert_eq!(fs.attrs_for(replaced, Some(fh)).unwrap().size, 23);
+    }
+
+    #[test]
+    fn the_crates_file_is_generated_again_once_crates_come_and_go() {
+        let cache = cache();
+        let mut fs = populated(cache.path(), &testing::options());
+        let (&crates, _) = fs
+            .virtual_files
+            .iter()
+            .find(|(_, (source, _))| matches!(source, VirtualSource::Control(ControlFile::Crates)))
+            .unwrap();
+        let opened = fs.virtual_snapshot(crates);
+        assert!(Arc::ptr_eq(&opened, &fs.virtual_snapshot(crates)));
+        assert_eq!(fs.attrs(crates).unwrap().size, opened.len() as u64);
+        Fixture::new("two-0.2.0")
+            .file("Cargo.toml", b"[package]\n")
+            .write_to(cache.path());
+        fs.refresh();
+        let size = fs.attrs(crates).unwrap().size;
+        let listed = fs.virtual_snapshot(crates);
+        assert_eq!(size, listed.len() as u64);
+        assert_eq!(listed.split(|&b| b == b'\n').count(), 3);
+        assert_eq!(opened.split(|&b| b == b'\n').count(), 2);
+    }
+
+    #[test]
+    fn entries_of_crates_hide_generated_ones_with_their_name() {
+        let cache = TempDir::new("cache");
+        Fixture::new("one-0.1.0")
+            .file(".cargo-checksum.json", b"{\"files\":{}}\n")
+            .file("Cargo.toml", b"[package]\n")
+            .write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let version = || VirtualNode::File(VirtualSource::Control(ControlFile::Version));
+        fs.add_virtual(Path::new("one-0.1.0/.cargo-checksum.json"), version())
+            .unwrap();
+        let extra = fs
+            .add_virtual(Path::new("one-0.1.0/meta/extra"), version())
+            .unwrap();
+        let krate = fs.find("one-0.1.0").unwrap();
+        let real = fs.find("one-0.1.0/.cargo-checksum.json").unwrap();
+        let found = |fs: &CargoCacheFs, dir: u64| {
+            fs.listing(dir, 2)
+                .map(|(_, child, _, name)| (name.to_str().unwrap().to_owned(), child))
+                .collect::<Vec<_>>()
+        };
+        let meta = fs.inodes[&extra].parent;
+        assert_eq!(
+            found(&fs, krate),
+            [
+                (".cargo-checksum.json".to_owned(), real),
+                (
+                    "Cargo.toml".to_owned(),
+                    fs.find("one-0.1.0/Cargo.toml").unwrap()
+                ),
+                ("meta".to_owned(), meta),
+            ]
+        );
+        assert_eq!(found(&fs, meta), [("extra".to_owned(), extra)]);
+        // What the archive holds, like any file of it.
+        assert_eq!(fs.attrs(real).unwrap().size, 13);
+        assert!(fs.inodes[&real].krate_path.is_some());
+        assert_eq!(fs.attrs(krate).unwrap().nlink, 3);
+        fs.note_shadowed("lookup", krate);
+        fs.note_shadowed("lookup", krate);
+        assert!(
+            stats(&fs).contains("virtual_entries_shadowed 1\n"),
+            "{}",
+            stats(&fs)
+        );
+        assert!(fs.check_consistency().is_empty());
+    }
+
+    #[test]
+    fn added_entries_nest_under_generated_directories() {
+        let cache = cache();
+        let mut fs = CargoCacheFs::builder(cache.path())
+            .add_virtual_file("VERSION", "1.4.2\n")
+            .add_virtual_file("org/team/manifest.json", || b"{}\n".to_vec())
+            .add_virtual_symlink("org/cache", "/var/cache/crates")
+            .add_virtual_file("one-0.1.0/Cargo.toml", "hidden by the crate's")
+            .add_virtual_file("one-0.1.0/ORIGIN", "added to the crate's\n")
+            .build()
+            .unwrap();
+        fs.populate().unwrap();
+        let listed = |fs: &CargoCacheFs, dir: u64| {
+            fs.listing(dir, 2)
+                .map(|(_, _, _, name)| name.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let find = |fs: &CargoCacheFs, dir: u64, name: &str| {
+            fs.child_named(dir, OsStr::new(name)).or_else(|| {
+                fs.virtual_children(dir)
+                    .find(|child| fs.inodes[child].name == name)
+            })
+        };
+        let root = fuser::FUSE_ROOT_ID;
+        assert_eq!(listed(&fs, root), ["one-0.1.0", "VERSION", "org"]);
+        let version = find(&fs, root, "VERSION").unwrap();
+        assert_eq!(&*fs.virtual_snapshot(version), b"1.4.2\n");
+        assert_eq!(fs.attrs(version).unwrap().size, 6);
+        let org = find(&fs, root, "org").unwrap();
+        assert_eq!(listed(&fs, org), ["team", "cache"]);
+        assert_eq!(fs.attrs(org).unwrap().nlink, 3);
+        let cache_link = find(&fs, org, "cache").unwrap();
+        assert_eq!(fs.attrs(cache_link).unwrap().kind, FileType::Symlink);
+        assert_eq!(
+            fs.inodes[&cache_link].link_target.as_deref(),
+            Some(Path::new("/var/cache/crates"))
+        );
+        let team = find(&fs, org, "team").unwrap();
+        let manifest = find(&fs, team, "manifest.json").unwrap();
+        assert_eq!(&*fs.virtual_snapshot(manifest), b"{}\n");
+        assert_eq!(fs.mount_path(manifest), Path::new("org/team/manifest.json"));
+        let krate = fs.find("one-0.1.0").unwrap();
+        assert_eq!(listed(&fs, krate), ["Cargo.toml", "src", "ORIGIN"]);
+        let toml = find(&fs, krate, "Cargo.toml").unwrap();
+        assert!(fs.inodes[&toml].krate_path.is_some());
+        assert!(!fs.writable(manifest));
+        assert!(fs.check_consistency().is_empty());
+    }
+
+    #[test]
+    fn without_a_control_dir_nothing_is_generated_in_the_root() {
+        let cache = cache();
+        let mut fs = CargoCacheFs::builder(cache.path())
+            .control_dir(None)
+            .add_virtual_file("VERSION", "1.4.2\n")
+            .add_virtual_file("one-0.1.0/ORIGIN", "added to the crate's\n")
+            .build()
+            .unwrap();
+        fs.populate().unwrap();
+        fs.show_control_dir = true;
+        let root = fuser::FUSE_ROOT_ID;
+        assert_eq!(fs.virtual_children(root).count(), 0);
+        assert_eq!(fs.attrs(root).unwrap().size, 1);
+        let krate = fs.find("one-0.1.0").unwrap();
+        assert_eq!(fs.virtual_children(krate).count(), 1);
+    }
+
+    #[test]
+    fn added_entries_need_a_name_of_their_own() {
+        let cache = cache();
+        let build = |paths: &[&str]| {
+            paths
+                .iter()
+                .fold(CargoCacheFs::builder(cache.path()), |builder, path| {
+                    builder.add_virtual_file(path, "")
+                })
+                .build()
+                .map(|_| ())
+        };
+        assert!(build(&["a/b", "a/c", "d"]).is_ok());
+        assert!(build(&["a", "a"]).is_err());
+        assert!(build(&["a", "a/b"]).is_err());
+        assert!(build(&[""]).is_err());
+        assert!(build(&["../a"]).is_err());
+        assert!(build(&["/a"]).is_err());
+        assert!(build(&[".fuse-crates/stats"]).is_err());
+    }
+
+    #[test]
+    fn parallel_population_builds_the_sequential_tree() {
+        let cache = TempDir::new("cache");
+        for i in 0..12 {
+            let mut krate = Fixture::new(&format!("crate{i}-1.0.0"))
+                .file("Cargo.toml", &testing::noise(i * 300, i as u64));
+            for j in 0..i {
+                krate = krate.file(
+                    &format!("src/m{j}/mod.rs"),
+                    &testing::noise(j * 50, j as u64),
+                );
+            }
+            krate.write_to(cache.path());
+        }
+        // Fails in its worker, and takes nothing else down with it.
+        std::fs::write(cache.path().join("broken-1.0.0.crate"), [0x1f, 0x8b, 0]).unwrap();
+        let tree = |jobs| {
+            let options = cli::Options {
+                jobs,
+                populate_budget: None,
+                ..testing::options()
+            };
+            let fs = populated(cache.path(), &options);
+            assert!(fs.deferred.is_empty());
+            assert!(matches!(
+                &fs.violations[..],
+                [Violation::CorruptCrate { krate, .. }] if krate == "broken-1.0.0"
+            ));
+            assert_eq!(fs.names(fuser::FUSE_ROOT_ID).len(), 12);
+            fs.inodes
+                .iter()
+                .map(|(&ino, inode)| {
+                    let attrs = inode.attrs;
+                    (
+                        ino,
+                        inode.parent,
+                        inode.name.clone(),
+                        attrs.kind,
+                        attrs.size,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let sequential = tree(1);
+        assert!(sequential.len() > 12 * 3, "{sequential:?}");
+        for jobs in [2, 4, 16] {
+            assert_eq!(tree(jobs), sequential, "{jobs} jobs");
+        }
+    }
+
+    #[test]
+    fn listings_resume_after_the_last_entry_returned() {
+        let cache = TempDir::new("cache");
+        let mut wide = Fixture::new("wide-0.1.0");
+        for i in 0..1000 {
+            wide = wide.file(&format!("src/file-{i:04}.rs"), b"");
+        }
+        wide.write_to(cache.path());
+        let mut fs = populated(cache.path(), &testing::options());
+        let src = fs.find("wide-0.1.0/src").unwrap();
+        // Replies as `ReplyDirectory` fills them: a 4 KiB buffer of
+        // dirents, each 24 bytes and the name padded to 8.
+        let mut names = vec![];
+        let mut offset = 0;
+        let mut replies = 0;
+        loop {
+            let mut used = 0;
+            let mut added = 0;
+            for (next, _, _, name) in fs.listing(src, offset) {
+                used += (24 + name.len() + 7) & !7;
+                if used > 4096 {
+                    break;
+                }
+                names.push(name.to_owned());
+                offset = next;
+                added += 1;
+            }
+            if added == 0 {
+                break;
+            }
+            replies += 1;
+        }
+        assert!(replies > 5, "{replies} replies");
+        let expected = [".".to_owned(), "..".to_owned()]
+            .into_iter()
+            .chain((0..1000).map(|i| format!("file-{i:04}.rs")))
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "already mapped to a different path")]
+    fn reusing_an_inode_for_another_path_is_caught() {
+        let cache = cache();
+        let mut fs = populated(cache.path(), &testing::options());
+        let file = fs.find("one-0.1.0/Cargo.toml").unwrap();
+        let inode = Inode {
+            attrs: fs.file_attr(file, 0, UNIX_EPOCH),
+            children: vec![],
+            name: "other.toml".into(),
+            parent: fs.inodes[&file].parent,
+            krate_path: None,
+            entry_index: 0,
+            link_target: None,
+            disk_path: None,
+            overlay_path: None,
+        };
+        fs.insert_inode(file, inode);
+    }
+}
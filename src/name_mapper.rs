@@ -0,0 +1,267 @@
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+};
+
+/// Where a name is presented.
+#[derive(Clone, Copy, Debug)]
+pub enum NameContext {
+    /// A crate or shard directory at the top of the mount.
+    Root,
+    /// A file or directory inside a crate.
+    Entry,
+}
+
+impl NameContext {
+    /// The context of names listed in directory `dir`.
+    pub fn of(dir: u64) -> Self {
+        match dir {
+            fuser::FUSE_ROOT_ID => Self::Root,
+            _ => Self::Entry,
+        }
+    }
+}
+
+/// Transforms names between the archive and the mount. Only names crossing
+/// the FUSE boundary go through it; the inode table keeps the real names.
+///
+/// `resolve` must undo `present`, and return `None` for names `present`
+/// never produces.
+pub trait NameMapper: Send {
+    fn present<'a>(&self, real: &'a OsStr, ctx: NameContext) -> Cow<'a, OsStr>;
+    fn resolve<'a>(&self, presented: &'a OsStr, ctx: NameContext) -> Option<Cow<'a, OsStr>>;
+
+    /// The target of a symlink inside a crate, with each name presented so
+    /// that it still leads to the entry within the mount. Absolute targets
+    /// lead out of the mount and are left alone.
+    fn present_target<'a>(&self, target: &'a OsStr) -> Cow<'a, OsStr> {
+        let bytes = target.as_bytes();
+        if bytes.starts_with(b"/") {
+            return Cow::Borrowed(target);
+        }
+        let mut presented = Vec::with_capacity(bytes.len());
+        for (i, name) in bytes.split(|&byte| byte == b'/').enumerate() {
+            if i > 0 {
+                presented.push(b'/');
+            }
+            match name {
+                b"" | b"." | b".." => presented.extend_from_slice(name),
+                name => presented.extend_from_slice(
+                    self.present(OsStr::from_bytes(name), NameContext::Entry)
+                        .as_bytes(),
+                ),
+            }
+        }
+        match presented == bytes {
+            true => Cow::Borrowed(target),
+            false => Cow::Owned(OsString::from_vec(presented)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum NameMapping {
+    #[default]
+    Identity,
+    Sanitize,
+}
+
+impl std::str::FromStr for NameMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "identity" => Ok(Self::Identity),
+            "sanitize" => Ok(Self::Sanitize),
+            _ => anyhow::bail!("Unknown name mapping {s}"),
+        }
+    }
+}
+
+impl NameMapping {
+    pub fn mapper(self) -> Box<dyn NameMapper> {
+        match self {
+            Self::Identity => Box::new(Identity),
+            Self::Sanitize => Box::new(Sanitize),
+        }
+    }
+}
+
+pub struct Identity;
+
+impl NameMapper for Identity {
+    fn present<'a>(&self, real: &'a OsStr, _ctx: NameContext) -> Cow<'a, OsStr> {
+        Cow::Borrowed(real)
+    }
+
+    fn resolve<'a>(&self, presented: &'a OsStr, _ctx: NameContext) -> Option<Cow<'a, OsStr>> {
+        Some(Cow::Borrowed(presented))
+    }
+}
+
+/// Percent-escapes the bytes Windows and FAT refuse in names, and `%`
+/// itself so that the escaping can be undone. Other bytes, including those
+/// of names that are not UTF-8, are kept as they are.
+pub struct Sanitize;
+
+impl Sanitize {
+    fn escaped(byte: u8) -> bool {
+        byte < 0x20 || b"<>:\"/\\|?*%".contains(&byte)
+    }
+}
+
+impl NameMapper for Sanitize {
+    fn present<'a>(&self, real: &'a OsStr, _ctx: NameContext) -> Cow<'a, OsStr> {
+        if !real.as_bytes().iter().copied().any(Self::escaped) {
+            return Cow::Borrowed(real);
+        }
+        let mut presented = Vec::with_capacity(real.len() + 8);
+        for &byte in real.as_bytes() {
+            if Self::escaped(byte) {
+                presented.extend_from_slice(format!("%{byte:02X}").as_bytes());
+            } else {
+                presented.push(byte);
+            }
+        }
+        Cow::Owned(OsString::from_vec(presented))
+    }
+
+    fn resolve<'a>(&self, presented: &'a OsStr, _ctx: NameContext) -> Option<Cow<'a, OsStr>> {
+        let bytes = presented.as_bytes();
+        if !bytes.iter().copied().any(Self::escaped) {
+            return Some(Cow::Borrowed(presented));
+        }
+        let mut real = Vec::with_capacity(bytes.len());
+        let mut rest = bytes;
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte != b'%' {
+                if Self::escaped(byte) {
+                    return None;
+                }
+                real.push(byte);
+                rest = tail;
+                continue;
+            }
+            let hex = tail.get(..2)?;
+            // Only the canonical spelling, so that every real name has one
+            // presented name.
+            if !hex
+                .iter()
+                .all(|digit| matches!(digit, b'0'..=b'9' | b'A'..=b'F'))
+            {
+                return None;
+            }
+            let escaped = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+            if !Self::escaped(escaped) {
+                return None;
+            }
+            real.push(escaped);
+            rest = &tail[2..];
+        }
+        Some(Cow::Owned(OsString::from_vec(real)))
+    }
+}
+
+/// `--icase` comparison of real names: ASCII letters match in either case,
+/// and with the `unicode-icase` feature so do the other letters of UTF-8
+/// names.
+pub fn eq_ignore_case(a: &OsStr, b: &OsStr) -> bool {
+    #[cfg(feature = "unicode-icase")]
+    if let (Some(a), Some(b)) = (a.to_str(), b.to_str()) {
+        return a
+            .chars()
+            .flat_map(char::to_lowercase)
+            .eq(b.chars().flat_map(char::to_lowercase));
+    }
+    a.as_bytes().eq_ignore_ascii_case(b.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn present(real: &[u8]) -> Vec<u8> {
+        Sanitize
+            .present(OsStr::from_bytes(real), NameContext::Entry)
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn resolve(presented: &[u8]) -> Option<Vec<u8>> {
+        Sanitize
+            .resolve(OsStr::from_bytes(presented), NameContext::Entry)
+            .map(|real| real.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn sanitize_round_trips() {
+        let cases: [(&[u8], &[u8]); 7] = [
+            (b"lib.rs", b"lib.rs"),
+            (b"a/b", b"a%2Fb"),
+            (b"100%", b"100%25"),
+            (b"nul\0byte", b"nul%00byte"),
+            (b"con:aux?*", b"con%3Aaux%3F%2A"),
+            (b"not \xff utf-8", b"not \xff utf-8"),
+            (b"%2F\x1f", b"%252F%1F"),
+        ];
+        for (real, presented) in cases {
+            assert_eq!(present(real), presented, "{real:?}");
+            assert_eq!(resolve(presented).as_deref(), Some(real), "{presented:?}");
+        }
+    }
+
+    #[test]
+    fn sanitize_resolves_only_names_it_presents() {
+        // Bytes that would have been escaped, lowercase, non-canonical and
+        // truncated escapes.
+        for presented in [&b"a:b"[..], b"a%3ab", b"%41", b"a%2", b"a%"] {
+            assert_eq!(resolve(presented), None, "{presented:?}");
+        }
+    }
+
+    #[test]
+    fn symlink_targets_are_presented_name_by_name() {
+        let target = |target: &str| {
+            Sanitize
+                .present_target(OsStr::new(target))
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(target("../src/a:b.rs"), "../src/a%3Ab.rs");
+        assert_eq!(target("./dir?/"), "./dir%3F/");
+        assert_eq!(target("plain/path"), "plain/path");
+        assert_eq!(target("/abs/a:b"), "/abs/a:b");
+        assert!(matches!(
+            Identity.present_target(OsStr::new("a:b")),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn ascii_case_is_ignored() {
+        assert!(eq_ignore_case(
+            OsStr::new("Cargo.toml"),
+            OsStr::new("cargo.TOML")
+        ));
+        assert!(!eq_ignore_case(
+            OsStr::new("Cargo.toml"),
+            OsStr::new("Cargo.tom")
+        ));
+        assert!(eq_ignore_case(
+            OsStr::from_bytes(b"A\xff"),
+            OsStr::from_bytes(b"a\xff")
+        ));
+    }
+
+    #[test]
+    fn unicode_case_is_ignored_with_the_feature() {
+        let (lower, upper) = (OsStr::new("ünïcödé.rs"), OsStr::new("ÜNÏCÖDÉ.RS"));
+        assert_eq!(
+            eq_ignore_case(lower, upper),
+            cfg!(feature = "unicode-icase")
+        );
+        assert!(!eq_ignore_case(lower, OsStr::new("unicode.rs")));
+    }
+}
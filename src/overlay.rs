@@ -0,0 +1,122 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{File, Metadata},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::archive;
+
+/// Marks a name deleted through the mount, as `.wh.<name>` next to where
+/// its copy would be.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// `--overlay`: the files written to or created through the mount. They
+/// are laid out as `<crate file stem>/<path in the crate directory>`, so
+/// that they are found again whatever the mount layout.
+pub struct Overlay {
+    dir: PathBuf,
+}
+
+/// What the overlay holds for a crate, parents before children, as paths
+/// relative to the crate directory. Files also give where their copy is.
+pub enum Change {
+    Dir(PathBuf, Metadata),
+    File(PathBuf, PathBuf, Metadata),
+    Whiteout(PathBuf),
+}
+
+impl Overlay {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Where `path` of the crate `stem` is kept.
+    pub fn path(&self, stem: &OsStr, path: &Path) -> PathBuf {
+        self.dir.join(stem).join(path)
+    }
+
+    fn whiteout_path(&self, stem: &OsStr, path: &Path) -> PathBuf {
+        let mut name = OsString::from(WHITEOUT_PREFIX);
+        name.push(path.file_name().unwrap_or_default());
+        self.path(stem, path).with_file_name(name)
+    }
+
+    pub fn whiteout(&self, stem: &OsStr, path: &Path) -> io::Result<()> {
+        let whiteout = self.whiteout_path(stem, path);
+        std::fs::create_dir_all(whiteout.parent().unwrap())?;
+        File::create(whiteout).map(drop)
+    }
+
+    /// Brings a deleted name back, for creating something there again.
+    pub fn clear_whiteout(&self, stem: &OsStr, path: &Path) -> io::Result<()> {
+        match std::fs::remove_file(self.whiteout_path(stem, path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Copies entry `entry_index` of `archive`, or its extracted copy at
+    /// `disk_path`, to where `path` of the crate `stem` is kept, or just
+    /// creates an empty file there with `truncate`.
+    pub fn copy_up(
+        &self,
+        stem: &OsStr,
+        path: &Path,
+        (archive, entry_index, disk_path): (&Path, usize, Option<&Path>),
+        truncate: bool,
+    ) -> io::Result<PathBuf> {
+        let copy = self.path(stem, path);
+        std::fs::create_dir_all(copy.parent().unwrap())?;
+        let mut file = File::create(&copy)?;
+        if truncate {
+            return Ok(copy);
+        }
+        let copied = match disk_path {
+            Some(disk_path) => io::copy(&mut File::open(disk_path)?, &mut file),
+            None => archive::open(archive)
+                .map_err(io::Error::other)?
+                .entries()?
+                .nth(entry_index)
+                .ok_or(io::ErrorKind::UnexpectedEof)?
+                .and_then(|mut entry| io::copy(&mut entry, &mut file)),
+        };
+        if let Err(e) = copied {
+            drop(file);
+            let _ = std::fs::remove_file(&copy);
+            return Err(e);
+        }
+        Ok(copy)
+    }
+
+    pub fn changes(&self, stem: &OsStr) -> io::Result<Vec<Change>> {
+        let mut changes = vec![];
+        let mut pending = vec![PathBuf::new()];
+        while let Some(dir) = pending.pop() {
+            let entries = match std::fs::read_dir(self.path(stem, &dir)) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name();
+                if let Some(deleted) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+                    changes.push(Change::Whiteout(dir.join(OsStr::from_bytes(deleted))));
+                    continue;
+                }
+                let metadata = entry.metadata()?;
+                let path = dir.join(&name);
+                if metadata.is_dir() {
+                    pending.push(path.clone());
+                    changes.push(Change::Dir(path, metadata));
+                } else if metadata.is_file() {
+                    changes.push(Change::File(path, entry.path(), metadata));
+                }
+            }
+        }
+        Ok(changes)
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+pub struct Checks {
+    pub mkdir: bool,
+    pub nonempty: bool,
+    pub force_remount: bool,
+}
+
+#[derive(Debug)]
+pub enum PreflightError {
+    Missing(PathBuf),
+    NotADirectory(PathBuf),
+    NotEmpty(PathBuf),
+    AlreadyMounted(PathBuf),
+    InsideSource {
+        mountpoint: PathBuf,
+        source: PathBuf,
+    },
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+}
+
+impl PreflightError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io { .. } => 1,
+            Self::Missing(_) => 2,
+            Self::NotADirectory(_) => 3,
+            Self::NotEmpty(_) => 4,
+            Self::AlreadyMounted(_) => 5,
+            Self::InsideSource { .. } => 6,
+        }
+    }
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(path) => write!(
+                f,
+                "mountpoint {} does not exist (pass --mkdir to create it)",
+                path.display()
+            ),
+            Self::NotADirectory(path) => {
+                write!(f, "mountpoint {} is not a directory", path.display())
+            }
+            Self::NotEmpty(path) => write!(
+                f,
+                "mountpoint {} is not empty (pass --nonempty to mount over it)",
+                path.display()
+            ),
+            Self::AlreadyMounted(path) => write!(
+                f,
+                "{} is already a FUSE mount (pass --force-remount to unmount it first)",
+                path.display()
+            ),
+            Self::InsideSource { mountpoint, source } => write!(
+                f,
+                "mountpoint {} is inside the source directory {}",
+                mountpoint.display(),
+                source.display()
+            ),
+            Self::Io { path, error } => write!(f, "checking {}: {error}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Validates `mountpoint` before handing it to fuser, so that the common
+/// mistakes get a specific message instead of an EPERM/EBUSY from mount.
+pub fn run<'a>(
+    mountpoint: &Path,
+    sources: impl IntoIterator<Item = &'a Path>,
+    checks: &Checks,
+) -> Result<(), PreflightError> {
+    run_with(mountpoint, sources, checks, Path::new(MOUNTINFO))
+}
+
+/// [`run`], looking the mounts up in `mountinfo`.
+fn run_with<'a>(
+    mountpoint: &Path,
+    sources: impl IntoIterator<Item = &'a Path>,
+    checks: &Checks,
+    mountinfo: &Path,
+) -> Result<(), PreflightError> {
+    let io = |error| PreflightError::Io {
+        path: mountpoint.to_path_buf(),
+        error,
+    };
+
+    if is_fuse_mount_in(mountpoint, mountinfo).map_err(io)? {
+        if !checks.force_remount {
+            return Err(PreflightError::AlreadyMounted(mountpoint.to_path_buf()));
+        }
+        let status = std::process::Command::new("fusermount3")
+            .arg("-u")
+            .arg(mountpoint)
+            .status()
+            .map_err(io)?;
+        if !status.success() {
+            return Err(PreflightError::AlreadyMounted(mountpoint.to_path_buf()));
+        }
+        info!("Unmounted stale mount at {}", mountpoint.display());
+    }
+
+    match std::fs::metadata(mountpoint) {
+        Ok(metadata) if !metadata.is_dir() => {
+            return Err(PreflightError::NotADirectory(mountpoint.to_path_buf()))
+        }
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound && checks.mkdir => {
+            std::fs::create_dir_all(mountpoint).map_err(io)?
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(PreflightError::Missing(mountpoint.to_path_buf()))
+        }
+        Err(e) => return Err(io(e)),
+    }
+
+    if std::fs::read_dir(mountpoint).map_err(io)?.next().is_some() {
+        if !checks.nonempty {
+            return Err(PreflightError::NotEmpty(mountpoint.to_path_buf()));
+        }
+        warn!(
+            "Mounting over the non-empty directory {}",
+            mountpoint.display()
+        );
+    }
+
+    let canonical_mountpoint = mountpoint.canonicalize().map_err(io)?;
+    for source in sources {
+        let source_io = |error| PreflightError::Io {
+            path: source.to_path_buf(),
+            error,
+        };
+        let canonical_source = source.canonicalize().map_err(source_io)?;
+        // Rather than mounting a directory that lists as empty.
+        if canonical_source.is_dir() {
+            std::fs::read_dir(&canonical_source).map_err(source_io)?;
+        } else {
+            std::fs::File::open(&canonical_source).map_err(source_io)?;
+        }
+        if canonical_mountpoint.starts_with(&canonical_source) {
+            return Err(PreflightError::InsideSource {
+                mountpoint: canonical_mountpoint,
+                source: canonical_source,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Looks `mountpoint` up in `/proc/self/mountinfo`. A stale FUSE mount
+/// cannot be canonicalized, so only its parent is resolved.
+pub fn is_fuse_mount(mountpoint: &Path) -> std::io::Result<bool> {
+    is_fuse_mount_in(mountpoint, Path::new(MOUNTINFO))
+}
+
+fn is_fuse_mount_in(mountpoint: &Path, mountinfo: &Path) -> std::io::Result<bool> {
+    let absolute = std::path::absolute(mountpoint)?;
+    let resolved = match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => match parent.canonicalize() {
+            Ok(parent) => parent.join(name),
+            Err(_) => return Ok(false),
+        },
+        _ => absolute,
+    };
+    let mountinfo = std::fs::read_to_string(mountinfo)?;
+    Ok(mountinfo.lines().any(|line| {
+        let mut fields = line.split(' ');
+        let mount_path = fields.nth(4).map(unescape);
+        let fstype = fields.skip_while(|field| *field != "-").nth(1);
+        mount_path.as_deref() == Some(resolved.as_os_str().to_string_lossy().as_ref())
+            && fstype.is_some_and(|fstype| fstype == "fuse" || fstype.starts_with("fuse."))
+    }))
+}
+
+/// Undoes the octal escaping (`\040` for a space) used in mountinfo.
+fn unescape(field: &str) -> String {
+    let mut out = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = field
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        if let Some(byte) = escaped {
+            out.push(byte);
+            i += 4;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    const CHECKS: Checks = Checks {
+        mkdir: false,
+        nonempty: false,
+        force_remount: false,
+    };
+
+    /// Runs the checks with a mountinfo listing `mounted` as FUSE mounts.
+    fn preflight(
+        mountpoint: &Path,
+        source: &Path,
+        checks: &Checks,
+        mounted: &[&Path],
+    ) -> Result<(), PreflightError> {
+        let proc = TempDir::new("proc");
+        let mountinfo = proc.path().join("mountinfo");
+        let mut lines = String::from("22 1 0:21 / /proc rw,nosuid - proc proc rw\n");
+        for (i, path) in mounted.iter().enumerate() {
+            let path = path.to_str().unwrap().replace(' ', "\\040");
+            lines += &format!(
+                "{} 22 0:{} / {path} ro,nosuid,nodev - fuse.fuse-crates cache ro\n",
+                100 + i,
+                50 + i
+            );
+        }
+        std::fs::write(&mountinfo, lines).unwrap();
+        run_with(mountpoint, [source], checks, &mountinfo)
+    }
+
+    fn source() -> TempDir {
+        let source = TempDir::new("source");
+        std::fs::write(source.path().join("one-0.1.0.crate"), b"").unwrap();
+        source
+    }
+
+    #[test]
+    fn an_empty_directory_passes() {
+        let (source, mnt) = (source(), TempDir::new("mnt"));
+        preflight(mnt.path(), source.path(), &CHECKS, &[]).unwrap();
+    }
+
+    #[test]
+    fn a_missing_mountpoint_is_created_with_mkdir() {
+        let (source, mnt) = (source(), TempDir::new("mnt"));
+        let mountpoint = mnt.path().join("a/b");
+        let error = preflight(&mountpoint, source.path(), &CHECKS, &[]).unwrap_err();
+        assert!(matches!(error, PreflightError::Missing(_)), "{error}");
+        assert_eq!(error.exit_code(), 2);
+        let checks = Checks {
+            mkdir: true,
+            ..CHECKS
+        };
+        preflight(&mountpoint, source.path(), &checks, &[]).unwrap();
+        assert!(mountpoint.is_dir());
+    }
+
+    #[test]
+    fn a_file_is_not_a_mountpoint() {
+        let (source, mnt) = (source(), TempDir::new("mnt"));
+        let file = mnt.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let error = preflight(&file, source.path(), &CHECKS, &[]).unwrap_err();
+        assert!(matches!(error, PreflightError::NotADirectory(_)), "{error}");
+        assert_eq!(error.exit_code(), 3);
+    }
+
+    #[test]
+    fn a_non_empty_mountpoint_needs_nonempty() {
+        let (source, mnt) = (source(), TempDir::new("mnt"));
+        std::fs::write(mnt.path().join("file"), b"").unwrap();
+        let error = preflight(mnt.path(), source.path(), &CHECKS, &[]).unwrap_err();
+        assert!(matches!(error, PreflightError::NotEmpty(_)), "{error}");
+        assert_eq!(error.exit_code(), 4);
+        let checks = Checks {
+            nonempty: true,
+            ..CHECKS
+        };
+        preflight(mnt.path(), source.path(), &checks, &[]).unwrap();
+    }
+
+    #[test]
+    fn an_existing_fuse_mount_is_refused() {
+        let source = source();
+        let mnt = TempDir::new("mnt");
+        let mountpoint = mnt.path().join("with space");
+        std::fs::create_dir(&mountpoint).unwrap();
+        let error = preflight(&mountpoint, source.path(), &CHECKS, &[&mountpoint]).unwrap_err();
+        assert!(
+            matches!(error, PreflightError::AlreadyMounted(_)),
+            "{error}"
+        );
+        assert_eq!(error.exit_code(), 5);
+        // Other FUSE mounts and a mount of the parent do not count.
+        let other = TempDir::new("other");
+        preflight(
+            &mountpoint,
+            source.path(),
+            &CHECKS,
+            &[other.path(), mnt.path()],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn a_mountpoint_inside_the_source_is_refused() {
+        let source = source();
+        let mountpoint = source.path().join("mnt");
+        std::fs::create_dir(&mountpoint).unwrap();
+        let error = preflight(&mountpoint, source.path(), &CHECKS, &[]).unwrap_err();
+        assert!(
+            matches!(error, PreflightError::InsideSource { .. }),
+            "{error}"
+        );
+        assert_eq!(error.exit_code(), 6);
+    }
+
+    #[test]
+    fn a_missing_source_is_an_io_error() {
+        let (source, mnt) = (source(), TempDir::new("mnt"));
+        let missing = source.path().join("missing");
+        let error = preflight(mnt.path(), &missing, &CHECKS, &[]).unwrap_err();
+        assert!(
+            matches!(&error, PreflightError::Io { path, error } if *path == missing && error.kind() == ErrorKind::NotFound),
+            "{error}"
+        );
+        assert_eq!(error.exit_code(), 1);
+    }
+
+    #[test]
+    fn an_unreadable_source_is_an_io_error() {
+        // Permissions do not apply to root.
+        if unsafe { libc::geteuid() } == 0 {
+            eprintln!("Skipping: running as root");
+            return;
+        }
+        let (source, mnt) = (source(), TempDir::new("mnt"));
+        for path in [source.path().join("one-0.1.0.crate"), source.path().into()] {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000)).unwrap();
+            let error = preflight(mnt.path(), &path, &CHECKS, &[]).unwrap_err();
+            assert!(
+                matches!(&error, PreflightError::Io { error, .. } if error.kind() == ErrorKind::PermissionDenied),
+                "{error}"
+            );
+            assert_eq!(error.exit_code(), 1);
+        }
+        std::fs::set_permissions(source.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn mountinfo_paths_are_unescaped() {
+        assert_eq!(
+            unescape(r"/mnt/with\040space\011tab"),
+            "/mnt/with space\ttab"
+        );
+        assert_eq!(unescape(r"/mnt/back\\slash\0"), r"/mnt/back\\slash\0");
+    }
+}
@@ -0,0 +1,237 @@
+//! What rescanning the sources changed, kept for `last-rescan.json`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::access_log::json_escape;
+
+/// How many reports `last-rescan.json` keeps, the latest first.
+pub const KEPT: usize = 8;
+
+/// The inode number, size and mtime of an archive file.
+pub type ArchiveStamp = (u64, u64, Option<SystemTime>);
+
+pub fn stamp(metadata: &std::fs::Metadata) -> ArchiveStamp {
+    (metadata.ino(), metadata.len(), metadata.modified().ok())
+}
+
+/// Why a crate was read again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// Another file took the archive's name.
+    Replaced,
+    /// Rewritten in place to another size.
+    Resized,
+    /// Rewritten in place, or touched, to the same size.
+    Modified,
+    /// Written to the `refresh` control file.
+    Asked,
+}
+
+impl Reason {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Replaced => "replaced",
+            Self::Resized => "resized",
+            Self::Modified => "modified",
+            Self::Asked => "asked",
+        }
+    }
+}
+
+/// The archives a rescan added, removed and read again, each sorted.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Delta {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub refreshed: Vec<(PathBuf, Reason)>,
+}
+
+impl Delta {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.refreshed.is_empty()
+    }
+}
+
+/// What changed from the archives listed `before` to those listed `after`.
+pub fn delta(
+    before: &HashMap<PathBuf, ArchiveStamp>,
+    after: &HashMap<PathBuf, ArchiveStamp>,
+) -> Delta {
+    let mut delta = Delta::default();
+    for (path, stamp) in after {
+        let Some(listed) = before.get(path) else {
+            delta.added.push(path.clone());
+            continue;
+        };
+        let reason = match (listed, stamp) {
+            ((ino, _, _), (now, _, _)) if ino != now => Reason::Replaced,
+            ((_, len, _), (_, now, _)) if len != now => Reason::Resized,
+            ((_, _, mtime), (_, _, now)) if mtime != now => Reason::Modified,
+            _ => continue,
+        };
+        delta.refreshed.push((path.clone(), reason));
+    }
+    delta.removed = before
+        .keys()
+        .filter(|path| !after.contains_key(*path))
+        .cloned()
+        .collect();
+    delta.added.sort();
+    delta.removed.sort();
+    delta.refreshed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    delta
+}
+
+/// A rescan, when it started and how long it took.
+pub struct Report {
+    pub started: SystemTime,
+    pub took: Duration,
+    pub delta: Delta,
+    /// Entries and inodes the kernel was told to forget on the way.
+    pub invalidations: u64,
+}
+
+/// The last [`KEPT`] reports.
+#[derive(Default)]
+pub struct History {
+    reports: VecDeque<Report>,
+    /// Reports ever added, which changes with the contents.
+    added: u64,
+}
+
+impl History {
+    pub fn push(&mut self, report: Report) {
+        if self.reports.len() == KEPT {
+            self.reports.pop_back();
+        }
+        self.reports.push_front(report);
+        self.added += 1;
+    }
+
+    pub fn version(&self) -> u64 {
+        self.added
+    }
+
+    /// A JSON array with an object per report, the latest first.
+    pub fn json(&self) -> Vec<u8> {
+        let paths = |paths: &mut dyn Iterator<Item = &PathBuf>| {
+            let paths = paths
+                .map(|path| format!("\"{}\"", json_escape(&path.to_string_lossy())))
+                .collect::<Vec<_>>();
+            format!("[{}]", paths.join(","))
+        };
+        let mut text = String::from("[");
+        for (n, report) in self.reports.iter().enumerate() {
+            if n > 0 {
+                text.push(',');
+            }
+            let started = report
+                .started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let refreshed = report
+                .delta
+                .refreshed
+                .iter()
+                .map(|(path, reason)| {
+                    format!(
+                        r#"{{"archive":"{}","reason":"{}"}}"#,
+                        json_escape(&path.to_string_lossy()),
+                        reason.name()
+                    )
+                })
+                .collect::<Vec<_>>();
+            write!(
+                text,
+                r#"{{"started":{started:.3},"seconds":{:.6},"added":{},"removed":{},"#,
+                report.took.as_secs_f64(),
+                paths(&mut report.delta.added.iter()),
+                paths(&mut report.delta.removed.iter()),
+            )
+            .unwrap();
+            write!(
+                text,
+                r#""refreshed":[{}],"invalidations":{}}}"#,
+                refreshed.join(","),
+                report.invalidations
+            )
+            .unwrap();
+        }
+        text.push_str("]\n");
+        text.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listed(archives: &[(&str, ArchiveStamp)]) -> HashMap<PathBuf, ArchiveStamp> {
+        archives
+            .iter()
+            .map(|(path, stamp)| (PathBuf::from(path), *stamp))
+            .collect()
+    }
+
+    #[test]
+    fn the_delta_tells_why_each_crate_is_read_again() {
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        let before = listed(&[
+            ("a.crate", (1, 10, at(1))),
+            ("b.crate", (2, 10, at(1))),
+            ("c.crate", (3, 10, at(1))),
+            ("d.crate", (4, 10, at(1))),
+            ("e.crate", (5, 10, at(1))),
+        ]);
+        let after = listed(&[
+            ("a.crate", (1, 10, at(1))),
+            ("b.crate", (9, 10, at(1))),
+            ("c.crate", (3, 11, at(2))),
+            ("d.crate", (4, 10, at(2))),
+            ("f.crate", (6, 10, at(1))),
+        ]);
+        let delta = delta(&before, &after);
+        assert_eq!(delta.added, [PathBuf::from("f.crate")]);
+        assert_eq!(delta.removed, [PathBuf::from("e.crate")]);
+        assert_eq!(
+            delta.refreshed,
+            [
+                (PathBuf::from("b.crate"), Reason::Replaced),
+                (PathBuf::from("c.crate"), Reason::Resized),
+                (PathBuf::from("d.crate"), Reason::Modified),
+            ]
+        );
+        assert!(super::delta(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn only_the_last_reports_are_kept() {
+        let mut history = History::default();
+        for n in 0..KEPT as u64 + 2 {
+            history.push(Report {
+                started: UNIX_EPOCH,
+                took: Duration::ZERO,
+                delta: Delta {
+                    added: vec![PathBuf::from(format!("{n}.crate"))],
+                    ..Delta::default()
+                },
+                invalidations: n,
+            });
+        }
+        assert_eq!(history.reports.len(), KEPT);
+        assert_eq!(history.version(), KEPT as u64 + 2);
+        let json = String::from_utf8(history.json()).unwrap();
+        assert!(json.starts_with(concat!(
+            r#"[{"started":0.000,"seconds":0.000000,"added":["9.crate"],"removed":[],"#,
+            r#""refreshed":[],"invalidations":9},"#
+        )));
+        assert!(json.ends_with("\"invalidations\":2}]\n"));
+    }
+}
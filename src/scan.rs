@@ -0,0 +1,245 @@
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{archive, system_time_from_tar, verify::Verifier, violation::Violation, PrefixPolicy};
+
+/// What scanning an archive depends on besides the archive.
+pub struct Config {
+    pub prefix_policy: PrefixPolicy,
+    /// `--src-dir`, where extracted copies of files are looked for.
+    pub src_dir: Option<PathBuf>,
+    pub verifier: Option<Verifier>,
+}
+
+/// What walking a crate archive found, before anything is put in the inode
+/// table. Producing it is the expensive part of populating a crate and
+/// touches nothing shared, so it can run off the main thread.
+#[derive(Default)]
+pub struct Scan {
+    pub entries: Vec<ScannedEntry>,
+    pub violations: Vec<Violation>,
+    /// `--prefix-policy reject` applied: the crate is left out.
+    pub rejected: bool,
+    /// The archive's SHA-256, when `--verify` computed it.
+    pub digest: Option<String>,
+    /// Why the archive failed `--verify`; it is not scanned.
+    pub verification_failed: Option<String>,
+}
+
+pub struct ScannedEntry {
+    pub entry_index: usize,
+    /// The path stored in the archive, which hard links refer to.
+    pub archive_path: PathBuf,
+    /// The path presented, after `--prefix-policy`.
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub mtime: SystemTime,
+    pub mode: Option<u32>,
+}
+
+pub enum EntryKind {
+    Directory,
+    File {
+        size: u64,
+        /// Set when the data ends before the header size, which means the
+        /// archive itself ended.
+        short: bool,
+        /// The same file in `--src-dir`, if it is there with this size.
+        disk_path: Option<PathBuf>,
+    },
+    Symlink(PathBuf),
+    HardLink(PathBuf),
+}
+
+pub fn scan_crate(archive: &Path, config: &Config) -> Result<Scan> {
+    let mut scan = Scan::default();
+    if let Some(verifier) = &config.verifier {
+        match verifier.verify(archive) {
+            Ok(digest) => scan.digest = digest,
+            Err(problem) => {
+                scan.verification_failed = Some(problem);
+                return Ok(scan);
+            }
+        }
+    }
+    let prefix_policy = config.prefix_policy;
+    let crate_name = archive::stem(archive).unwrap();
+    let krate = crate_name.to_string_lossy();
+    let mut tar = archive::open(archive)?;
+    let mut mismatch_logged = false;
+    let mut mtime_logged = false;
+    for (entry_index, entry) in tar.entries().context("Get entries")?.enumerate() {
+        let mut entry = entry.context("Unwrapping entry")?;
+        let mut entry_path = entry.path().context("Extracting path entry")?.into_owned();
+        if entry_path.components().any(|component| {
+            !matches!(
+                component,
+                std::path::Component::Normal(_) | std::path::Component::CurDir
+            )
+        }) {
+            scan.violations.push(Violation::PathTraversal {
+                krate: krate.clone().into_owned(),
+                path: entry_path.to_string_lossy().into_owned(),
+            });
+            continue;
+        }
+        // Without the `.` components, which would otherwise show up as
+        // directories named `.`; a bare `./` names nothing.
+        entry_path = normalize(&entry_path);
+        if entry_path.as_os_str().is_empty() {
+            continue;
+        }
+        let archive_path = entry_path.clone();
+        let entry_type = entry.header().entry_type();
+        // `entries` applies GNU long names and PAX headers to the entry
+        // they precede, so these only turn up if that fails to happen.
+        if entry_type.is_gnu_longname()
+            || entry_type.is_gnu_longlink()
+            || entry_type.is_pax_local_extensions()
+            || entry_type.is_pax_global_extensions()
+        {
+            continue;
+        }
+        let supported = entry_type.is_file()
+            || entry_type.is_contiguous()
+            || entry_type.is_gnu_sparse()
+            || entry_type.is_dir()
+            || entry_type.is_hard_link()
+            || entry_type.is_symlink();
+        if !supported {
+            let kind = match entry_type {
+                tar::EntryType::Fifo => "FIFO".to_owned(),
+                tar::EntryType::Char => "character device".to_owned(),
+                tar::EntryType::Block => "block device".to_owned(),
+                other => format!("entry of type {:?}", other.as_byte() as char),
+            };
+            scan.violations.push(Violation::UnsupportedEntry {
+                krate: krate.clone().into_owned(),
+                path: entry_path.to_string_lossy().into_owned(),
+                kind,
+            });
+            continue;
+        }
+        if entry_type.is_dir() && entry_path == Path::new(crate_name) {
+            continue;
+        }
+        if let Some(mismatch) = prefix_mismatch(crate_name, &entry_path) {
+            if !mismatch_logged {
+                scan.violations.push(Violation::PrefixMismatch {
+                    krate: krate.clone().into_owned(),
+                    detail: format!("{mismatch}, applying {prefix_policy:?}"),
+                });
+                mismatch_logged = true;
+            }
+            match prefix_policy {
+                PrefixPolicy::TrustArchive => (),
+                PrefixPolicy::TrustFilename => {
+                    let mut components = entry_path.components();
+                    if entry_path.components().count() > 1 {
+                        components.next();
+                    }
+                    entry_path = Path::new(crate_name).join(components.as_path());
+                }
+                PrefixPolicy::Reject => {
+                    scan.rejected = true;
+                    return Ok(scan);
+                }
+            }
+        }
+        let (mtime, clamped) = match entry.header().mtime() {
+            Ok(raw) => system_time_from_tar(raw),
+            Err(_) => (UNIX_EPOCH, true),
+        };
+        let mode = entry.header().mode().ok();
+        let kind = if entry_type.is_dir() {
+            EntryKind::Directory
+        } else if entry_type.is_hard_link() {
+            let target = entry.link_name().context("Link name")?.unwrap_or_default();
+            EntryKind::HardLink(normalize(&target))
+        } else if entry_type.is_symlink() {
+            let target = entry.link_name().context("Link name")?;
+            EntryKind::Symlink(target.map(Cow::into_owned).unwrap_or_default())
+        } else {
+            let header_size = entry.header().size().context("File size")?;
+            // The data has to be decompressed to reach the next header
+            // anyway; counting it catches headers that claim more than the
+            // archive holds.
+            let size = std::io::copy(&mut entry, &mut std::io::sink())
+                .with_context(|| format!("Reading {}", entry_path.to_string_lossy()))?;
+            if size != header_size {
+                scan.violations.push(Violation::SizeMismatch {
+                    krate: krate.clone().into_owned(),
+                    path: entry_path.to_string_lossy().into_owned(),
+                    header_size,
+                    actual: size,
+                });
+            }
+            let disk_path = config
+                .src_dir
+                .as_ref()
+                .map(|dir| dir.join(&entry_path))
+                .filter(|path| {
+                    std::fs::symlink_metadata(path)
+                        .is_ok_and(|metadata| metadata.is_file() && metadata.len() == size)
+                });
+            EntryKind::File {
+                size,
+                short: size != header_size,
+                disk_path,
+            }
+        };
+        if clamped
+            && !mtime_logged
+            && matches!(kind, EntryKind::File { .. } | EntryKind::Symlink(_))
+        {
+            scan.violations.push(Violation::MtimeOutOfRange {
+                krate: krate.clone().into_owned(),
+                path: entry_path.to_string_lossy().into_owned(),
+            });
+            mtime_logged = true;
+        }
+        let short = matches!(kind, EntryKind::File { short: true, .. });
+        scan.entries.push(ScannedEntry {
+            entry_index,
+            archive_path,
+            path: entry_path,
+            kind,
+            mtime,
+            mode,
+        });
+        if short {
+            break;
+        }
+    }
+    Ok(scan)
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, std::path::Component::CurDir))
+        .collect()
+}
+
+fn prefix_mismatch(crate_name: &OsStr, entry_path: &Path) -> Option<String> {
+    let mut components = entry_path.components();
+    let top = components.next()?;
+    if components.next().is_none() {
+        return Some(format!(
+            "entry {} is outside any top-level directory",
+            entry_path.to_string_lossy()
+        ));
+    }
+    if top.as_os_str() != crate_name {
+        return Some(format!(
+            "top-level directory {} does not match the file name",
+            top.as_os_str().to_string_lossy()
+        ));
+    }
+    None
+}
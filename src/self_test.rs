@@ -0,0 +1,139 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    io::Read,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{filter::CrateFilter, source::Source};
+
+const SAMPLE_CRATES: usize = 3;
+const SAMPLE_FILES: usize = 4;
+const CHUNK: u64 = 4096;
+
+#[derive(Default)]
+pub struct Report {
+    pub crates_listed: usize,
+    pub files_checked: usize,
+    pub failures: Vec<String>,
+}
+
+impl Report {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Checks a mounted cache from the outside, through plain `std::fs`, against
+/// the archives in `sources`.
+pub fn run(mountpoint: &Path, sources: &[Source], filter: &CrateFilter) -> Result<Report> {
+    let mut report = Report::default();
+
+    // With --shard-root the root lists shard directories, so crates are
+    // looked up by name rather than matched against the listing.
+    for entry in std::fs::read_dir(mountpoint).context("Listing mount root")? {
+        entry.context("Listing mount root")?;
+    }
+    let mut present = vec![];
+    for source in sources {
+        let dir = match &source.dir_name {
+            Some(name) => mountpoint.join(name),
+            None => mountpoint.to_path_buf(),
+        };
+        for (stem, archive) in crate_stems(source, filter)? {
+            if !dir.join(&stem).is_dir() {
+                report.failures.push(format!(
+                    "{} is in the source but not in the mount",
+                    stem.to_string_lossy()
+                ));
+                continue;
+            }
+            present.push((dir.clone(), archive));
+        }
+    }
+
+    report.crates_listed = present.len();
+    for (dir, krate) in present.into_iter().take(SAMPLE_CRATES) {
+        if let Err(e) = check_crate(&dir, &krate, &mut report) {
+            report
+                .failures
+                .push(format!("{}: {e:#}", krate.to_string_lossy()));
+        }
+    }
+    Ok(report)
+}
+
+/// The archives of `source` that `filter` lets be mounted, by stem.
+fn crate_stems(source: &Source, filter: &CrateFilter) -> Result<BTreeMap<OsString, PathBuf>> {
+    let paths = match source.is_archive {
+        true => vec![source.path.clone()],
+        false => std::fs::read_dir(&source.path)
+            .context("Listing source directory")?
+            .map(|file| Ok(file?.path()))
+            .collect::<std::io::Result<_>>()
+            .context("Listing source directory")?,
+    };
+    Ok(paths
+        .into_iter()
+        .filter_map(|path| {
+            let stem = crate::archive::stem(&path).filter(|stem| filter.admits(stem))?;
+            Some((stem.to_os_string(), path))
+        })
+        .collect())
+}
+
+fn check_crate(mountpoint: &Path, krate: &Path, report: &mut Report) -> Result<()> {
+    let mut archive = crate::archive::open(krate)?;
+    let mut checked = 0;
+    for entry in archive.entries().context("Get entries")? {
+        if checked == SAMPLE_FILES {
+            break;
+        }
+        let mut entry = entry.context("Unwrapping entry")?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let mounted = mountpoint.join(entry.path().context("Extracting path entry")?);
+        let mut expected = vec![];
+        entry
+            .read_to_end(&mut expected)
+            .context("Reading entry from the archive")?;
+        if let Err(e) = check_file(&mounted, &expected) {
+            report
+                .failures
+                .push(format!("{}: {e:#}", mounted.to_string_lossy()));
+        }
+        report.files_checked += 1;
+        checked += 1;
+    }
+    Ok(())
+}
+
+/// Compares the size and the first and last 4 KiB of `mounted` with `expected`.
+pub fn check_file(mounted: &Path, expected: &[u8]) -> Result<()> {
+    let metadata = std::fs::metadata(mounted).context("Stat")?;
+    anyhow::ensure!(metadata.is_file(), "not a regular file");
+    let size = expected.len() as u64;
+    anyhow::ensure!(
+        metadata.len() == size,
+        "size is {} instead of {size}",
+        metadata.len()
+    );
+    let file = File::open(mounted).context("Open")?;
+    let head = CHUNK.min(size);
+    let tail = size.saturating_sub(CHUNK);
+    for (offset, len) in [(0, head), (tail, size - tail)] {
+        let mut buf = vec![0u8; len as usize];
+        file.read_exact_at(&mut buf, offset)
+            .with_context(|| format!("Reading {len} bytes at {offset}"))?;
+        anyhow::ensure!(
+            buf == expected[offset as usize..(offset + len) as usize],
+            "{len} bytes at {offset} differ from the archive"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{archive, control};
+
+/// A path given to mount: a cache directory, whose archives are all
+/// mounted, or a single archive.
+#[derive(Clone)]
+pub struct Source {
+    pub path: PathBuf,
+    pub is_archive: bool,
+    /// With several sources, the top-level directory this one is under:
+    /// the archive's stem or the directory's name, with a numeric suffix if
+    /// an earlier source took it.
+    pub dir_name: Option<OsString>,
+    /// The directory its crates are put in, 0 until populated.
+    pub top: u64,
+    /// When it was last listed, to tell when to list it again.
+    pub listed_mtime: Option<SystemTime>,
+}
+
+impl Source {
+    /// The archives in the source, each with its metadata. Notes the mtime
+    /// they were listed at. An archive that is gone is just not listed.
+    pub fn list(&mut self) -> std::io::Result<Vec<std::io::Result<(PathBuf, std::fs::Metadata)>>> {
+        let metadata = match std::fs::metadata(&self.path) {
+            Err(e) if self.is_archive && e.kind() == ErrorKind::NotFound => {
+                self.listed_mtime = None;
+                return Ok(vec![]);
+            }
+            metadata => metadata?,
+        };
+        self.listed_mtime = Some(metadata.modified()?);
+        if self.is_archive {
+            return Ok(vec![Ok((self.path.clone(), metadata))]);
+        }
+        Ok(std::fs::read_dir(&self.path)?
+            .map(|file| file.and_then(|file| Ok((file.path(), file.metadata()?))))
+            .collect())
+    }
+
+    /// Whether `archive` is this source or one of its archives.
+    pub fn holds(&self, archive: &Path) -> bool {
+        match self.is_archive {
+            true => archive == self.path,
+            false => archive.parent() == Some(&self.path),
+        }
+    }
+}
+
+/// Checks that every path is a directory or an archive, and given once.
+pub fn resolve(paths: &[PathBuf]) -> Result<Vec<Source>> {
+    let mut sources = vec![];
+    let mut canonical: Vec<(PathBuf, bool, &Path)> = vec![];
+    let mut taken = HashSet::from([OsString::from(control::DIR)]);
+    for path in paths {
+        let metadata = match std::fs::metadata(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                bail!("{} does not exist", path.display())
+            }
+            metadata => metadata.with_context(|| format!("Reading {}", path.display()))?,
+        };
+        let is_archive = metadata.is_file() && archive::stem(path).is_some();
+        if metadata.is_file() && path.extension().is_some_and(|extension| extension == "zst") {
+            bail!(
+                "{} is zstd-compressed, which is not supported",
+                path.display()
+            );
+        }
+        if !is_archive && !metadata.is_dir() {
+            bail!(
+                "{} is neither a directory nor a .crate, .tar.gz, .tgz or .tar archive",
+                path.display()
+            );
+        }
+        let resolved = path
+            .canonicalize()
+            .with_context(|| format!("Reading {}", path.display()))?;
+        // The same archive twice would be two crate directories of one
+        // archive, which the filesystem keys its crates by.
+        let earlier = canonical.iter().find(|(earlier, earlier_is_archive, _)| {
+            *earlier == resolved
+                || is_archive && resolved.parent() == Some(earlier.as_path())
+                || *earlier_is_archive && earlier.parent() == Some(resolved.as_path())
+        });
+        if let Some((_, _, earlier)) = earlier {
+            bail!(
+                "{} is already mounted through {}",
+                path.display(),
+                earlier.display()
+            );
+        }
+        let name = match is_archive {
+            true => archive::stem(path).unwrap().to_os_string(),
+            false => resolved
+                .file_name()
+                .map_or_else(|| OsString::from("_"), |name| name.to_os_string()),
+        };
+        let mut dir_name = name.clone();
+        for suffix in 2.. {
+            if taken.insert(dir_name.clone()) {
+                break;
+            }
+            dir_name = name.clone();
+            dir_name.push(format!("-{suffix}"));
+        }
+        canonical.push((resolved, is_archive, path));
+        sources.push(Source {
+            path: path.clone(),
+            is_archive,
+            dir_name: Some(dir_name),
+            top: 0,
+            listed_mtime: None,
+        });
+    }
+    if let [source] = &mut sources[..] {
+        source.dir_name = None;
+    }
+    Ok(sources)
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use log::warn;
+
+use crate::content_cache::EntryKey;
+
+/// Decompressed copies of files too large for the content cache, in
+/// `--spill-dir`, so that a read near the end of a large file does not
+/// decompress everything in front of it every time.
+///
+/// At most `capacity` bytes are kept on disk, least recently used first
+/// out. A copy is only used while its archive keeps the mtime it had when
+/// the copy was made.
+///
+/// This stands in for a gzip seek index: resuming inflate at a checkpoint
+/// needs the 32 KiB window primed into the decompressor, which the Rust
+/// backend of `flate2` this crate builds with cannot do, and a copy on
+/// disk also makes every later read a plain `pread`.
+pub struct SpillCache {
+    dir: PathBuf,
+    capacity: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<EntryKey, Spilled>,
+    bytes: u64,
+    tick: u64,
+    next_file: u64,
+}
+
+struct Spilled {
+    file: Arc<File>,
+    path: PathBuf,
+    len: u64,
+    archive_mtime: SystemTime,
+    last_used: u64,
+}
+
+impl SpillCache {
+    pub fn new(dir: PathBuf, capacity: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            capacity,
+            state: Mutex::default(),
+        })
+    }
+
+    pub fn fits(&self, size: u64) -> bool {
+        size <= self.capacity
+    }
+
+    pub fn get(&self, key: &EntryKey, archive_mtime: SystemTime) -> Option<Arc<File>> {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        let spilled = state.entries.get_mut(key)?;
+        if spilled.archive_mtime != archive_mtime {
+            let stale = state.entries.remove(key).unwrap();
+            state.bytes -= stale.len;
+            remove(&stale.path);
+            return None;
+        }
+        spilled.last_used = tick;
+        Some(spilled.file.clone())
+    }
+
+    /// A new file to spill an entry to, with the path to hand to
+    /// [`Self::insert`].
+    pub fn create(&self) -> std::io::Result<(File, PathBuf)> {
+        let n = {
+            let mut state = self.state.lock().unwrap();
+            state.next_file += 1;
+            state.next_file
+        };
+        let path = self.dir.join(format!("{}-{n}.spill", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok((file, path))
+    }
+
+    pub fn insert(&self, key: EntryKey, file: Arc<File>, path: PathBuf, archive_mtime: SystemTime) {
+        let len = match file.metadata() {
+            Ok(metadata) if self.fits(metadata.len()) => metadata.len(),
+            _ => return remove(&path),
+        };
+        let mut state = self.state.lock().unwrap();
+        while state.bytes + len > self.capacity {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, spilled)| spilled.last_used)
+                .map(|(key, _)| key.clone());
+            let Some(oldest) = oldest else { break };
+            let evicted = state.entries.remove(&oldest).unwrap();
+            state.bytes -= evicted.len;
+            remove(&evicted.path);
+        }
+        state.tick += 1;
+        let spilled = Spilled {
+            file,
+            path,
+            len,
+            archive_mtime,
+            last_used: state.tick,
+        };
+        if let Some(replaced) = state.entries.insert(key, spilled) {
+            state.bytes -= replaced.len;
+            remove(&replaced.path);
+        }
+        state.bytes += len;
+    }
+
+    /// Removes every spilled file.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        for (_, spilled) in state.entries.drain() {
+            remove(&spilled.path);
+        }
+        state.bytes = 0;
+    }
+}
+
+impl Drop for SpillCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("[spill] removing {}: {e}", path.to_string_lossy());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Duration};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    const MTIME: SystemTime = SystemTime::UNIX_EPOCH;
+
+    fn key(index: usize) -> EntryKey {
+        (PathBuf::from("a-1.0.0.crate"), 2, index)
+    }
+
+    /// Spills `len` bytes as entry `index`.
+    fn spill(cache: &SpillCache, index: usize, len: usize) {
+        let (mut file, path) = cache.create().unwrap();
+        file.write_all(&vec![index as u8; len]).unwrap();
+        cache.insert(key(index), Arc::new(file), path, MTIME);
+    }
+
+    fn files(dir: &TempDir) -> usize {
+        std::fs::read_dir(dir.path()).unwrap().count()
+    }
+
+    #[test]
+    fn the_least_recently_used_are_evicted_beyond_capacity() {
+        let dir = TempDir::new("spill");
+        let cache = SpillCache::new(dir.path().to_path_buf(), 300).unwrap();
+        spill(&cache, 0, 100);
+        spill(&cache, 1, 100);
+        spill(&cache, 2, 100);
+        assert!(cache.get(&key(0), MTIME).is_some());
+        spill(&cache, 3, 150);
+        assert!(cache.get(&key(1), MTIME).is_none());
+        assert!(cache.get(&key(2), MTIME).is_none());
+        assert!(cache.get(&key(0), MTIME).is_some());
+        assert_eq!(
+            cache.get(&key(3), MTIME).unwrap().metadata().unwrap().len(),
+            150
+        );
+        assert_eq!(cache.state.lock().unwrap().bytes, 250);
+        assert_eq!(files(&dir), 2);
+    }
+
+    #[test]
+    fn files_larger_than_the_capacity_are_not_kept() {
+        let dir = TempDir::new("spill");
+        let cache = SpillCache::new(dir.path().to_path_buf(), 100).unwrap();
+        spill(&cache, 0, 100);
+        assert!(!cache.fits(101));
+        spill(&cache, 1, 101);
+        assert!(cache.get(&key(1), MTIME).is_none());
+        assert!(cache.get(&key(0), MTIME).is_some());
+        assert_eq!(files(&dir), 1);
+    }
+
+    #[test]
+    fn a_changed_archive_mtime_drops_the_copy() {
+        let dir = TempDir::new("spill");
+        let cache = SpillCache::new(dir.path().to_path_buf(), 100).unwrap();
+        spill(&cache, 0, 10);
+        assert!(cache.get(&key(0), MTIME + Duration::from_secs(1)).is_none());
+        assert!(cache.get(&key(0), MTIME).is_none());
+        assert_eq!(cache.state.lock().unwrap().bytes, 0);
+        assert_eq!(files(&dir), 0);
+    }
+
+    #[test]
+    fn spilled_files_are_removed_on_drop() {
+        let dir = TempDir::new("spill");
+        let cache = SpillCache::new(dir.path().to_path_buf(), 100).unwrap();
+        spill(&cache, 0, 10);
+        spill(&cache, 0, 20);
+        assert_eq!(files(&dir), 1);
+        drop(cache);
+        assert_eq!(files(&dir), 0);
+    }
+}
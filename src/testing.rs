@@ -0,0 +1,533 @@
+//! `.crate` archives built on the fly, for testing this crate and what is
+//! built on it (with the `test-fixtures` feature): well-formed ones, with
+//! a description of the tree `tar` would extract from them, and damaged
+//! ones; and what populating a registry of them costs in memory.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    mem::size_of,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::CargoCacheFs;
+
+/// Every archive entry gets this mtime unless given another.
+pub const MTIME: u64 = 1_600_000_000;
+
+/// A directory under the system temporary directory, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(label: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "fuse-crates-test-{}-{}-{label}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// What extracting a [`Fixture`] leaves at a path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    Dir,
+    File {
+        contents: Vec<u8>,
+        mode: u32,
+        mtime: u64,
+    },
+    Symlink(PathBuf),
+}
+
+/// What extracting a [`Fixture`] leaves, by path from the extraction
+/// directory. Hard links show up as copies of their target.
+pub type Tree = BTreeMap<PathBuf, Node>;
+
+/// Ways in which [`Fixture::write_corrupt_to`] damages an archive.
+#[derive(Clone, Copy, Debug)]
+pub enum Corruption {
+    /// The gzip stream cut off halfway, inside the deflate data.
+    TruncatedGzip,
+    /// The header of the entry at this index, counted from 0, fails its
+    /// checksum.
+    BadChecksum(usize),
+}
+
+/// A `.crate` archive, its entries given relative to the `<stem>/` they
+/// are put under.
+pub struct Fixture {
+    stem: String,
+    builder: tar::Builder<Vec<u8>>,
+    /// Where each entry's header starts in the tar stream.
+    headers: Vec<usize>,
+    expected: Tree,
+}
+
+impl Fixture {
+    pub fn new(stem: &str) -> Self {
+        Self {
+            stem: stem.to_owned(),
+            builder: tar::Builder::new(vec![]),
+            headers: vec![],
+            expected: Tree::new(),
+        }
+    }
+
+    fn header(kind: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_mtime(MTIME);
+        header
+    }
+
+    /// Starts an entry, returning its path in the archive.
+    fn entry(&mut self, path: &str) -> PathBuf {
+        self.headers.push(self.builder.get_ref().len());
+        Path::new(&self.stem).join(path)
+    }
+
+    /// Records what extracting the entry at `path` leaves there: a later
+    /// entry replaces an earlier one, except that a file never replaces a
+    /// directory.
+    fn expect(&mut self, path: PathBuf, node: Node) {
+        for parent in path.ancestors().skip(1) {
+            if parent.as_os_str().is_empty() {
+                break;
+            }
+            if self.expected.get(parent) != Some(&Node::Dir) {
+                self.expected.insert(parent.to_path_buf(), Node::Dir);
+            }
+        }
+        if self.expected.get(&path) != Some(&Node::Dir) {
+            self.expected.insert(path, node);
+        }
+    }
+
+    pub fn dir(mut self, path: &str) -> Self {
+        let mut header = Self::header(tar::EntryType::Directory, 0o755, 0);
+        let path = self.entry(path);
+        self.builder
+            .append_data(&mut header, path.join(""), std::io::empty())
+            .unwrap();
+        self.expect(path, Node::Dir);
+        self
+    }
+
+    pub fn file(self, path: &str, contents: &[u8]) -> Self {
+        self.file_with(path, contents, 0o644, MTIME)
+    }
+
+    pub fn file_with_mode(self, path: &str, contents: &[u8], mode: u32) -> Self {
+        self.file_with(path, contents, mode, MTIME)
+    }
+
+    /// A file with the raw `mtime`, two's complement if negative.
+    pub fn file_at(self, path: &str, contents: &[u8], mtime: u64) -> Self {
+        self.file_with(path, contents, 0o644, mtime)
+    }
+
+    fn file_with(mut self, path: &str, contents: &[u8], mode: u32, mtime: u64) -> Self {
+        let mut header = Self::header(tar::EntryType::Regular, mode, contents.len() as u64);
+        header.set_mtime(mtime);
+        let path = self.entry(path);
+        self.builder
+            .append_data(&mut header, &path, contents)
+            .unwrap();
+        let contents = contents.to_vec();
+        self.expect(
+            path,
+            Node::File {
+                contents,
+                mode,
+                mtime,
+            },
+        );
+        self
+    }
+
+    /// A second file entry for `path`, as some packagers write; the later
+    /// one wins when extracting.
+    pub fn duplicate(self, path: &str, contents: &[u8]) -> Self {
+        assert!(
+            self.expected
+                .contains_key(&Path::new(&self.stem).join(path)),
+            "{path} is not in the archive yet"
+        );
+        self.file(path, contents)
+    }
+
+    pub fn symlink(mut self, path: &str, target: &str) -> Self {
+        let mut header = Self::header(tar::EntryType::Symlink, 0o777, 0);
+        let path = self.entry(path);
+        self.builder
+            .append_link(&mut header, &path, target)
+            .unwrap();
+        self.expect(path, Node::Symlink(PathBuf::from(target)));
+        self
+    }
+
+    /// Another name for `target`, which is also under `<stem>/`.
+    pub fn hard_link(mut self, path: &str, target: &str) -> Self {
+        let mut header = Self::header(tar::EntryType::Link, 0o644, 0);
+        let target = Path::new(&self.stem).join(target);
+        let path = self.entry(path);
+        self.builder
+            .append_link(&mut header, &path, &target)
+            .unwrap();
+        if let Some(target) = self.expected.get(&target).cloned() {
+            self.expect(path, target);
+        }
+        self
+    }
+
+    /// An entry named exactly `name`, outside `<stem>/` and without the
+    /// checks `tar` does on paths, for archives no well-behaved tool makes.
+    /// It is left out of [`Fixture::expected`].
+    pub fn raw(mut self, name: &[u8], kind: tar::EntryType, contents: &[u8]) -> Self {
+        let mut header = Self::header(kind, 0o644, contents.len() as u64);
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_cksum();
+        self.headers.push(self.builder.get_ref().len());
+        self.builder.append(&header, contents).unwrap();
+        self
+    }
+
+    /// What extracting the archive leaves, but for [`Fixture::raw`]
+    /// entries.
+    pub fn expected(&self) -> &Tree {
+        &self.expected
+    }
+
+    /// The uncompressed tar stream.
+    pub fn into_tar(self) -> Vec<u8> {
+        self.builder.into_inner().unwrap()
+    }
+
+    /// The `.crate` file's contents: the tar stream, gzip-compressed.
+    pub fn into_gzip(self) -> Vec<u8> {
+        gzip(&self.into_tar())
+    }
+
+    /// Writes `<dir>/<stem>.crate`.
+    pub fn write_to(self, dir: &Path) -> PathBuf {
+        let path = dir.join(format!("{}.crate", self.stem));
+        std::fs::write(&path, self.into_gzip()).unwrap();
+        path
+    }
+
+    /// Writes `<dir>/<stem>.crate`, damaged by `corruption`.
+    pub fn write_corrupt_to(self, dir: &Path, corruption: Corruption) -> PathBuf {
+        let path = dir.join(format!("{}.crate", self.stem));
+        let gzip = match corruption {
+            Corruption::TruncatedGzip => {
+                let mut gzip = self.into_gzip();
+                gzip.truncate(gzip.len() / 2);
+                gzip
+            }
+            Corruption::BadChecksum(index) => {
+                let start = self.headers[index];
+                let mut tar = self.into_tar();
+                // The checksum field, octal digits that no longer add up.
+                let checksum = &mut tar[start + 148..start + 154];
+                checksum[0] = if checksum[0] == b'7' { b'6' } else { b'7' };
+                gzip(&tar)
+            }
+        };
+        std::fs::write(&path, gzip).unwrap();
+        path
+    }
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+    gz.write_all(data).unwrap();
+    gz.finish().unwrap()
+}
+
+/// Writes `data` gzip-compressed to `path`.
+pub fn write_gzip(path: &Path, data: &[u8]) {
+    File::create(path).unwrap().write_all(&gzip(data)).unwrap();
+}
+
+/// Bytes that do not compress, so that reads of them go through the
+/// decompressor for real.
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+/// Checks that `dir` holds what `expected` describes and nothing else:
+/// the same names, file types, contents, link targets, file mtimes and
+/// execute bits.
+pub fn assert_tree(dir: &Path, expected: &Tree) {
+    let mut found = Tree::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in std::fs::read_dir(dir.join(&relative)).unwrap() {
+            let path = relative.join(entry.unwrap().file_name());
+            let metadata = std::fs::symlink_metadata(dir.join(&path)).unwrap();
+            let node = if metadata.is_dir() {
+                pending.push(path.clone());
+                Node::Dir
+            } else if metadata.is_symlink() {
+                Node::Symlink(std::fs::read_link(dir.join(&path)).unwrap())
+            } else {
+                Node::File {
+                    contents: std::fs::read(dir.join(&path)).unwrap(),
+                    mode: metadata.permissions().mode() & 0o111,
+                    mtime: metadata.mtime() as u64,
+                }
+            };
+            found.insert(path, node);
+        }
+    }
+    let expected = expected
+        .iter()
+        .map(|(path, node)| match node {
+            Node::File {
+                contents,
+                mode,
+                mtime,
+            } => (
+                path.clone(),
+                Node::File {
+                    contents: contents.clone(),
+                    mode: mode & 0o111,
+                    mtime: *mtime,
+                },
+            ),
+            _ => (path.clone(), node.clone()),
+        })
+        .collect::<Tree>();
+    assert_eq!(found, expected, "tree under {dir:?}");
+}
+
+/// Writes `crates` archives of `files` files each, over a few directories,
+/// the way a registry cache holds them.
+pub fn registry(dir: &Path, crates: usize, files: usize) {
+    for n in 0..crates {
+        let mut fixture = Fixture::new(&format!("synthetic-{n}-0.1.{}", n % 7))
+            .file("Cargo.toml", b"[package]\n");
+        for file in 0..files.saturating_sub(1) {
+            let path = format!("src/module_{}/file_{file}.rs", file % 4);
+            fixture = fixture.file(&path, b"pub fn f() {}\n");
+        }
+        fixture.write_to(dir);
+    }
+}
+
+/// Counts the bytes allocated through it, for a `#[global_allocator]`.
+pub struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+impl CountingAllocator {
+    /// The bytes allocated now.
+    pub fn allocated() -> usize {
+        ALLOCATED.load(Ordering::Relaxed)
+    }
+
+    /// The most bytes allocated at once since the last
+    /// [`Self::reset_peak`].
+    pub fn peak() -> usize {
+        PEAK.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_peak() {
+        PEAK.store(Self::allocated(), Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// What one of the filesystem's structures holds.
+#[derive(Debug)]
+pub struct Part {
+    pub name: &'static str,
+    pub entries: usize,
+    /// Estimated from sizes and capacities, leaving out what the
+    /// allocator rounds up and the maps' own bookkeeping.
+    pub bytes: usize,
+}
+
+/// The bytes of the slots of a hash map of `capacity` entries.
+fn slots<K, V>(capacity: usize) -> usize {
+    capacity * (size_of::<K>() + size_of::<V>() + 1)
+}
+
+fn path_bytes(path: &Path) -> usize {
+    path.as_os_str().len()
+}
+
+impl CargoCacheFs {
+    /// The filesystem of `source` with every crate populated, as mounting
+    /// it with `--eager` leaves it.
+    pub fn populated(source: &Path) -> anyhow::Result<Self> {
+        let mut fs = Self::builder(source).eager(true).build()?;
+        fs.populate().map_err(std::io::Error::from_raw_os_error)?;
+        Ok(fs)
+    }
+
+    /// What each of the structures populating fills holds, so that a
+    /// change in [`CountingAllocator::allocated`] can be told apart.
+    pub fn memory_breakdown(&self) -> Vec<Part> {
+        let inodes = self.inodes.values();
+        let paths = |inode: &crate::Inode| {
+            [&inode.link_target, &inode.disk_path, &inode.overlay_path]
+                .into_iter()
+                .flatten()
+                .map(|path| path_bytes(path))
+                .sum::<usize>()
+        };
+        let crates = self.crate_dirs.keys().map(|path| path_bytes(path));
+        let aliases = self.crate_aliases.keys().map(|(_, name)| name.len());
+        let content_cache = self.workers.stats().content_cache;
+        vec![
+            Part {
+                name: "Inode",
+                entries: self.inodes.len(),
+                bytes: self.inodes.len() * (size_of::<u64>() + size_of::<crate::Inode>()),
+            },
+            Part {
+                name: "Inode.name",
+                entries: self.inodes.len(),
+                bytes: inodes.clone().map(|inode| inode.name.capacity()).sum(),
+            },
+            Part {
+                name: "Inode.children",
+                entries: inodes.clone().map(|inode| inode.children.len()).sum(),
+                bytes: inodes
+                    .clone()
+                    .map(|inode| inode.children.capacity() * size_of::<u64>())
+                    .sum(),
+            },
+            Part {
+                name: "Inode paths",
+                entries: inodes.clone().filter(|inode| paths(inode) > 0).count(),
+                bytes: inodes.map(paths).sum(),
+            },
+            Part {
+                name: "crate_dirs",
+                entries: self.crate_dirs.len(),
+                bytes: slots::<PathBuf, (u64, u64)>(self.crate_dirs.capacity())
+                    + crates.sum::<usize>(),
+            },
+            Part {
+                name: "archive_stamps",
+                entries: self.archive_stamps.len(),
+                bytes: slots::<PathBuf, crate::ArchiveStamp>(self.archive_stamps.capacity())
+                    + self
+                        .archive_stamps
+                        .keys()
+                        .map(|path| path_bytes(path))
+                        .sum::<usize>(),
+            },
+            Part {
+                name: "crate_aliases",
+                entries: self.crate_aliases.len(),
+                bytes: slots::<(u64, std::ffi::OsString), u64>(self.crate_aliases.capacity())
+                    + aliases.sum::<usize>(),
+            },
+            Part {
+                name: "lookups",
+                entries: self.lookups.len(),
+                bytes: slots::<u64, u64>(self.lookups.capacity()),
+            },
+            Part {
+                name: "content cache",
+                entries: content_cache.entries,
+                bytes: content_cache.bytes as usize,
+            },
+        ]
+    }
+}
+
+#[cfg(test)]
+pub(crate) use unit::*;
+
+/// What the unit tests add, driving the filesystem without mounting it.
+#[cfg(test)]
+mod unit {
+    use std::{ffi::OsStr, path::Path};
+
+    use crate::{cli, source, CargoCacheFs};
+
+    /// The filesystem of `source` with `options`, populated as when mounted.
+    pub(crate) fn populated(source: &Path, options: &cli::Options) -> CargoCacheFs {
+        let sources = source::resolve(&[source.to_path_buf()]).unwrap();
+        let mut fs = CargoCacheFs::with_options(sources, options, None, None).unwrap();
+        fs.populate().unwrap();
+        fs
+    }
+
+    /// Options that put crates at the root and populate them when looked into.
+    pub(crate) fn options() -> cli::Options {
+        cli::Options {
+            use_src_dir: false,
+            io_threads: 1,
+            jobs: 1,
+            ..cli::Options::default()
+        }
+    }
+
+    impl CargoCacheFs {
+        /// The inode at `path` from the root, populating crates on the way.
+        pub fn find(&mut self, path: &str) -> Option<u64> {
+            let mut ino = fuser::FUSE_ROOT_ID;
+            for name in Path::new(path).iter() {
+                self.populate_deferred(ino).ok()?;
+                ino = self.child_named(ino, name)?;
+            }
+            self.populate_deferred(ino).ok()?;
+            Some(ino)
+        }
+
+        /// The names in directory `ino`, in order.
+        pub fn names(&self, ino: u64) -> Vec<&OsStr> {
+            self.inodes[&ino]
+                .children
+                .iter()
+                .map(|child| self.inodes[child].name.as_os_str())
+                .collect()
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a throttled read wakes up to check for shutdown.
+const SLICE: Duration = Duration::from_millis(50);
+
+/// Time as the limiters see it, so that tests can run it without waiting.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Token bucket holding at most one second worth of bytes.
+///
+/// A read takes its bytes out up front and may leave the bucket in debt;
+/// the debt is what it, and whoever comes next, has to sleep off.
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    bucket: Mutex<Bucket>,
+    waited_micros: AtomicU64,
+    clock: Box<dyn Clock>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// What a limiter shows in `/.fuse-crates/stats`.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub waited: Duration,
+    /// Bytes that can be read right away; negative while in debt.
+    pub tokens: i64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self::with_clock(bytes_per_second, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(bytes_per_second: u64, clock: Box<dyn Clock>) -> Self {
+        Self {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                refilled: clock.now(),
+            }),
+            waited_micros: AtomicU64::new(0),
+            clock,
+        }
+    }
+
+    /// Tops the bucket up for the time since it last was.
+    fn refill(&self, bucket: &mut Bucket) {
+        let rate = self.bytes_per_second as f64;
+        let now = self.clock.now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate);
+        bucket.refilled = now;
+    }
+
+    fn reserve(&self, bytes: u64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-bucket.tokens / self.bytes_per_second as f64)
+    }
+
+    /// Sleeps until `bytes` fit in the budget. Returns false if `cancelled`
+    /// was set in the meantime.
+    pub fn acquire(&self, bytes: u64, cancelled: &AtomicBool) -> bool {
+        let wait = self.reserve(bytes);
+        let started = self.clock.now();
+        while let Some(left) = wait.checked_sub(self.clock.now() - started) {
+            if left.is_zero() || cancelled.load(Ordering::Relaxed) {
+                break;
+            }
+            self.clock.sleep(left.min(SLICE));
+        }
+        let waited = self.clock.now() - started;
+        self.waited_micros
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        !cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Total time reads spent waiting on this limiter.
+    pub fn waited(&self) -> Duration {
+        Duration::from_micros(self.waited_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        Stats {
+            waited: self.waited(),
+            tokens: bucket.tokens.floor() as i64,
+        }
+    }
+}
+
+/// Waits on each of `limiters` in turn, e.g. the global one and that of
+/// the handle being read. Returns false if `cancelled` was set.
+pub fn acquire_all<'a>(
+    limiters: impl IntoIterator<Item = &'a RateLimiter>,
+    bytes: u64,
+    cancelled: &AtomicBool,
+) -> bool {
+    limiters
+        .into_iter()
+        .all(|limiter| limiter.acquire(bytes, cancelled))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// A clock that only moves when slept on.
+    #[derive(Clone)]
+    struct FakeClock {
+        start: Instant,
+        elapsed: Arc<Mutex<Duration>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                elapsed: Arc::default(),
+            }
+        }
+
+        fn elapsed(&self) -> Duration {
+            *self.elapsed.lock().unwrap()
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.advance(duration)
+        }
+    }
+
+    fn limiter(bytes_per_second: u64, clock: &FakeClock) -> RateLimiter {
+        RateLimiter::with_clock(bytes_per_second, Box::new(clock.clone()))
+    }
+
+    static GO: AtomicBool = AtomicBool::new(false);
+
+    #[test]
+    fn a_full_bucket_allows_a_burst_of_one_second() {
+        let clock = FakeClock::new();
+        let limiter = limiter(1000, &clock);
+        assert!(limiter.acquire(600, &GO));
+        assert!(limiter.acquire(400, &GO));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        assert_eq!(limiter.stats().tokens, 0);
+        // The debt of the next read is slept off.
+        assert!(limiter.acquire(500, &GO));
+        assert_eq!(clock.elapsed(), Duration::from_millis(500));
+        assert_eq!(limiter.waited(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn the_bucket_refills_at_the_rate_up_to_one_second() {
+        let clock = FakeClock::new();
+        let limiter = limiter(1000, &clock);
+        assert!(limiter.acquire(1000, &GO));
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(limiter.stats().tokens, 250);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(limiter.stats().tokens, 1000);
+        // A read larger than the bucket goes into debt.
+        assert!(limiter.acquire(3000, &GO));
+        assert_eq!(limiter.waited(), Duration::from_secs(2));
+        assert_eq!(limiter.stats().tokens, 0);
+    }
+
+    #[test]
+    fn a_later_read_pays_for_the_debt_of_an_earlier_one() {
+        let clock = FakeClock::new();
+        let limiter = limiter(1024, &clock);
+        assert_eq!(limiter.reserve(1536), Duration::from_millis(500));
+        assert_eq!(limiter.stats().tokens, -512);
+        assert_eq!(limiter.reserve(512), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn the_tighter_of_the_global_and_handle_limits_applies() {
+        let clock = FakeClock::new();
+        let (global, handle) = (limiter(1000, &clock), limiter(400, &clock));
+        assert!(acquire_all([&global, &handle], 600, &GO));
+        assert_eq!(clock.elapsed(), Duration::from_millis(500));
+        assert_eq!(handle.waited(), Duration::from_millis(500));
+
+        let clock = FakeClock::new();
+        let (global, handle) = (limiter(100, &clock), limiter(1000, &clock));
+        assert!(acquire_all([&global, &handle], 300, &GO));
+        assert_eq!(clock.elapsed(), Duration::from_secs(2));
+        assert_eq!(global.waited(), Duration::from_secs(2));
+        assert_eq!(handle.waited(), Duration::ZERO);
+    }
+
+    #[test]
+    fn handles_share_the_global_limit() {
+        let clock = FakeClock::new();
+        let global = limiter(1000, &clock);
+        let (first, second) = (limiter(1000, &clock), limiter(1000, &clock));
+        assert!(acquire_all([&global, &first], 1000, &GO));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        // The second handle has its whole budget, but not the global one.
+        assert!(acquire_all([&global, &second], 1000, &GO));
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+        assert_eq!(global.waited(), Duration::from_secs(1));
+        assert_eq!(second.waited(), Duration::ZERO);
+        assert_eq!(global.stats().tokens, 0);
+    }
+
+    #[test]
+    fn cancelling_stops_the_wait() {
+        let clock = FakeClock::new();
+        let limiter = limiter(1000, &clock);
+        let cancelled = AtomicBool::new(true);
+        assert!(!limiter.acquire(5000, &cancelled));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+
+use crate::{archive, sha256};
+
+/// `--verify`: checks the sha256 of each archive before it is populated.
+///
+/// The expected digest comes from the `--checksums` file, or failing that
+/// from the `.cargo-checksum.json` Cargo leaves in the extracted crate.
+/// Crates with neither are populated unchecked.
+pub struct Verifier {
+    /// By archive file name.
+    checksums: HashMap<OsString, String>,
+    src_dir: Option<PathBuf>,
+    memo: Option<Memo>,
+}
+
+/// Digests already computed, in a file of `<sha256> <size> <mtime ns>
+/// <path>` lines appended to as archives are hashed. The last line for a
+/// path wins, and only while the archive keeps its size and mtime.
+struct Memo {
+    path: PathBuf,
+    digests: Mutex<HashMap<PathBuf, (u64, u128, String)>>,
+}
+
+impl Verifier {
+    pub fn new(
+        checksums: Option<&Path>,
+        src_dir: Option<PathBuf>,
+        memo: Option<PathBuf>,
+    ) -> Result<Self> {
+        let checksums = match checksums {
+            Some(path) => parse_checksums(
+                &std::fs::read(path)
+                    .with_context(|| format!("Reading {}", path.to_string_lossy()))?,
+            )
+            .with_context(|| format!("Parsing {}", path.to_string_lossy()))?,
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            checksums,
+            src_dir,
+            memo: memo.map(Memo::load),
+        })
+    }
+
+    /// The digest of `archive` if it was checked and matched, `None` if
+    /// there is nothing to check it against, or what is wrong with it.
+    pub fn verify(&self, archive: &Path) -> Result<Option<String>, String> {
+        let Some(expected) = self.expected(archive) else {
+            debug!("[verify] no checksum for {}", archive.to_string_lossy());
+            return Ok(None);
+        };
+        let digest = self
+            .digest(archive)
+            .map_err(|e| format!("hashing the archive: {e}"))?;
+        if digest != expected {
+            return Err(format!("sha256 is {digest}, expected {expected}"));
+        }
+        Ok(Some(digest))
+    }
+
+    fn expected(&self, archive: &Path) -> Option<String> {
+        if let Some(expected) = self.checksums.get(archive.file_name()?) {
+            return Some(expected.clone());
+        }
+        let stem = archive::stem(archive)?;
+        let json = std::fs::read(
+            self.src_dir
+                .as_ref()?
+                .join(stem)
+                .join(".cargo-checksum.json"),
+        );
+        package_checksum(&json.ok()?)
+    }
+
+    fn digest(&self, archive: &Path) -> std::io::Result<String> {
+        let metadata = std::fs::metadata(archive)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |mtime| mtime.as_nanos());
+        let key = (metadata.len(), mtime);
+        if let Some(memo) = &self.memo {
+            let digests = memo.digests.lock().unwrap();
+            match digests.get(archive) {
+                Some((size, at, digest)) if (*size, *at) == key => return Ok(digest.clone()),
+                _ => (),
+            }
+        }
+        let digest = sha256::hex_digest(File::open(archive)?)?;
+        if let Some(memo) = &self.memo {
+            memo.record(archive, key, &digest);
+        }
+        Ok(digest)
+    }
+}
+
+impl Memo {
+    fn load(path: PathBuf) -> Self {
+        let mut digests = HashMap::new();
+        match std::fs::read(&path) {
+            Ok(contents) => {
+                for line in contents.split(|&byte| byte == b'\n') {
+                    let mut fields = line.splitn(4, |&byte| byte == b' ');
+                    let fields = (fields.next(), fields.next(), fields.next(), fields.next());
+                    let (Some(digest), Some(size), Some(mtime), Some(archive)) = fields else {
+                        continue;
+                    };
+                    let number = |field| std::str::from_utf8(field).ok();
+                    let size = number(size).and_then(|size| size.parse().ok());
+                    let mtime = number(mtime).and_then(|mtime| mtime.parse().ok());
+                    let (Some(size), Some(mtime)) = (size, mtime) else {
+                        continue;
+                    };
+                    let digest = String::from_utf8_lossy(digest).into_owned();
+                    digests.insert(
+                        PathBuf::from(OsStr::from_bytes(archive)),
+                        (size, mtime, digest),
+                    );
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => warn!("[verify] reading {}: {e}", path.to_string_lossy()),
+        }
+        Self {
+            path,
+            digests: Mutex::new(digests),
+        }
+    }
+
+    fn record(&self, archive: &Path, (size, mtime): (u64, u128), digest: &str) {
+        let mut line = format!("{digest} {size} {mtime} ").into_bytes();
+        line.extend_from_slice(archive.as_os_str().as_bytes());
+        line.push(b'\n');
+        let appended = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| {
+                File::options()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?
+                    .write_all(&line)
+            });
+        if let Err(e) = appended {
+            warn!("[verify] writing {}: {e}", self.path.to_string_lossy());
+        }
+        self.digests
+            .lock()
+            .unwrap()
+            .insert(archive.to_path_buf(), (size, mtime, digest.to_owned()));
+    }
+}
+
+/// Where digests are memoized unless `--verify-cache` says otherwise.
+pub fn default_memo() -> Option<PathBuf> {
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(cache) => PathBuf::from(cache),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("fuse-crates").join("sha256"))
+}
+
+/// `sha256sum` output: `<digest>  <file name>`, `*` before binary names.
+fn parse_checksums(contents: &[u8]) -> Result<HashMap<OsString, String>> {
+    let mut checksums = HashMap::new();
+    for (number, line) in contents.split(|&byte| byte == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let Some(split) = line.iter().position(|&byte| byte == b' ') else {
+            bail!("line {}: no file name", number + 1);
+        };
+        let (digest, name) = line.split_at(split);
+        if !is_digest(digest) {
+            bail!("line {}: not a sha256 digest", number + 1);
+        }
+        let name = name.trim_ascii_start();
+        let name = name.strip_prefix(b"*").unwrap_or(name);
+        // Names are matched without their directory.
+        let name = Path::new(OsStr::from_bytes(name))
+            .file_name()
+            .with_context(|| format!("line {}: no file name", number + 1))?;
+        checksums.insert(
+            name.to_owned(),
+            String::from_utf8_lossy(digest).to_ascii_lowercase(),
+        );
+    }
+    Ok(checksums)
+}
+
+/// The `"package"` digest of a `.cargo-checksum.json`, which is that of the
+/// `.crate` the directory was extracted from.
+fn package_checksum(json: &[u8]) -> Option<String> {
+    let key = b"\"package\"";
+    let start = json.windows(key.len()).position(|window| window == key)? + key.len();
+    let rest = json[start..].trim_ascii_start().strip_prefix(b":")?;
+    let rest = rest.trim_ascii_start().strip_prefix(b"\"")?;
+    let digest = &rest[..rest.iter().position(|&byte| byte == b'"')?];
+    is_digest(digest).then(|| String::from_utf8(digest.to_ascii_lowercase()).unwrap())
+}
+
+fn is_digest(digest: &[u8]) -> bool {
+    digest.len() == 64 && digest.iter().all(u8::is_ascii_hexdigit)
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+use crate::access_log::json_escape;
+
+/// A problem found while populating a crate. Outside `--strict` these are
+/// logged and population carries on.
+#[derive(Debug)]
+pub enum Violation {
+    /// The archive could not be read to the end; the crate is left out.
+    CorruptCrate {
+        krate: String,
+        error: String,
+    },
+    PrefixMismatch {
+        krate: String,
+        detail: String,
+    },
+    /// An entry with a `..`, absolute or prefix component; it is skipped.
+    PathTraversal {
+        krate: String,
+        path: String,
+    },
+    /// A second entry for the same path. As when extracting, the later one
+    /// replaces the earlier, except that a file never replaces a directory;
+    /// a directory, or anything below one, replaces a file.
+    DuplicateEntry {
+        krate: String,
+        path: String,
+    },
+    MtimeOutOfRange {
+        krate: String,
+        path: String,
+    },
+    /// The entry data ends before its header size; the size presented is
+    /// what is actually there.
+    SizeMismatch {
+        krate: String,
+        path: String,
+        header_size: u64,
+        actual: u64,
+    },
+    /// The archive does not have the checksum it should; it is presented
+    /// as a directory explaining that.
+    ChecksumMismatch {
+        krate: String,
+        detail: String,
+    },
+    /// A hard link to an entry that is not (yet) in the archive; it is
+    /// skipped.
+    DanglingLink {
+        krate: String,
+        path: String,
+        target: String,
+    },
+    /// A FIFO, device or unknown entry type, which a crate has no use for;
+    /// it is skipped.
+    UnsupportedEntry {
+        krate: String,
+        path: String,
+        kind: String,
+    },
+}
+
+impl Violation {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::CorruptCrate { .. } => "corrupt-crate",
+            Self::PrefixMismatch { .. } => "prefix-mismatch",
+            Self::PathTraversal { .. } => "path-traversal",
+            Self::DuplicateEntry { .. } => "duplicate-entry",
+            Self::MtimeOutOfRange { .. } => "mtime-out-of-range",
+            Self::SizeMismatch { .. } => "size-mismatch",
+            Self::ChecksumMismatch { .. } => "checksum-mismatch",
+            Self::DanglingLink { .. } => "dangling-link",
+            Self::UnsupportedEntry { .. } => "unsupported-entry",
+        }
+    }
+
+    fn krate(&self) -> &str {
+        match self {
+            Self::CorruptCrate { krate, .. }
+            | Self::PrefixMismatch { krate, .. }
+            | Self::PathTraversal { krate, .. }
+            | Self::DuplicateEntry { krate, .. }
+            | Self::MtimeOutOfRange { krate, .. }
+            | Self::SizeMismatch { krate, .. }
+            | Self::ChecksumMismatch { krate, .. }
+            | Self::DanglingLink { krate, .. }
+            | Self::UnsupportedEntry { krate, .. } => krate,
+        }
+    }
+
+    /// One JSON object per line, for `--strict`.
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"violation":"{}","crate":"{}","message":"{}"}}"#,
+            self.kind(),
+            json_escape(self.krate()),
+            json_escape(&self.to_string()),
+        )
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CorruptCrate { krate, error } => write!(f, "Crate {krate}: {error}"),
+            Self::PrefixMismatch { krate, detail } => write!(f, "Crate {krate}: {detail}"),
+            Self::PathTraversal { krate, path } => {
+                write!(f, "Crate {krate}: entry {path} escapes the crate directory")
+            }
+            Self::DuplicateEntry { krate, path } => {
+                write!(f, "Crate {krate}: entry {path} appears more than once")
+            }
+            Self::MtimeOutOfRange { krate, path } => {
+                write!(f, "Crate {krate}: mtime of {path} is out of range, clamped")
+            }
+            Self::SizeMismatch {
+                krate,
+                path,
+                header_size,
+                actual,
+            } => write!(
+                f,
+                "Crate {krate}: entry {path} claims {header_size} bytes but holds {actual}"
+            ),
+            Self::ChecksumMismatch { krate, detail } => {
+                write!(f, "Crate {krate}: failed verification, {detail}")
+            }
+            Self::DanglingLink {
+                krate,
+                path,
+                target,
+            } => write!(
+                f,
+                "Crate {krate}: hard link {path} points at missing {target}"
+            ),
+            Self::UnsupportedEntry { krate, path, kind } => {
+                write!(f, "Crate {krate}: entry {path} is a {kind}, skipped")
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::{Arc, Mutex};
+
+/// The bytes of a file added with
+/// [`Builder::add_virtual_file`](crate::Builder::add_virtual_file).
+///
+/// Bytes and strings are contents that never change; a closure is called
+/// every time the file is opened or its size asked for.
+pub trait VirtualContent: Send {
+    fn contents(&self) -> Vec<u8>;
+
+    /// Changes whenever [`Self::contents`] would, so that they are only
+    /// generated again then; `None`, the default, generates them every
+    /// time.
+    fn version(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl VirtualContent for Vec<u8> {
+    fn contents(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn version(&self) -> Option<u64> {
+        Some(0)
+    }
+}
+
+impl VirtualContent for String {
+    fn contents(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn version(&self) -> Option<u64> {
+        Some(0)
+    }
+}
+
+impl VirtualContent for &'static str {
+    fn contents(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn version(&self) -> Option<u64> {
+        Some(0)
+    }
+}
+
+impl<F: Fn() -> Vec<u8> + Send> VirtualContent for F {
+    fn contents(&self) -> Vec<u8> {
+        self()
+    }
+}
+
+/// The contents of a file generated rather than read from an archive,
+/// kept for as long as the data they are generated from stays the same.
+///
+/// Every open takes a [`VirtualFile::snapshot`] and reads from it, so that
+/// a reader keeps the bytes it opened however often they are generated
+/// again meanwhile, and the size reported for the file is that of the
+/// bytes a reader opening it then gets. Whoever asks for a snapshot while
+/// the contents are being generated waits for them rather than generating
+/// them a second time.
+#[derive(Default)]
+pub struct VirtualFile {
+    /// The last contents generated and the version they were generated
+    /// for.
+    generated: Mutex<Option<(u64, Arc<[u8]>)>>,
+}
+
+impl VirtualFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The contents for `version`, from `generate` unless the last ones
+    /// were generated for the same version. Contents without a version are
+    /// generated every time.
+    pub fn snapshot(&self, version: Option<u64>, generate: impl FnOnce() -> Vec<u8>) -> Arc<[u8]> {
+        let mut generated = self.generated.lock().unwrap();
+        match (&*generated, version) {
+            (Some((last, contents)), Some(version)) if *last == version => contents.clone(),
+            (_, Some(version)) => {
+                let contents = Arc::<[u8]>::from(generate());
+                *generated = Some((version, contents.clone()));
+                contents
+            }
+            (_, None) => {
+                *generated = None;
+                generate().into()
+            }
+        }
+    }
+
+    /// The size of [`Self::snapshot`] for `version`.
+    pub fn size(&self, version: Option<u64>, generate: impl FnOnce() -> Vec<u8>) -> u64 {
+        self.snapshot(version, generate).len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Barrier,
+        },
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn size_is_that_of_the_snapshot() {
+        let file = VirtualFile::new();
+        let generated = AtomicUsize::new(0);
+        let generate = || {
+            generated.fetch_add(1, Ordering::Relaxed);
+            b"{\"crates\":3}\n".to_vec()
+        };
+        assert_eq!(file.size(Some(1), generate), 13);
+        assert_eq!(&*file.snapshot(Some(1), generate), b"{\"crates\":3}\n");
+        assert_eq!(generated.load(Ordering::Relaxed), 1);
+        assert_eq!(file.size(None, generate), 13);
+        assert_eq!(file.size(None, generate), 13);
+        assert_eq!(generated.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn contents_are_generated_again_for_another_version() {
+        let file = VirtualFile::new();
+        let first = file.snapshot(Some(1), || b"one".to_vec());
+        assert_eq!(&*file.snapshot(Some(1), || b"not again".to_vec()), b"one");
+        let second = file.snapshot(Some(2), || b"two, longer".to_vec());
+        assert_eq!(&*second, b"two, longer");
+        assert_eq!(file.size(Some(2), || unreachable!()), 11);
+        // What was opened before stays as it was.
+        assert_eq!(&*first, b"one");
+        // Going back to a version does not find it again.
+        assert_eq!(
+            &*file.snapshot(Some(1), || b"one again".to_vec()),
+            b"one again"
+        );
+    }
+
+    #[test]
+    fn snapshots_taken_while_generating_wait_for_the_contents() {
+        let file = VirtualFile::new();
+        let opened = file.snapshot(Some(1), || b"old".to_vec());
+        let generating = Barrier::new(2);
+        let generated = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let regenerating = scope.spawn(|| {
+                file.snapshot(Some(2), || {
+                    generating.wait();
+                    // Taking a while, with the other snapshot asked for.
+                    thread::sleep(std::time::Duration::from_millis(50));
+                    generated.fetch_add(1, Ordering::Relaxed);
+                    b"new".to_vec()
+                })
+            });
+            generating.wait();
+            // A reader of the old generation is not held up.
+            assert_eq!(&*opened, b"old");
+            let waited = file.snapshot(Some(2), || {
+                generated.fetch_add(1, Ordering::Relaxed);
+                b"generated twice".to_vec()
+            });
+            assert_eq!(&*waited, b"new");
+            assert_eq!(&*regenerating.join().unwrap(), b"new");
+        });
+        assert_eq!(generated.load(Ordering::Relaxed), 1);
+    }
+}
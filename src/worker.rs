@@ -0,0 +1,659 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    archive::Decoder,
+    archive_cache::ArchiveCache,
+    content_cache::{self, ContentCache, EntryKey},
+    spill::SpillCache,
+    throttle::{self, RateLimiter},
+    SCRATCH_SIZE,
+};
+
+pub struct ReadJob {
+    pub ino: u64,
+    pub krate_path: PathBuf,
+    /// The inode of the crate's directory, which is another one once the
+    /// archive was replaced, so that what was cached of the archive before
+    /// is not taken for what it holds now.
+    pub krate: u64,
+    pub entry_index: usize,
+    /// Read instead of the archive while it is there.
+    pub disk_path: Option<PathBuf>,
+    pub file_size: u64,
+    pub offset: i64,
+    pub size: u32,
+    pub reply: fuser::ReplyData,
+    /// Count of the bytes the reading process was served through the
+    /// handle, for `access-summary.json` and `--access-log`.
+    pub served: Arc<AtomicU64>,
+    /// `--max-read-bps-per-handle` limiter of the handle being read.
+    pub throttle: Option<Arc<RateLimiter>>,
+    pub cursor: Arc<Mutex<Option<Cursor>>>,
+    /// The archive the handle was opened on, read rather than whatever is at
+    /// `krate_path` by then. Lent to `cursor` while it holds a decoder.
+    pub archive: Arc<Mutex<Option<File>>>,
+}
+
+/// Where the previous read of a handle left off in its entry. Holds an open
+/// archive until the handle is released or reads somewhere else.
+pub struct Cursor {
+    decoder: Decoder,
+    /// How far into the entry's data `decoder` is.
+    position: u64,
+}
+
+/// Runs archive reads off the FUSE dispatch thread.
+///
+/// Jobs are routed to a worker by inode, so reads of the same file are
+/// answered in the order they were submitted.
+pub struct WorkerPool {
+    senders: Vec<mpsc::Sender<(u64, ReadJob)>>,
+    shared: Arc<Shared>,
+    next_job: u64,
+}
+
+struct Shared {
+    cache: Arc<ArchiveCache>,
+    /// `--verify-reads`: check entry sizes and the gzip CRC where a read
+    /// makes it cheap, and fail every later read of a crate that does not
+    /// match.
+    verify_reads: bool,
+    corrupt: Mutex<HashSet<PathBuf>>,
+    cancelled: AtomicBool,
+    contents: ContentCache,
+    spill: Option<SpillCache>,
+    /// `--max-read-bps` limiter shared by every read.
+    throttle: Option<RateLimiter>,
+    /// Queued and running jobs, as `(ino, offset)` by job number.
+    in_flight: Mutex<HashMap<u64, (u64, i64)>>,
+    drained: Condvar,
+    bytes_served: AtomicU64,
+    decompressions_started: AtomicU64,
+}
+
+/// Counters for `/.fuse-crates/stats`.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub bytes_served: u64,
+    /// Archives opened for reads, rather than reused.
+    pub archives_opened: u64,
+    /// Reads that decompressed an archive from its start, rather than
+    /// continuing a handle's cursor.
+    pub decompressions_started: u64,
+    pub content_cache: content_cache::Stats,
+    /// `--max-read-bps`.
+    pub throttle: Option<throttle::Stats>,
+}
+
+impl WorkerPool {
+    pub fn new(
+        workers: usize,
+        cache: Arc<ArchiveCache>,
+        verify_reads: bool,
+        content_cache_size: u64,
+        spill: Option<SpillCache>,
+        max_read_bps: Option<u64>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            cache,
+            verify_reads,
+            corrupt: Mutex::new(HashSet::new()),
+            cancelled: AtomicBool::new(false),
+            contents: ContentCache::new(content_cache_size),
+            spill,
+            throttle: max_read_bps.map(RateLimiter::new),
+            in_flight: Mutex::new(HashMap::new()),
+            drained: Condvar::new(),
+            bytes_served: AtomicU64::new(0),
+            decompressions_started: AtomicU64::new(0),
+        });
+        let senders = (0..workers.max(1))
+            .map(|n| {
+                let (sender, receiver) = mpsc::channel::<(u64, ReadJob)>();
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("fuse-crates-io-{n}"))
+                    .spawn(move || {
+                        let mut scratch = vec![0u8; SCRATCH_SIZE];
+                        for (id, job) in receiver {
+                            if !throttle(&job, &shared) {
+                                warn!("[read] ino 0x{:016x} => EIO, shutting down", job.ino);
+                                job.reply.error(libc::EIO);
+                            } else {
+                                serve_read(job, &shared, &mut scratch);
+                            }
+                            shared.in_flight.lock().unwrap().remove(&id);
+                            shared.drained.notify_all();
+                        }
+                    })
+                    .expect("Spawning I/O worker");
+                sender
+            })
+            .collect();
+        Self {
+            senders,
+            shared,
+            next_job: 0,
+        }
+    }
+
+    pub fn submit(&mut self, job: ReadJob) {
+        let id = self.next_job;
+        self.next_job += 1;
+        self.shared
+            .in_flight
+            .lock()
+            .unwrap()
+            .insert(id, (job.ino, job.offset));
+        let worker = (job.ino % self.senders.len() as u64) as usize;
+        if let Err(mpsc::SendError((_, job))) = self.senders[worker].send((id, job)) {
+            self.shared.in_flight.lock().unwrap().remove(&id);
+            warn!(
+                "[read] ino 0x{:016x} => EIO, worker {worker} is gone",
+                job.ino
+            );
+            job.reply.error(libc::EIO);
+        }
+    }
+
+    /// An open archive for a handle to read through, from those kept open
+    /// between reads if there is one.
+    pub fn checkout(&self, krate_path: &Path) -> std::io::Result<File> {
+        self.shared.cache.checkout(krate_path)
+    }
+
+    /// Keeps the archive a released handle read through open for later
+    /// reads.
+    pub fn checkin(&self, krate_path: PathBuf, file: File) {
+        self.shared.cache.checkin(krate_path, file)
+    }
+
+    /// Forgets what is known of the archive at `krate_path`, which was
+    /// replaced: the handles kept open to it and whether it failed
+    /// verification.
+    pub fn forget(&self, krate_path: &Path) {
+        self.shared.cache.forget(krate_path);
+        self.shared.corrupt.lock().unwrap().remove(krate_path);
+    }
+
+    /// Forgets every decompressed and spilled file and closes the archives
+    /// kept open between reads.
+    pub fn drop_caches(&self) {
+        self.shared.contents.clear();
+        if let Some(spill) = &self.shared.spill {
+            spill.clear();
+        }
+        self.shared.cache.clear();
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            bytes_served: self.shared.bytes_served.load(Ordering::Relaxed),
+            archives_opened: self.shared.cache.opened(),
+            decompressions_started: self.shared.decompressions_started.load(Ordering::Relaxed),
+            content_cache: self.shared.contents.stats(),
+            throttle: self.shared.throttle.as_ref().map(RateLimiter::stats),
+        }
+    }
+
+    /// Fails every queued read with EIO and waits up to `deadline` for the
+    /// running ones. A read stuck in decompression cannot be interrupted, so
+    /// it is logged and left behind rather than holding up the unmount.
+    pub fn shutdown(&self, deadline: Duration) {
+        self.shared.cancelled.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+        let mut in_flight = self.shared.in_flight.lock().unwrap();
+        while !in_flight.is_empty() {
+            let Some(left) = deadline.checked_sub(started.elapsed()) else {
+                break;
+            };
+            in_flight = self.shared.drained.wait_timeout(in_flight, left).unwrap().0;
+        }
+        for (ino, offset) in in_flight.values() {
+            warn!("[shutdown] abandoning read of ino 0x{ino:016x} at offset {offset}");
+        }
+        if let Some(spill) = &self.shared.spill {
+            spill.clear();
+        }
+        if let Some(throttle) = &self.shared.throttle {
+            info!(
+                "[throttle] reads waited {:.3}s in total on --max-read-bps",
+                throttle.waited().as_secs_f64()
+            );
+        }
+    }
+}
+
+/// Waits for the read bandwidth limits before any decompression is done.
+/// Returns false if the pool is shutting down.
+fn throttle(job: &ReadJob, shared: &Shared) -> bool {
+    let bytes = u64::from(job.size).min(job.file_size.saturating_sub(job.offset as u64));
+    throttle::acquire_all(
+        shared.throttle.iter().chain(job.throttle.as_deref()),
+        bytes,
+        &shared.cancelled,
+    )
+}
+
+fn serve_read(job: ReadJob, shared: &Shared, scratch: &mut [u8]) {
+    let ReadJob {
+        ino,
+        krate_path,
+        krate,
+        entry_index,
+        disk_path,
+        file_size,
+        offset,
+        size,
+        reply,
+        served,
+        cursor,
+        archive,
+        ..
+    } = job;
+    let cache = &shared.cache;
+    if shared.corrupt.lock().unwrap().contains(&krate_path) {
+        warn!("[read] ino 0x{ino:016x} => EIO, crate failed verification");
+        return reply.error(libc::EIO);
+    }
+    if let Some(disk_path) = disk_path {
+        let size = size.min(file_size.saturating_sub(offset as u64) as u32);
+        match File::open(&disk_path).and_then(|file| read_at(&file, offset, size)) {
+            Ok(data) => return reply_data(shared, &data, &served, reply),
+            Err(e) => warn!(
+                "[read] ino 0x{ino:016x} {}: {e}, reading the archive instead",
+                disk_path.to_string_lossy()
+            ),
+        }
+    }
+    // Files that fit in the content cache are decompressed whole, once.
+    let whole = shared.contents.fits(file_size);
+    let key = (krate_path, krate, entry_index);
+    if let Some(data) = whole.then(|| shared.contents.get(&key)).flatten() {
+        return reply_data(shared, slice(&data, offset, size), &served, reply);
+    }
+    if let Some(spill) = shared
+        .spill
+        .as_ref()
+        .filter(|spill| !whole && spill.fits(file_size))
+    {
+        return match serve_spilled(spill, key, file_size, &archive, shared) {
+            Ok(file) => match read_at(&file, offset, size) {
+                Ok(data) => reply_data(shared, &data, &served, reply),
+                Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+            },
+            Err(e) => {
+                warn!("[read] ino 0x{ino:016x} => EIO, {e}");
+                reply.error(e.raw_os_error().unwrap_or(libc::EIO))
+            }
+        };
+    }
+    let (krate_path, _, entry_index) = key;
+    let (read_offset, read_size) = match whole {
+        true => (0, file_size),
+        false => (offset, u64::from(size)),
+    };
+    // A handle read front to back continues where its previous read left
+    // off instead of decompressing everything in front of it again.
+    let resumed = match (!whole).then(|| cursor.lock().unwrap().take()).flatten() {
+        Some(resumed) if resumed.position <= offset as u64 => Some(resumed),
+        Some(behind) => {
+            *archive.lock().unwrap() = Some(behind.decoder.into_inner());
+            None
+        }
+        None => None,
+    };
+    let (mut result, mut decoder, linear) = match resumed {
+        Some(Cursor {
+            mut decoder,
+            position,
+        }) => {
+            let result = continue_entry(
+                &mut decoder,
+                position,
+                file_size,
+                read_offset,
+                read_size,
+                scratch,
+            );
+            (result, decoder, true)
+        }
+        None => {
+            let file = match checkout(&archive, &krate_path, cache) {
+                Ok(file) => file,
+                Err(e) => return reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+            };
+            shared
+                .decompressions_started
+                .fetch_add(1, Ordering::Relaxed);
+            let mut archive = match Decoder::new(file) {
+                Ok(decoder) => tar::Archive::new(decoder),
+                Err(e) => {
+                    warn!("[read] ino 0x{ino:016x} => EIO, {e}");
+                    return reply.error(libc::EIO);
+                }
+            };
+            let result = read_entry(
+                &mut archive,
+                entry_index,
+                file_size,
+                read_offset,
+                read_size,
+                scratch,
+            );
+            let linear = matches!(result, Ok((_, true)));
+            (result.map(|(data, _)| data), archive.into_inner(), linear)
+        }
+    };
+    if shared.verify_reads {
+        if let Ok(data) = &result {
+            if let Err(problem) =
+                verify(&mut decoder, data.len(), file_size, read_offset, read_size)
+            {
+                error!(
+                    "[read] ino 0x{ino:016x} => EIO, {}: {problem}",
+                    krate_path.to_string_lossy()
+                );
+                shared.corrupt.lock().unwrap().insert(krate_path.clone());
+                result = Err(std::io::Error::from_raw_os_error(libc::EIO));
+            }
+        }
+    }
+    let position = match &result {
+        Ok(data) if !whole && linear => Some(read_offset as u64 + data.len() as u64),
+        _ => None,
+    };
+    match position.filter(|&position| position < file_size) {
+        Some(position) => *cursor.lock().unwrap() = Some(Cursor { decoder, position }),
+        None => *archive.lock().unwrap() = Some(decoder.into_inner()),
+    }
+    match result {
+        Ok(data) if whole => {
+            let data = Arc::new(data);
+            shared
+                .contents
+                .insert((krate_path, krate, entry_index), data.clone());
+            reply_data(shared, slice(&data, offset, size), &served, reply)
+        }
+        Ok(data) => reply_data(shared, &data, &served, reply),
+        Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+    }
+}
+
+/// The archive the handle was opened on, or one of `krate_path` from
+/// `cache` if it has none.
+fn checkout(
+    archive: &Mutex<Option<File>>,
+    krate_path: &Path,
+    cache: &ArchiveCache,
+) -> std::io::Result<File> {
+    match archive.lock().unwrap().take() {
+        Some(mut file) => {
+            file.seek(SeekFrom::Start(0))?;
+            Ok(file)
+        }
+        None => cache.checkout(krate_path),
+    }
+}
+
+/// The spilled copy of an entry, decompressing it to the spill directory
+/// first if there is none for the archive as it is now.
+fn serve_spilled(
+    spill: &SpillCache,
+    key: EntryKey,
+    file_size: u64,
+    archive: &Mutex<Option<File>>,
+    shared: &Shared,
+) -> std::io::Result<Arc<File>> {
+    let krate_path = &key.0;
+    let file = checkout(archive, krate_path, &shared.cache)?;
+    let archive_mtime = match file.metadata().and_then(|metadata| metadata.modified()) {
+        Ok(mtime) => mtime,
+        Err(e) => {
+            *archive.lock().unwrap() = Some(file);
+            return Err(e);
+        }
+    };
+    if let Some(spilled) = spill.get(&key, archive_mtime) {
+        *archive.lock().unwrap() = Some(file);
+        return Ok(spilled);
+    }
+    shared
+        .decompressions_started
+        .fetch_add(1, Ordering::Relaxed);
+    let mut reader = tar::Archive::new(Decoder::new(file)?);
+    let (mut file, path) = spill.create()?;
+    let written = reader.entries().and_then(|mut entries| {
+        entries
+            .nth(key.2)
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?
+            .and_then(|entry| std::io::copy(&mut entry.take(file_size), &mut file))
+    });
+    let mut decoder = reader.into_inner();
+    let result = match written {
+        Ok(written) if shared.verify_reads => {
+            verify(&mut decoder, written as usize, file_size, 0, file_size).map_err(|problem| {
+                error!("{}: {problem}", krate_path.to_string_lossy());
+                shared.corrupt.lock().unwrap().insert(krate_path.clone());
+                std::io::Error::from_raw_os_error(libc::EIO)
+            })
+        }
+        written => written.map(drop),
+    };
+    *archive.lock().unwrap() = Some(decoder.into_inner());
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    let file = Arc::new(file);
+    spill.insert(key, file.clone(), path, archive_mtime);
+    Ok(file)
+}
+
+pub fn read_at(file: &File, offset: i64, size: u32) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0; size as usize];
+    let mut filled = 0;
+    while filled < data.len() {
+        match file.read_at(&mut data[filled..], offset as u64 + filled as u64) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    data.truncate(filled);
+    Ok(data)
+}
+
+fn reply_data(shared: &Shared, data: &[u8], served: &AtomicU64, reply: fuser::ReplyData) {
+    shared
+        .bytes_served
+        .fetch_add(data.len() as u64, Ordering::Relaxed);
+    served.fetch_add(data.len() as u64, Ordering::Relaxed);
+    reply.data(data)
+}
+
+pub fn slice(data: &[u8], offset: i64, size: u32) -> &[u8] {
+    let start = (offset as usize).min(data.len());
+    let end = start.saturating_add(size as usize).min(data.len());
+    &data[start..end]
+}
+
+/// Checks a successful read against the tar header size. A read that
+/// reaches the end of the entry also drains the rest of the stream, which
+/// makes a gzip decoder check the CRC in the trailer.
+fn verify(
+    decoder: &mut Decoder,
+    read: usize,
+    file_size: u64,
+    offset: i64,
+    size: u64,
+) -> Result<(), String> {
+    let expected = size.min(file_size.saturating_sub(offset as u64));
+    if (read as u64) < expected {
+        return Err(format!(
+            "entry is shorter than its header size of {file_size} bytes"
+        ));
+    }
+    if offset as u64 + read as u64 == file_size {
+        std::io::copy(decoder, &mut std::io::sink()).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// The requested range and whether the entry's data is stored as is, so
+/// that the decoder is left `offset` plus the length read into it.
+fn read_entry(
+    archive: &mut tar::Archive<Decoder>,
+    entry_index: usize,
+    file_size: u64,
+    offset: i64,
+    size: u64,
+    scratch: &mut [u8],
+) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut entry = archive
+        .entries()?
+        .nth(entry_index)
+        .ok_or(std::io::ErrorKind::UnexpectedEof)??;
+    let linear = !entry.header().entry_type().is_gnu_sparse();
+    if !discard(&mut entry, offset as u64, scratch)? {
+        return Ok((vec![], linear));
+    }
+    let size = size.min(file_size.saturating_sub(offset as u64));
+    let mut data = Vec::with_capacity(size as usize);
+    entry.take(size).read_to_end(&mut data)?;
+    Ok((data, linear))
+}
+
+/// Like [`read_entry`], from a decoder `position` bytes into the entry's
+/// data.
+fn continue_entry(
+    decoder: &mut Decoder,
+    position: u64,
+    file_size: u64,
+    offset: i64,
+    size: u64,
+    scratch: &mut [u8],
+) -> std::io::Result<Vec<u8>> {
+    let mut entry = decoder.take(file_size - position);
+    if !discard(&mut entry, offset as u64 - position, scratch)? {
+        return Ok(vec![]);
+    }
+    let size = size.min(file_size.saturating_sub(offset as u64));
+    let mut data = Vec::with_capacity(size as usize);
+    entry.take(size).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Discards exactly `count` bytes; `false` if the reader ends first and has
+/// nothing left to read.
+fn discard<R: Read>(reader: &mut R, count: u64, scratch: &mut [u8]) -> std::io::Result<bool> {
+    let mut remaining = count;
+    while remaining > 0 {
+        let chunk = remaining.min(scratch.len() as u64) as usize;
+        match reader.read(&mut scratch[..chunk]) {
+            Ok(0) => return Ok(false),
+            Ok(read) => remaining -= read as u64,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    use super::*;
+    use crate::testing::{self, Fixture, TempDir};
+
+    /// Counts the allocations of each thread, so that tests running
+    /// alongside do not add to them.
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn count() {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count();
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    #[test]
+    fn random_reads_allocate_the_same_wherever_they_are() {
+        let dir = TempDir::new("cache");
+        let size = 4 << 20;
+        let archive = Fixture::new("big-0.1.0")
+            .file("Cargo.toml", b"toml")
+            .file("big.bin", &testing::noise(size, 1))
+            .write_to(dir.path());
+        let mut scratch = vec![0; SCRATCH_SIZE];
+        // What a 4 KiB read allocates when it decompresses from the start.
+        let mut read = |offset: u64| {
+            let before = ALLOCATIONS.with(Cell::get);
+            let mut archive =
+                tar::Archive::new(Decoder::new(File::open(&archive).unwrap()).unwrap());
+            let (data, _) = read_entry(
+                &mut archive,
+                1,
+                size as u64,
+                offset as i64,
+                4096,
+                &mut scratch,
+            )
+            .unwrap();
+            assert_eq!(data.len(), 4096.min(size - offset as usize));
+            drop(archive);
+            ALLOCATIONS.with(Cell::get) - before
+        };
+        let first = read(0);
+        // Bytes skipped on the way are discarded through `scratch`.
+        let offsets = testing::noise(32, 2)
+            .chunks(4)
+            .map(|bytes| u64::from(u32::from_le_bytes(bytes.try_into().unwrap())) % size as u64)
+            .collect::<Vec<_>>();
+        for offset in offsets.into_iter().chain([size as u64 - 100]) {
+            assert_eq!(read(offset), first, "at {offset}");
+        }
+        // The decoder's state and buffers and the data read, rather than
+        // anything per block skipped.
+        assert!(first < 16, "{first} allocations");
+    }
+}
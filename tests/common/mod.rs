@@ -0,0 +1,220 @@
+//! Mounts shared by the integration tests, on top of the archives
+//! [`fuse_crates::testing`] builds: the filesystem mounted either
+//! in-process or through the `fuse-crates` binary, and a comparison of the
+//! mount with what `tar` extracts from the same archives.
+
+// Each test binary uses its own part of this.
+#![allow(dead_code)]
+
+use std::{
+    fs::File,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+pub use fuse_crates::testing::*;
+
+/// A crate with names that differ only in case, `Makefile` and `makefile`,
+/// for `--icase`.
+pub fn makefiles(dir: &Path) -> PathBuf {
+    Fixture::new("make-0.1.0")
+        .file("Cargo.toml", b"toml\n")
+        .file("Makefile", b"upper\n")
+        .file("makefile", b"lower\n")
+        .write_to(dir)
+}
+
+/// Whether this machine can mount FUSE filesystems at all. Tests return
+/// early when it cannot, saying so.
+pub fn fuse_available() -> bool {
+    let available = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .is_ok();
+    if !available {
+        eprintln!("skipping: /dev/fuse is not available");
+    }
+    available
+}
+
+enum Session {
+    Library(fuser::BackgroundSession),
+    Binary(Child),
+}
+
+/// A mounted cache directory, unmounted on drop, even while a failed
+/// assertion unwinds.
+pub struct Mount {
+    session: Option<Session>,
+    mountpoint: TempDir,
+}
+
+impl Mount {
+    /// Mounts `fs`, a [`fuse_crates::CargoCacheFs`] or a wrapper around
+    /// one, in this process.
+    pub fn library(fs: impl fuser::Filesystem + Send + 'static) -> Self {
+        let mountpoint = TempDir::new("mnt");
+        let session = fuser::spawn_mount2(
+            fs,
+            mountpoint.path(),
+            &[
+                fuser::MountOption::RO,
+                fuser::MountOption::FSName("fuse-crates-test".to_owned()),
+            ],
+        )
+        .expect("mounting in-process");
+        let mount = Self {
+            session: Some(Session::Library(session)),
+            mountpoint,
+        };
+        mount.wait();
+        mount
+    }
+
+    /// Mounts `cache` by running the `fuse-crates` binary with `args`.
+    pub fn binary(cache: &Path, args: &[&str]) -> Self {
+        let mountpoint = TempDir::new("mnt");
+        let child = Command::new(env!("CARGO_BIN_EXE_fuse-crates"))
+            .args(args)
+            .arg(cache)
+            .arg(mountpoint.path())
+            .stdin(Stdio::null())
+            .spawn()
+            .expect("running fuse-crates");
+        let mount = Self {
+            session: Some(Session::Binary(child)),
+            mountpoint,
+        };
+        mount.wait();
+        mount
+    }
+
+    pub fn path(&self) -> &Path {
+        self.mountpoint.path()
+    }
+
+    /// Until the mountpoint is on another device than its parent.
+    fn wait(&self) {
+        let parent = std::fs::metadata(self.path().parent().unwrap())
+            .unwrap()
+            .dev();
+        let started = Instant::now();
+        while std::fs::metadata(self.path())
+            .map(|metadata| metadata.dev())
+            .ok()
+            == Some(parent)
+        {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "{} was not mounted",
+                self.path().display()
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        match self.session.take() {
+            // Unmounts.
+            Some(Session::Library(session)) => drop(session),
+            Some(Session::Binary(mut child)) => {
+                // The binary unmounts on SIGTERM.
+                unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+                let started = Instant::now();
+                while child.try_wait().ok().flatten().is_none()
+                    && started.elapsed() < Duration::from_secs(10)
+                {
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            None => (),
+        }
+        // Whatever is left, so that the directory can be removed.
+        let path = std::ffi::CString::new(self.path().as_os_str().as_bytes()).unwrap();
+        unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) };
+    }
+}
+
+/// Extracts every archive in `cache` with `tar`, under a new directory.
+pub fn extract(cache: &Path) -> TempDir {
+    let dir = TempDir::new("extracted");
+    for archive in std::fs::read_dir(cache).unwrap() {
+        let archive = archive.unwrap().path();
+        let gz = flate2::read::GzDecoder::new(File::open(&archive).unwrap());
+        tar::Archive::new(gz).unpack(dir.path()).unwrap();
+    }
+    dir
+}
+
+/// Checks that `mounted` holds the same names, file types, sizes, file
+/// mtimes, execute bits, contents and link targets as `extracted`, and
+/// that reads at odd offsets return the same bytes.
+pub fn assert_same_tree(mounted: &Path, extracted: &Path) {
+    let names = |dir: &Path| {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let mounted_names = names(mounted);
+    assert_eq!(mounted_names, names(extracted), "listing of {mounted:?}");
+    for name in mounted_names {
+        let (mounted, extracted) = (mounted.join(&name), extracted.join(&name));
+        let metadata = std::fs::symlink_metadata(&mounted).unwrap();
+        let expected = std::fs::symlink_metadata(&extracted).unwrap();
+        assert_eq!(metadata.file_type(), expected.file_type(), "{mounted:?}");
+        if metadata.is_dir() {
+            assert_same_tree(&mounted, &extracted);
+        } else if metadata.is_symlink() {
+            assert_eq!(
+                std::fs::read_link(&mounted).unwrap(),
+                std::fs::read_link(&extracted).unwrap(),
+                "{mounted:?}"
+            );
+        } else {
+            assert_eq!(metadata.len(), expected.len(), "size of {mounted:?}");
+            assert_eq!(metadata.mtime(), expected.mtime(), "mtime of {mounted:?}");
+            assert_eq!(
+                metadata.permissions().mode() & 0o111 != 0,
+                expected.permissions().mode() & 0o111 != 0,
+                "execute bits of {mounted:?}"
+            );
+            let contents = std::fs::read(&extracted).unwrap();
+            assert!(
+                std::fs::read(&mounted).unwrap() == contents,
+                "contents of {mounted:?}"
+            );
+            assert_same_preads(&mounted, &contents);
+        }
+    }
+}
+
+fn assert_same_preads(mounted: &Path, contents: &[u8]) {
+    let file = File::open(mounted).unwrap();
+    let offsets = noise(64, contents.len() as u64);
+    for pair in offsets.chunks(2) {
+        // Anywhere in the file, and from just before its end to past it.
+        let offset = contents.len() * usize::from(pair[0]) / 255;
+        let len = 1 + usize::from(pair[1]) * 97;
+        let mut buf = vec![0; len];
+        let read = file.read_at(&mut buf, offset as u64).unwrap();
+        let end = contents.len().min(offset + len);
+        assert_eq!(
+            &buf[..read],
+            &contents[offset..end],
+            "{len} bytes at {offset} of {mounted:?}"
+        );
+    }
+}
@@ -0,0 +1,42 @@
+//! How much populating a registry allocates per inode, against limits
+//! that catch a structure growing by a field or a copy per inode. Run
+//! with `cargo test --release --test memory -- --ignored`; `cargo run
+//! --release --example memstats` breaks the same numbers down.
+
+use fuse_crates::{
+    testing::{registry, CountingAllocator, TempDir},
+    CargoCacheFs,
+};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Bytes still allocated per inode once populated.
+const RESIDENT_PER_INODE: usize = 800;
+/// The most bytes allocated at once per inode while populating.
+const PEAK_PER_INODE: usize = 1000;
+
+#[test]
+#[ignore = "slow, and only meaningful on its own in a release build"]
+fn populating_stays_within_its_memory_per_inode() {
+    let cache = TempDir::new("memory");
+    registry(cache.path(), 2000, 20);
+    let before = CountingAllocator::allocated();
+    CountingAllocator::reset_peak();
+    let fs = CargoCacheFs::populated(cache.path()).unwrap();
+    let resident = CountingAllocator::allocated() - before;
+    let peak = CountingAllocator::peak() - before;
+    let parts = fs.memory_breakdown();
+    let inodes = parts[0].entries;
+    assert!(inodes > 2000 * 20, "{parts:#?}");
+    assert!(
+        resident / inodes <= RESIDENT_PER_INODE,
+        "{} resident bytes per inode: {parts:#?}",
+        resident / inodes
+    );
+    assert!(
+        peak / inodes <= PEAK_PER_INODE,
+        "{} peak bytes per inode: {parts:#?}",
+        peak / inodes
+    );
+}
@@ -0,0 +1,1623 @@
+mod common;
+
+use std::{
+    ffi::OsStr,
+    io::{ErrorKind, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, MetadataExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use common::{
+    assert_same_tree, assert_tree, extract, fuse_available, makefiles, noise, write_gzip, Fixture,
+    Mount, TempDir,
+};
+
+/// A crate covering nested and empty directories, empty and large files,
+/// an executable, a symlink and names that need care.
+fn fixture(dir: &Path) -> PathBuf {
+    let long_name = format!("{}.rs", "long".repeat(40));
+    Fixture::new("fixture-0.1.0")
+        .file("Cargo.toml", b"[package]\nname = \"fixture\"\n")
+        .dir("src")
+        .file("src/lib.rs", b"pub mod nested;\n")
+        .file("src/nested/deeper/mod.rs", b"// three levels down\n")
+        .file("empty.txt", b"")
+        .dir("empty-dir")
+        .file("big.bin", &noise(3 << 20 | 123, 1))
+        .file_with_mode("bin/run.sh", b"#!/bin/sh\necho run\n", 0o755)
+        .symlink("lib-link.rs", "src/lib.rs")
+        .file("with space.txt", b"space\n")
+        .file("#hash%-dash.txt", b"punctuation\n")
+        .file("ünïcödé.rs", b"// utf-8\n")
+        .file(&format!("long/{long_name}"), b"// a GNU long name\n")
+        .write_to(dir)
+}
+
+fn other(dir: &Path) -> PathBuf {
+    Fixture::new("other-2.0.0-rc.1")
+        .file("Cargo.toml", b"[package]\nname = \"other\"\n")
+        .file("data/medium.bin", &noise(200_000, 2))
+        .write_to(dir)
+}
+
+fn fixtures() -> TempDir {
+    let cache = TempDir::new("cache");
+    fixture(cache.path());
+    other(cache.path());
+    cache
+}
+
+#[test]
+fn library_mount_matches_extraction() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let extracted = extract(cache.path());
+    let fs = fuse_crates::CargoCacheFs::new(cache.path()).unwrap();
+    let mount = Mount::library(fs);
+    assert_same_tree(mount.path(), extracted.path());
+}
+
+#[test]
+fn library_eager_mount_matches_extraction() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let extracted = extract(cache.path());
+    let fs = fuse_crates::CargoCacheFs::builder(cache.path())
+        .eager(true)
+        .build()
+        .unwrap();
+    let mount = Mount::library(fs);
+    assert_same_tree(mount.path(), extracted.path());
+}
+
+#[test]
+fn binary_mount_matches_extraction() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let extracted = extract(cache.path());
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    assert_same_tree(mount.path(), extracted.path());
+}
+
+#[test]
+fn binary_eager_mount_matches_extraction() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let extracted = extract(cache.path());
+    let mount = Mount::binary(cache.path(), &["--no-src-dir", "--eager"]);
+    assert_same_tree(mount.path(), extracted.path());
+}
+
+#[test]
+fn mount_and_extraction_match_the_fixture_description() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    let fixture = Fixture::new("described-0.1.0")
+        .file("Cargo.toml", b"first\n")
+        .duplicate("Cargo.toml", b"second\n")
+        .dir("empty")
+        .file_with_mode("bin/run.sh", b"#!/bin/sh\n", 0o755)
+        .file_at("old.txt", b"old\n", 1_000_000_000)
+        .symlink("link", "old.txt")
+        .hard_link("same.txt", "old.txt");
+    let expected = fixture.expected().clone();
+    fixture.write_to(cache.path());
+    let extracted = extract(cache.path());
+    assert_tree(extracted.path(), &expected);
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    assert_tree(mount.path(), &expected);
+}
+
+#[test]
+fn hostile_entries_stay_confined() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    let regular = tar::EntryType::Regular;
+    let directory = tar::EntryType::Directory;
+    Fixture::new("hostile-0.1.0")
+        .raw(b"hostile-0.1.0/Cargo.toml", regular, b"first\n")
+        .raw(b"hostile-0.1.0/../../escaped", regular, b"escaped\n")
+        .raw(b"/absolute", regular, b"absolute\n")
+        .raw(b"./hostile-0.1.0/./src/lib.rs", regular, b"dotted\n")
+        .raw(b"./", directory, b"")
+        .raw(b"hostile-0.1.0/Cargo.toml", regular, b"second\n")
+        .raw(b"hostile-0.1.0/file", regular, b"a file\n")
+        .raw(b"hostile-0.1.0/file/", directory, b"")
+        .raw(b"hostile-0.1.0/file/under", regular, b"under a file\n")
+        .write_to(cache.path());
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    let list = |dir: &Path| {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let krate = mount.path().join("hostile-0.1.0");
+    assert_eq!(list(mount.path()), ["hostile-0.1.0"]);
+    assert_eq!(list(&krate), ["Cargo.toml", "file", "src"]);
+    // The later of two entries wins, as when extracting.
+    assert_eq!(
+        std::fs::read(krate.join("Cargo.toml")).unwrap(),
+        b"second\n"
+    );
+    assert_eq!(
+        std::fs::read(krate.join("src/lib.rs")).unwrap(),
+        b"dotted\n"
+    );
+    // A directory replaces a file, and keeps what is below it.
+    assert_eq!(list(&krate.join("file")), ["under"]);
+    assert_eq!(
+        std::fs::read(krate.join("file/under")).unwrap(),
+        b"under a file\n"
+    );
+    assert!(mount.path().join("escaped").symlink_metadata().is_err());
+    assert!(mount.path().join("absolute").symlink_metadata().is_err());
+}
+
+#[test]
+fn icase_prefers_exact_then_first_match() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    makefiles(cache.path());
+    for icase in [true, false] {
+        let args: &[&str] = match icase {
+            true => &["--no-src-dir", "--icase"],
+            false => &["--no-src-dir"],
+        };
+        let mount = Mount::binary(cache.path(), args);
+        let krate = mount.path().join("make-0.1.0");
+        let mut listed = std::fs::read_dir(&krate)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(
+            listed,
+            ["Cargo.toml", "Makefile", "makefile"],
+            "icase {icase}"
+        );
+        let read = |name| std::fs::read(krate.join(name)).map_err(|e| e.kind());
+        let ino = |name| std::fs::metadata(krate.join(name)).unwrap().ino();
+        assert_eq!(read("Makefile"), Ok(b"upper\n".to_vec()));
+        assert_eq!(read("makefile"), Ok(b"lower\n".to_vec()));
+        assert_ne!(ino("Makefile"), ino("makefile"));
+        if !icase {
+            for name in ["MAKEFILE", "mAKEFILE", "cargo.TOML"] {
+                assert_eq!(read(name), Err(ErrorKind::NotFound), "{name}");
+            }
+            continue;
+        }
+        // Neither is an exact match, so the first by name.
+        assert_eq!(read("MAKEFILE"), Ok(b"upper\n".to_vec()));
+        assert_eq!(read("mAKEFILE"), Ok(b"upper\n".to_vec()));
+        assert_eq!(ino("mAKEFILE"), ino("Makefile"));
+        assert_eq!(read("cargo.TOML"), Ok(b"toml\n".to_vec()));
+        assert_eq!(ino("cargo.TOML"), ino("Cargo.toml"));
+    }
+}
+
+#[test]
+fn library_archive_mount_matches_extraction() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    let archive = fixture(cache.path());
+    let extracted = extract(cache.path());
+    let fs = fuse_crates::CargoCacheFs::new(archive).unwrap();
+    let mount = Mount::library(fs);
+    assert_same_tree(mount.path(), extracted.path());
+}
+
+#[test]
+fn binary_archive_mount_matches_extraction() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let single = TempDir::new("single");
+    std::fs::copy(
+        cache.path().join("other-2.0.0-rc.1.crate"),
+        single.path().join("other-2.0.0-rc.1.crate"),
+    )
+    .unwrap();
+    let extracted = extract(single.path());
+    let archive = cache.path().join("other-2.0.0-rc.1.crate");
+    let mount = Mount::binary(&archive, &["--no-src-dir"]);
+    assert_same_tree(mount.path(), extracted.path());
+}
+
+#[test]
+fn several_sources_get_a_directory_each() {
+    if !fuse_available() {
+        return;
+    }
+    // Two caches named alike, and an archive.
+    let (first, second, lone) = (
+        TempDir::new("first"),
+        TempDir::new("second"),
+        TempDir::new("lone"),
+    );
+    let (first_cache, second_cache) = (first.path().join("cache"), second.path().join("cache"));
+    std::fs::create_dir(&first_cache).unwrap();
+    std::fs::create_dir(&second_cache).unwrap();
+    fixture(&first_cache);
+    other(&first_cache);
+    other(&second_cache);
+    let archive = Fixture::new("lone-0.1.0")
+        .file("Cargo.toml", b"[package]\nname = \"lone\"\n")
+        .write_to(lone.path());
+    let mount = Mount::binary(
+        &first_cache,
+        &[
+            "--no-src-dir",
+            "--shard-root",
+            "1",
+            archive.to_str().unwrap(),
+            second_cache.to_str().unwrap(),
+        ],
+    );
+    let mut names = std::fs::read_dir(mount.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["cache", "cache-2", "lone-0.1.0"]);
+    // Sources are taken in order, the first cache last. Shards are under
+    // the directory of each source, and crates are also found there by
+    // name.
+    let under = |name: &str| mount.path().join(name);
+    assert!(under("cache-2/fi/fixture-0.1.0").is_dir());
+    assert!(under("cache-2/fixture-0.1.0").is_dir());
+    assert!(!under("fixture-0.1.0").exists());
+    let extracted = extract(&second_cache);
+    assert_same_tree(&under("cache/ot"), extracted.path());
+    let extracted = extract(lone.path());
+    assert_same_tree(&under("lone-0.1.0/lo"), extracted.path());
+}
+
+#[test]
+fn bad_sources_are_refused_before_mounting() {
+    let cache = fixtures();
+    let mountpoint = TempDir::new("mnt");
+    let not_an_archive = cache.path().join("notes.txt");
+    std::fs::write(&not_an_archive, b"notes\n").unwrap();
+    let archive = cache.path().join("other-2.0.0-rc.1.crate");
+    let refusals = [
+        (vec![cache.path().join("missing.crate")], "does not exist"),
+        (vec![not_an_archive], "is neither a directory nor"),
+        (
+            vec![cache.path().to_path_buf(), archive],
+            "is already mounted through",
+        ),
+    ];
+    for (sources, expected) in refusals {
+        let output = Command::new(env!("CARGO_BIN_EXE_fuse-crates"))
+            .args(&sources)
+            .arg(mountpoint.path())
+            .env("RUST_BACKTRACE", "0")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{sources:?} was mounted");
+        assert!(stderr.contains(expected), "{sources:?}: {stderr}");
+    }
+}
+
+#[test]
+fn self_test_skips_the_crates_filtered_out() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    for stem in ["a-1.0.0", "a-2.0.0", "ab-1.0.0", "b-1.0.0", "c-1.0.0"] {
+        Fixture::new(stem)
+            .file("Cargo.toml", b"[package]\n")
+            .file("src/lib.rs", &noise(10_000, 3))
+            .write_to(cache.path());
+    }
+    let mountpoint = TempDir::new("mnt");
+    let output = Command::new(env!("CARGO_BIN_EXE_fuse-crates"))
+        .args(["--no-src-dir", "--include", "a-*", "--self-test-exit"])
+        .arg(cache.path())
+        .arg(mountpoint.path())
+        .env("RUST_LOG", "info")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{stderr}");
+    assert!(stderr.contains("2 crates listed"), "{stderr}");
+}
+
+#[test]
+fn help_is_printed_and_unknown_flags_point_at_it() {
+    let run = |arg: &str| {
+        Command::new(env!("CARGO_BIN_EXE_fuse-crates"))
+            .arg(arg)
+            .env("RUST_BACKTRACE", "0")
+            .output()
+            .unwrap()
+    };
+    let help = run("--help");
+    assert!(help.status.success());
+    let usage = String::from_utf8(help.stdout).unwrap();
+    assert!(usage.starts_with("Usage: fuse-crates [OPTIONS]"), "{usage}");
+    assert!(usage.contains("\n  --spill-dir <dir>  "), "{usage}");
+    assert_eq!(run("-h").stdout, usage.as_bytes());
+    let unknown = run("--spill");
+    assert!(!unknown.status.success());
+    let stderr = String::from_utf8_lossy(&unknown.stderr);
+    assert!(
+        stderr.contains("Unknown argument: --spill (see --help)"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn strict_lists_violations_instead_of_mounting() {
+    let cache = fixtures();
+    Fixture::new("hostile-0.1.0")
+        .raw(b"hostile-0.1.0/../escaped", tar::EntryType::Regular, b"")
+        .file("Cargo.toml", b"first\n")
+        .file("Cargo.toml", b"second\n")
+        .write_to(cache.path());
+    let mountpoint = TempDir::new("mnt");
+    let output = Command::new(env!("CARGO_BIN_EXE_fuse-crates"))
+        .args(["--strict", "--no-src-dir"])
+        .arg(cache.path())
+        .arg(mountpoint.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2, "{stdout}");
+    assert!(
+        lines[0].starts_with(r#"{"violation":"path-traversal","crate":"hostile-0.1.0","#),
+        "{stdout}"
+    );
+    assert!(
+        lines[1].starts_with(r#"{"violation":"duplicate-entry","crate":"hostile-0.1.0","#),
+        "{stdout}"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("2 violations found"));
+    assert_eq!(std::fs::read_dir(mountpoint.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn verify_reads_fails_a_crate_whose_data_was_altered() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    let data = noise(100_000, 3);
+    let tar = Fixture::new("altered-0.1.0")
+        .file("Cargo.toml", b"[package]\n")
+        .file("data.bin", &data)
+        .into_tar();
+    // Stored uncompressed, so that a flipped byte still decompresses and
+    // only the CRC catches it.
+    let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::none());
+    gzip.write_all(&tar).unwrap();
+    let mut gzip = gzip.finish().unwrap();
+    let window = &data[50_000..50_016];
+    let at = gzip.windows(16).position(|bytes| bytes == window).unwrap();
+    gzip[at] ^= 0xff;
+    std::fs::write(cache.path().join("altered-0.1.0.crate"), &gzip).unwrap();
+    let spill = TempDir::new("spill");
+    let spill_dir = spill.path().to_str().unwrap();
+    // Through the content cache, a cursor and the spill directory.
+    let paths: [&[&str]; 3] = [
+        &[],
+        &["--content-cache-size", "0"],
+        &["--content-cache-size", "0", "--spill-dir", spill_dir],
+    ];
+    for path in paths {
+        let mount = Mount::binary(cache.path(), &[&["--no-src-dir"], path].concat());
+        let read = std::fs::read(mount.path().join("altered-0.1.0/data.bin")).unwrap();
+        assert_eq!(read.len(), data.len());
+        assert_ne!(read, data);
+        drop(mount);
+        let args = [&["--no-src-dir", "--verify-reads"], path].concat();
+        let mount = Mount::binary(cache.path(), &args);
+        let krate = mount.path().join("altered-0.1.0");
+        let error = std::fs::read(krate.join("data.bin")).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EIO), "{args:?}");
+        // The rest of the crate is not trusted either.
+        let error = std::fs::read(krate.join("Cargo.toml")).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EIO), "{args:?}");
+    }
+}
+
+#[test]
+fn sanitized_names_keep_symlinks_working() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    Fixture::new("colons-0.1.0")
+        .file("src/a:b.rs", b"// colon\n")
+        .symlink("link.rs", "src/a:b.rs")
+        .symlink("outside", "/tmp/a:b")
+        .write_to(cache.path());
+    let mount = Mount::binary(
+        cache.path(),
+        &["--no-src-dir", "--name-mapping", "sanitize"],
+    );
+    let krate = mount.path().join("colons-0.1.0");
+    assert!(krate.join("src/a%3Ab.rs").exists());
+    assert_eq!(
+        std::fs::read_link(krate.join("link.rs")).unwrap(),
+        Path::new("src/a%3Ab.rs")
+    );
+    assert_eq!(std::fs::read(krate.join("link.rs")).unwrap(), b"// colon\n");
+    // Out of the mount, where names are not mapped.
+    assert_eq!(
+        std::fs::read_link(krate.join("outside")).unwrap(),
+        Path::new("/tmp/a:b")
+    );
+}
+
+#[test]
+fn file_and_dir_modes_only_take_permissions_away() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    Fixture::new("modes-0.1.0")
+        .file("Cargo.toml", b"toml\n")
+        .file_with_mode("run.sh", b"#!/bin/sh\n", 0o755)
+        .dir("empty")
+        .file("src/lib.rs", b"// lib\n")
+        .write_to(cache.path());
+    // `--file-mode`, `--dir-mode`, then what `stat -c %a` says of a file,
+    // an executable and directories.
+    let cases: [(&[&str], [&str; 5]); 4] = [
+        (&[], ["444", "555", "555", "555", "555"]),
+        (
+            &["--file-mode", "440", "--dir-mode", "550"],
+            ["440", "440", "550", "550", "550"],
+        ),
+        (
+            &["--file-mode", "0o550", "--dir-mode", "0o500"],
+            ["440", "550", "500", "500", "500"],
+        ),
+        (
+            &["--file-mode", "444", "--dir-mode", "111"],
+            ["444", "444", "111", "111", "111"],
+        ),
+    ];
+    for (args, expected) in cases {
+        let args = [&["--no-src-dir"], args].concat();
+        let mount = Mount::binary(cache.path(), &args);
+        let krate = mount.path().join("modes-0.1.0");
+        let paths = ["Cargo.toml", "run.sh", "empty", "src", ""].map(|path| krate.join(path));
+        let output = Command::new("stat")
+            .args(["-c", "%a"])
+            .args(&paths)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        let modes = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(modes.lines().collect::<Vec<_>>(), expected, "{args:?}");
+    }
+}
+
+#[test]
+fn reads_keep_to_max_read_bps() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let mount = Mount::binary(
+        cache.path(),
+        &[
+            "--no-src-dir",
+            "--max-read-bps",
+            "100000",
+            "--max-read-bps-per-handle",
+            "1000000",
+        ],
+    );
+    // A second's worth comes out of the full bucket right away, the other
+    // 100 kB at the rate.
+    let started = Instant::now();
+    let data = std::fs::read(mount.path().join("other-2.0.0-rc.1/data/medium.bin")).unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(data.len(), 200_000);
+    assert!(
+        (Duration::from_millis(900)..Duration::from_secs(3)).contains(&elapsed),
+        "{elapsed:?}"
+    );
+    let stats = std::fs::read_to_string(mount.path().join(".fuse-crates/stats")).unwrap();
+    let value = |key: &str| {
+        stats
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no {key} in {stats}"))
+            .parse::<f64>()
+            .unwrap()
+    };
+    assert!(value("throttle_waited_seconds") >= 0.9, "{stats}");
+    assert!(value("throttle_tokens") <= 100_000.0, "{stats}");
+    assert_eq!(value("handle_throttle_waited_seconds"), 0.0, "{stats}");
+}
+
+/// The counter `name` in the `stats` of the filesystem mounted at `mount`.
+fn stat(mount: &Path, name: &str) -> u64 {
+    let stats = std::fs::read_to_string(mount.join(".fuse-crates/stats")).unwrap();
+    stats
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {name} in {stats}"))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn archives_stay_open_between_reads() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    let krate = mount.path().join("other-2.0.0-rc.1");
+    let opened = || stat(mount.path(), "archives_opened");
+    assert_eq!(opened(), 0);
+    std::fs::read(krate.join("Cargo.toml")).unwrap();
+    assert_eq!(opened(), 1);
+    // Well within the idle timeout, one file after the other.
+    std::fs::read(krate.join("data/medium.bin")).unwrap();
+    std::fs::read(mount.path().join("fixture-0.1.0/Cargo.toml")).unwrap();
+    std::fs::read(mount.path().join("fixture-0.1.0/src/lib.rs")).unwrap();
+    assert_eq!(opened(), 2);
+}
+
+/// Reads each `(offset, size)` in turn through one handle opened with
+/// `O_DIRECT`, so that the filesystem gets them as given rather than as
+/// whole pages the kernel clamps to the file size.
+fn read_direct(path: &Path, reads: &[(u64, usize)]) -> Vec<Vec<u8>> {
+    let file = std::fs::File::options()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+        .unwrap();
+    reads
+        .iter()
+        .map(|&(offset, size)| {
+            let mut data = vec![0; size];
+            let read = file.read_at(&mut data, offset).unwrap();
+            data.truncate(read);
+            data
+        })
+        .collect()
+}
+
+#[test]
+fn extracted_sources_are_read_from_disk() {
+    if !fuse_available() {
+        return;
+    }
+    let registry = TempDir::new("registry");
+    let cache = registry.path().join("cache/index-0123");
+    let src = registry.path().join("src/index-0123");
+    std::fs::create_dir_all(&cache).unwrap();
+    for stem in ["disk-1.0.0", "cached-1.0.0", "stale-1.0.0"] {
+        Fixture::new(stem)
+            .file("Cargo.toml", b"[package]\n")
+            .file("src/lib.rs", b"// from the archive\n")
+            .write_to(&cache);
+    }
+    // As long as the sizes match, what is on disk is what is read.
+    for (stem, lib) in [
+        ("disk-1.0.0", "// from the disk!!!\n"),
+        ("stale-1.0.0", "// edited\n"),
+    ] {
+        std::fs::create_dir_all(src.join(stem).join("src")).unwrap();
+        std::fs::write(src.join(stem).join("Cargo.toml"), b"[package]\n").unwrap();
+        std::fs::write(src.join(stem).join("src/lib.rs"), lib).unwrap();
+    }
+    let mount = Mount::binary(&cache, &[]);
+    let read = |path: &str| std::fs::read_to_string(mount.path().join(path)).unwrap();
+    assert_eq!(read("disk-1.0.0/Cargo.toml"), "[package]\n");
+    assert_eq!(read("disk-1.0.0/src/lib.rs"), "// from the disk!!!\n");
+    assert_eq!(stat(mount.path(), "archives_opened"), 0);
+    assert_eq!(stat(mount.path(), "decompressions_started"), 0);
+    assert_eq!(read("cached-1.0.0/src/lib.rs"), "// from the archive\n");
+    assert_eq!(read("stale-1.0.0/src/lib.rs"), "// from the archive\n");
+    assert_eq!(stat(mount.path(), "decompressions_started"), 2);
+}
+
+#[test]
+fn identical_files_across_versions_are_cached_once() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    let shared = noise(100_000, 5);
+    for stem in ["serde-1.0.190", "serde-1.0.203"] {
+        Fixture::new(stem)
+            .file("Cargo.toml", stem.as_bytes())
+            .file("src/de.rs", &shared)
+            .write_to(cache.path());
+    }
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    for stem in ["serde-1.0.190", "serde-1.0.203"] {
+        let path = mount.path().join(stem).join("src/de.rs");
+        assert_eq!(std::fs::read(path).unwrap(), shared);
+    }
+    assert_eq!(stat(mount.path(), "content_cache_files"), 2);
+    assert_eq!(stat(mount.path(), "content_cache_distinct_files"), 1);
+    assert_eq!(stat(mount.path(), "content_cache_bytes"), 100_000);
+    assert_eq!(
+        stat(mount.path(), "content_cache_deduplicated_bytes"),
+        100_000
+    );
+}
+
+#[test]
+fn seeking_finds_data_up_to_the_end_and_a_hole_there() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    let seek = |path: &str, offset: i64, whence| {
+        let file = std::fs::File::open(mount.path().join(path)).unwrap();
+        match unsafe { libc::lseek(file.as_raw_fd(), offset, whence) } {
+            -1 => Err(std::io::Error::last_os_error().raw_os_error().unwrap()),
+            offset => Ok(offset),
+        }
+    };
+    let size = (3 << 20 | 123) as i64;
+    for path in ["fixture-0.1.0/big.bin", "fixture-0.1.0/empty.txt"] {
+        let size = if path.ends_with("big.bin") { size } else { 0 };
+        for offset in [0, 1, size / 2, size - 1]
+            .into_iter()
+            .filter(|&offset| offset >= 0 && offset < size)
+        {
+            assert_eq!(
+                seek(path, offset, libc::SEEK_DATA),
+                Ok(offset),
+                "{path} {offset}"
+            );
+            assert_eq!(
+                seek(path, offset, libc::SEEK_HOLE),
+                Ok(size),
+                "{path} {offset}"
+            );
+        }
+        for offset in [size, size + 1, size + 4096] {
+            assert_eq!(
+                seek(path, offset, libc::SEEK_DATA),
+                Err(libc::ENXIO),
+                "{path}"
+            );
+            assert_eq!(
+                seek(path, offset, libc::SEEK_HOLE),
+                Err(libc::ENXIO),
+                "{path}"
+            );
+        }
+        assert_eq!(seek(path, -1, libc::SEEK_DATA), Err(libc::EINVAL), "{path}");
+        assert_eq!(seek(path, 7, libc::SEEK_SET), Ok(7), "{path}");
+        assert_eq!(seek(path, 0, libc::SEEK_END), Ok(size), "{path}");
+    }
+    let stats = std::fs::read(mount.path().join(".fuse-crates/stats")).unwrap();
+    let end = stats.len() as i64;
+    assert_eq!(seek(".fuse-crates/stats", 0, libc::SEEK_HOLE), Ok(end));
+    assert_eq!(
+        seek(".fuse-crates/stats", end, libc::SEEK_DATA),
+        Err(libc::ENXIO)
+    );
+}
+
+#[test]
+fn overlay_changes_survive_a_remount_and_leave_the_archive_alone() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let archive = cache.path().join("fixture-0.1.0.crate");
+    let original = std::fs::read(&archive).unwrap();
+    let overlay = TempDir::new("overlay");
+    let args = [
+        "--no-src-dir",
+        "--overlay",
+        overlay.path().to_str().unwrap(),
+    ];
+    {
+        let mount = Mount::binary(cache.path(), &args);
+        let krate = mount.path().join("fixture-0.1.0");
+        let lib = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(krate.join("src/lib.rs"))
+            .unwrap();
+        lib.write_all_at(b"tested", 8).unwrap();
+        drop(lib);
+        std::fs::write(krate.join("src/patched.rs"), b"// new\n").unwrap();
+        std::fs::create_dir(krate.join("src/made")).unwrap();
+        std::fs::remove_file(krate.join("empty.txt")).unwrap();
+    }
+    let mount = Mount::binary(cache.path(), &args);
+    let krate = mount.path().join("fixture-0.1.0");
+    assert_eq!(
+        std::fs::read(krate.join("src/lib.rs")).unwrap(),
+        b"pub mod tested;\n"
+    );
+    assert_eq!(
+        std::fs::read(krate.join("src/patched.rs")).unwrap(),
+        b"// new\n"
+    );
+    assert!(krate.join("src/made").is_dir());
+    assert_eq!(
+        std::fs::metadata(krate.join("empty.txt"))
+            .unwrap_err()
+            .kind(),
+        ErrorKind::NotFound
+    );
+    let listed = std::fs::read_dir(&krate)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert!(!listed.iter().any(|name| name == "empty.txt"), "{listed:?}");
+    assert_eq!(std::fs::read(&archive).unwrap(), original);
+    drop(mount);
+    // Without it, as read-only as ever, and without the changes.
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    let krate = mount.path().join("fixture-0.1.0");
+    assert_eq!(
+        std::fs::read(krate.join("src/lib.rs")).unwrap(),
+        b"pub mod nested;\n"
+    );
+    let error = std::fs::write(krate.join("src/lib.rs"), b"").unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::EROFS));
+    let error = std::fs::remove_file(krate.join("empty.txt")).unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::EROFS));
+}
+
+/// What `find <dir> -type f` prints, relative to `dir` and sorted.
+fn find_files(dir: &Path) -> Vec<String> {
+    let output = Command::new("find")
+        .arg(dir)
+        .args(["-type", "f"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let mut files = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.strip_prefix(dir.to_str().unwrap()).unwrap().to_owned())
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[test]
+fn directories_count_their_subdirectories_in_nlink() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    Fixture::new("nested-0.1.0")
+        .file("a/b/c/d.rs", b"")
+        .file("a/b/e.rs", b"")
+        .dir("a/f")
+        .file("a/g/h.rs", b"")
+        .file("i.rs", b"")
+        .write_to(cache.path());
+    let extracted = extract(cache.path());
+    let mount = Mount::binary(cache.path(), &["--no-src-dir", "--ttl", "0"]);
+    // Not known before the crate is populated.
+    let krate = std::fs::metadata(mount.path().join("nested-0.1.0")).unwrap();
+    assert_eq!(krate.nlink(), 1);
+    assert_eq!(find_files(mount.path()), find_files(extracted.path()));
+    let mut pending = vec![mount.path().to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        let subdirs = entries
+            .iter()
+            .filter(|entry| std::fs::symlink_metadata(entry).unwrap().is_dir())
+            .cloned()
+            .collect::<Vec<_>>();
+        let metadata = std::fs::metadata(&dir).unwrap();
+        assert_eq!(metadata.nlink(), 2 + subdirs.len() as u64, "{dir:?}");
+        assert_eq!(metadata.len(), entries.len() as u64, "{dir:?}");
+        pending.extend(subdirs);
+    }
+}
+
+#[test]
+fn sequential_reads_decompress_once() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let mount = Mount::binary(cache.path(), &["--no-src-dir", "--content-cache-size", "0"]);
+    let big = mount.path().join("fixture-0.1.0/big.bin");
+    let len = std::fs::metadata(&big).unwrap().len();
+    let chunks = (0..len.div_ceil(4096))
+        .map(|chunk| (chunk * 4096, 4096))
+        .collect::<Vec<_>>();
+    let data = read_direct(&big, &chunks).concat();
+    assert_eq!(data, noise(3 << 20 | 123, 1));
+    assert_eq!(stat(mount.path(), "archives_opened"), 1);
+    assert_eq!(stat(mount.path(), "decompressions_started"), 1);
+    // Skipping ahead continues too, going back starts over.
+    read_direct(&big, &[(0, 4096), (len / 2, 4096), (4096, 4096)]);
+    assert_eq!(stat(mount.path(), "decompressions_started"), 3);
+}
+
+#[test]
+fn reads_that_straddle_the_end_of_a_file_are_cut_short() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let extracted = extract(cache.path());
+    let spill = TempDir::new("spill");
+    let spill_dir = spill.path().to_str().unwrap();
+    let src_dir = extracted.path().to_str().unwrap();
+    let data = noise(200_000, 2);
+    let len = data.len() as u64;
+    let tail = |from: u64| data[from as usize..].to_vec();
+    let paths: [&[&str]; 4] = [
+        &["--src-dir", src_dir],
+        &["--no-src-dir"],
+        &[
+            "--no-src-dir",
+            "--content-cache-size",
+            "0",
+            "--spill-dir",
+            spill_dir,
+        ],
+        &["--no-src-dir", "--content-cache-size", "0"],
+    ];
+    for args in paths {
+        let mount = Mount::binary(cache.path(), args);
+        let medium = mount.path().join("other-2.0.0-rc.1/data/medium.bin");
+        // From the start, then on from where that left off, as a cursor
+        // continues.
+        let reads = read_direct(
+            &medium,
+            &[
+                (0, len as usize - 10),
+                (len - 10, 4096),
+                (len, 4096),
+                (len + 1, 1),
+            ],
+        );
+        assert_eq!(reads[0], data[..len as usize - 10], "{args:?}");
+        assert_eq!(reads[1], tail(len - 10), "{args:?}");
+        assert_eq!(reads[2..], [vec![], vec![]], "{args:?}");
+        // Straight to offsets past the middle.
+        for offset in [len - 1, len - 4095, len / 2 + 1] {
+            let reads = read_direct(&medium, &[(offset, 4096 * 32)]);
+            assert_eq!(reads[0], tail(offset), "{args:?} at {offset}");
+        }
+        let toml = mount.path().join("other-2.0.0-rc.1/Cargo.toml");
+        let expected = b"[package]\nname = \"other\"\n";
+        let reads = read_direct(
+            &toml,
+            &[(5, 4096), (expected.len() as u64, 1), (u64::MAX >> 2, 1)],
+        );
+        assert_eq!(reads[0], expected[5..], "{args:?}");
+        assert_eq!(reads[1..], [vec![], vec![]], "{args:?}");
+    }
+}
+
+/// Counts the lookups the kernel makes, passing everything a listing needs
+/// on to the filesystem.
+struct CountingLookups {
+    fs: fuse_crates::CargoCacheFs,
+    lookups: Arc<AtomicUsize>,
+}
+
+impl fuser::Filesystem for CountingLookups {
+    fn init(
+        &mut self,
+        req: &fuser::Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        self.fs.init(req, config)
+    }
+
+    fn lookup(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        self.fs.lookup(req, parent, name, reply)
+    }
+
+    fn forget(&mut self, req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        self.fs.forget(req, ino, nlookup)
+    }
+
+    fn getattr(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        self.fs.getattr(req, ino, reply)
+    }
+
+    fn opendir(&mut self, req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        self.fs.opendir(req, ino, flags, reply)
+    }
+
+    fn readdir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: fuser::ReplyDirectory,
+    ) {
+        self.fs.readdir(req, ino, fh, offset, reply)
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: fuser::ReplyDirectoryPlus,
+    ) {
+        self.fs.readdirplus(req, ino, fh, offset, reply)
+    }
+
+    fn releasedir(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.fs.releasedir(req, ino, fh, flags, reply)
+    }
+}
+
+#[test]
+fn long_listing_needs_no_lookups() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    let mut wide = Fixture::new("wide-0.1.0").file("Cargo.toml", b"toml\n");
+    for i in 0..300 {
+        wide = match i % 10 {
+            0 => wide.dir(&format!("src/dir-{i}")),
+            1 => wide.symlink(&format!("src/link-{i}"), "../Cargo.toml"),
+            _ => wide.file(&format!("src/file-{i}.rs"), &noise(i * 37, i as u64)),
+        };
+    }
+    wide.write_to(cache.path());
+    let extracted = extract(cache.path());
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let fs = CountingLookups {
+        fs: fuse_crates::CargoCacheFs::new(cache.path()).unwrap(),
+        lookups: lookups.clone(),
+    };
+    let mount = Mount::library(fs);
+    let src = mount.path().join("wide-0.1.0/src");
+    assert!(src.is_dir());
+    let before = lookups.load(Ordering::Relaxed);
+    // What `ls -l` does: list, then stat every entry.
+    let mut listed = 0;
+    for entry in std::fs::read_dir(&src).unwrap() {
+        let name = entry.unwrap().file_name();
+        let metadata = std::fs::symlink_metadata(src.join(&name)).unwrap();
+        let expected =
+            std::fs::symlink_metadata(extracted.path().join("wide-0.1.0/src").join(&name)).unwrap();
+        assert_eq!(metadata.file_type(), expected.file_type(), "{name:?}");
+        if metadata.is_file() {
+            assert_eq!(metadata.len(), expected.len(), "size of {name:?}");
+        }
+        listed += 1;
+    }
+    assert_eq!(listed, 300);
+    assert_eq!(lookups.load(Ordering::Relaxed), before);
+}
+
+#[test]
+fn a_directory_of_1000_entries_lists_each_once() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    // Long names, so the kernel fetches the listing in many replies.
+    let name = |i| format!("{}-{i:04}.rs", "x".repeat(100));
+    let mut wide = Fixture::new("wide-0.1.0");
+    for i in 0..1000 {
+        wide = wide.file(&format!("src/{}", name(i)), b"");
+    }
+    wide.write_to(cache.path());
+    let expected = (0..1000).map(name).collect::<Vec<_>>();
+    for mount in [
+        Mount::library(fuse_crates::CargoCacheFs::new(cache.path()).unwrap()),
+        Mount::binary(cache.path(), &[]),
+    ] {
+        let src = mount.path().join("wide-0.1.0/src");
+        let mut listed = std::fs::read_dir(&src)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(listed, expected);
+    }
+}
+
+#[test]
+fn hard_links_pass_fsck_and_read_their_target() {
+    let cache = fixtures();
+    Fixture::new("linked-0.1.0")
+        .file("Cargo.toml", b"[package]\n")
+        .file("src/lib.rs", b"// lib\n")
+        .hard_link("src/again.rs", "src/lib.rs")
+        .write_to(cache.path());
+    let output = Command::new(env!("CARGO_BIN_EXE_fuse-crates"))
+        .args(["--fsck", "--no-src-dir"])
+        .arg(cache.path())
+        .arg(TempDir::new("mnt").path())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(0), "{stdout}");
+    assert_eq!(stdout, "");
+    if !fuse_available() {
+        return;
+    }
+    let extracted = extract(cache.path());
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    assert_same_tree(mount.path(), extracted.path());
+    for name in ["lib.rs", "again.rs"] {
+        let metadata = std::fs::metadata(mount.path().join("linked-0.1.0/src").join(name));
+        assert_eq!(metadata.unwrap().nlink(), 2, "{name}");
+    }
+}
+
+#[test]
+fn every_archive_format_mounts_the_same_tree() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    let fixture = |stem| {
+        Fixture::new(stem)
+            .file("Cargo.toml", b"[package]\n")
+            .file("src/lib.rs", &noise(100_000, 7))
+            .dir("src/empty")
+            .symlink("src/link.rs", "lib.rs")
+            .into_tar()
+    };
+    for (stem, extension) in [
+        ("gz-0.1.0", "crate"),
+        ("tgz-0.1.0", "tgz"),
+        ("targz-0.1.0", "tar.gz"),
+    ] {
+        write_gzip(
+            &cache.path().join(format!("{stem}.{extension}")),
+            &fixture(stem),
+        );
+    }
+    std::fs::write(cache.path().join("plain-0.1.0.tar"), fixture("plain-0.1.0")).unwrap();
+    // Skipped, for want of a decoder.
+    std::fs::write(cache.path().join("zstd-0.1.0.tar.zst"), b"\x28\xb5\x2f\xfd").unwrap();
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    let mut listed = std::fs::read_dir(mount.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    listed.sort();
+    assert_eq!(
+        listed,
+        ["gz-0.1.0", "plain-0.1.0", "targz-0.1.0", "tgz-0.1.0"]
+    );
+    let reference = TempDir::new("reference");
+    write_gzip(
+        &reference.path().join("gz-0.1.0.crate"),
+        &fixture("gz-0.1.0"),
+    );
+    let extracted = extract(reference.path());
+    for stem in listed {
+        assert_same_tree(&mount.path().join(stem), &extracted.path().join("gz-0.1.0"));
+    }
+}
+
+#[test]
+fn zstd_archives_are_declined_by_name() {
+    let cache = TempDir::new("cache");
+    let zstd = b"\x28\xb5\x2f\xfd\0\0\0\0";
+    std::fs::write(cache.path().join("z-0.1.0.crate"), zstd).unwrap();
+    std::fs::write(cache.path().join("z-0.1.0.tar.zst"), zstd).unwrap();
+    let mountpoint = TempDir::new("mnt");
+    let run = |source: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_fuse-crates"))
+            .args(["--strict", "--no-src-dir"])
+            .arg(source)
+            .arg(mountpoint.path())
+            .output()
+            .unwrap()
+    };
+    let output = run(cache.path());
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1, "{stdout}");
+    assert!(
+        stdout.contains("zstd-compressed archives are not supported"),
+        "{stdout}"
+    );
+    let output = run(&cache.path().join("z-0.1.0.tar.zst"));
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("is zstd-compressed, which is not supported"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn archives_added_and_removed_while_mounted_show_up_when_listed() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    fixture(cache.path());
+    let other = other(cache.path());
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    let root = || {
+        let mut names = std::fs::read_dir(mount.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    assert_eq!(root(), ["fixture-0.1.0", "other-2.0.0-rc.1"]);
+    let kept = std::fs::metadata(mount.path().join("fixture-0.1.0"))
+        .unwrap()
+        .ino();
+    Fixture::new("late-1.0.0")
+        .file("Cargo.toml", b"[package]\nname = \"late\"\n")
+        .write_to(cache.path());
+    std::fs::remove_file(other).unwrap();
+    assert_eq!(root(), ["fixture-0.1.0", "late-1.0.0"]);
+    assert_eq!(
+        std::fs::read(mount.path().join("late-1.0.0/Cargo.toml")).unwrap(),
+        b"[package]\nname = \"late\"\n"
+    );
+    let metadata = std::fs::metadata(mount.path().join("fixture-0.1.0")).unwrap();
+    assert_eq!(metadata.ino(), kept);
+}
+
+#[test]
+fn handles_keep_reading_the_archive_they_were_opened_on() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = TempDir::new("cache");
+    let staging = TempDir::new("staging");
+    Fixture::new("replaced-1.0.0")
+        .file("Cargo.toml", b"[package]\nversion = \"old\"\n")
+        .write_to(cache.path());
+    let gone = Fixture::new("gone-1.0.0")
+        .file("Cargo.toml", b"[package]\n")
+        .write_to(cache.path());
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    let open = |path: &str| {
+        std::fs::File::options()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(mount.path().join(path))
+            .unwrap()
+    };
+    let read = |file: &std::fs::File| {
+        let mut data = vec![0; 4096];
+        file.read_at(&mut data, 0).map(|read| {
+            data.truncate(read);
+            data
+        })
+    };
+    let old = open("replaced-1.0.0/Cargo.toml");
+    assert_eq!(read(&old).unwrap(), b"[package]\nversion = \"old\"\n");
+    let of_gone = open("gone-1.0.0/Cargo.toml");
+    // Replaced the way cargo writes archives, by renaming another file over
+    // it.
+    let replacement = Fixture::new("replaced-1.0.0")
+        .file("Cargo.toml", b"[package]\nversion = \"replaced\"\n")
+        .write_to(staging.path());
+    std::fs::rename(replacement, cache.path().join("replaced-1.0.0.crate")).unwrap();
+    std::fs::remove_file(gone).unwrap();
+    let names = std::fs::read_dir(mount.path()).unwrap().count();
+    assert_eq!(names, 1);
+    assert_eq!(read(&old).unwrap(), b"[package]\nversion = \"old\"\n");
+    let new = open("replaced-1.0.0/Cargo.toml");
+    assert_eq!(read(&new).unwrap(), b"[package]\nversion = \"replaced\"\n");
+    let error = read(&of_gone).unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::ESTALE));
+}
+
+fn statvfs(path: &Path) -> libc::statvfs {
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    let mut stat = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { libc::statvfs(path.as_ptr(), &mut stat) }, 0);
+    stat
+}
+
+#[test]
+fn statfs_counts_what_has_been_populated() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let mount = Mount::binary(cache.path(), &["--no-src-dir"]);
+    let before = statvfs(mount.path());
+    let (mut files, mut blocks) = (1, 0);
+    let mut pending = vec![mount.path().to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let metadata = entry.metadata().unwrap();
+            files += 1;
+            if metadata.is_file() {
+                blocks += metadata.len().div_ceil(512);
+            }
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            }
+        }
+    }
+    let after = statvfs(mount.path());
+    for stat in [before, after] {
+        assert_eq!(
+            (stat.f_bsize, stat.f_frsize, stat.f_namemax),
+            (512, 512, 255)
+        );
+        assert_eq!((stat.f_bfree, stat.f_bavail, stat.f_ffree), (0, 0, 0));
+    }
+    // Every inode counts, the control directory and its files among them.
+    assert!(
+        after.f_files > files,
+        "{} files, {files} walked",
+        after.f_files
+    );
+    assert!(
+        after.f_blocks >= blocks,
+        "{} blocks, {blocks} walked",
+        after.f_blocks
+    );
+    assert!(after.f_files > before.f_files);
+}
+
+#[test]
+fn access_log_and_summary_count_the_bytes_read() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let logs = TempDir::new("logs");
+    let log = logs.path().join("access.jsonl");
+    let mount = Mount::binary(
+        cache.path(),
+        &["--no-src-dir", "--access-log", log.to_str().unwrap()],
+    );
+    let krate = mount.path().join("other-2.0.0-rc.1");
+    let summary = mount.path().join(".fuse-crates/access-summary.json");
+    assert_eq!(std::fs::read_to_string(&summary).unwrap(), "{}\n");
+    let medium = std::fs::read(krate.join("data/medium.bin")).unwrap();
+    // The second time from the page cache, without a record.
+    let toml = std::fs::read(krate.join("Cargo.toml")).unwrap();
+    std::fs::read(krate.join("Cargo.toml")).unwrap();
+    // Counted while the file is still open, too.
+    let lib = std::fs::File::open(mount.path().join("fixture-0.1.0/src/lib.rs")).unwrap();
+    let mut buf = [0; 4];
+    lib.read_at(&mut buf, 0).unwrap();
+    let bytes = medium.len() + toml.len();
+    assert_eq!(
+        std::fs::read_to_string(&summary).unwrap(),
+        format!(
+            concat!(
+                r#"{{"fixture-0.1.0":{{"reads":1,"bytes":16}},"#,
+                r#""other-2.0.0-rc.1":{{"reads":2,"bytes":{}}}}}"#,
+                "\n"
+            ),
+            bytes
+        )
+    );
+    drop(lib);
+    drop(mount);
+    let log = std::fs::read_to_string(log).unwrap();
+    let mut lines = log.lines().collect::<Vec<_>>();
+    lines.sort_by_key(|line| line.contains("Cargo.toml"));
+    // FUSE passes the id of the reading thread.
+    let pid = format!(r#""pid":{}"#, unsafe { libc::gettid() });
+    assert_eq!(lines.len(), 3, "{log}");
+    lines.retain(|line| !line.contains("fixture-0.1.0"));
+    for (line, path, bytes) in [
+        (lines[0], "data/medium.bin", medium.len()),
+        (lines[1], "Cargo.toml", toml.len()),
+    ] {
+        assert!(line.contains(r#""crate":"other-2.0.0-rc.1""#), "{line}");
+        assert!(line.contains(&format!(r#""path":"{path}""#)), "{line}");
+        assert!(line.contains(&format!(r#""bytes":{bytes}}}"#)), "{line}");
+        assert!(line.contains(&pid), "{line}");
+    }
+}
+
+/// The contents and mtime of every generated file in the mount at `mount`.
+fn generated_files(mount: &Path, reports: &[&str]) -> Vec<(PathBuf, Vec<u8>, i64)> {
+    let control = mount.join(".fuse-crates");
+    let mut paths = std::fs::read_dir(&control)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths.extend(reports.iter().map(|report| mount.join(report)));
+    paths
+        .into_iter()
+        .map(|path| {
+            let contents = std::fs::read(&path).unwrap();
+            let mtime = std::fs::metadata(&path).unwrap().mtime();
+            (
+                path.strip_prefix(mount).unwrap().to_path_buf(),
+                contents,
+                mtime,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn generated_files_are_the_same_in_every_mount() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    Fixture::new("bad-0.1.0")
+        .file("Cargo.toml", b"[package]\n")
+        .write_to(cache.path());
+    let scratch = TempDir::new("verify");
+    let checksums = scratch.path().join("SHA256SUMS");
+    std::fs::write(&checksums, format!("{}  bad-0.1.0.crate\n", "0".repeat(64))).unwrap();
+    let memo = scratch.path().join("memo");
+    let args = [
+        "--no-src-dir",
+        "--verify",
+        "--checksums",
+        checksums.to_str().unwrap(),
+        "--verify-cache",
+        memo.to_str().unwrap(),
+    ];
+    let generated = || {
+        let mount = Mount::binary(cache.path(), &args);
+        generated_files(mount.path(), &["bad-0.1.0/VERIFICATION_FAILED"])
+    };
+    let first = generated();
+    let names = first
+        .iter()
+        .map(|(path, _, _)| path.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            ".fuse-crates/access-summary.json",
+            ".fuse-crates/crates",
+            ".fuse-crates/last-rescan.json",
+            ".fuse-crates/stats",
+            ".fuse-crates/version",
+            "bad-0.1.0/VERIFICATION_FAILED",
+        ]
+    );
+    assert!(first.iter().all(|(_, contents, _)| !contents.is_empty()));
+    // In fresh processes, some time later.
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(generated(), first);
+}
+
+fn root_names(mount: &Path) -> Vec<String> {
+    let mut names = std::fs::read_dir(mount)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn the_control_dir_is_renamed_moved_to_a_socket_or_left_out() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let crates = ["fixture-0.1.0", "other-2.0.0-rc.1"];
+    let shown = ["--no-src-dir", "--show-control-dir"];
+    let missing = |path: PathBuf| {
+        let error = std::fs::metadata(&path).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound, "{}", path.display());
+    };
+
+    let mount = Mount::binary(
+        cache.path(),
+        &[&shown[..], &["--control-dir", "_fc"]].concat(),
+    );
+    assert_eq!(root_names(mount.path()), ["_fc", crates[0], crates[1]]);
+    let version = std::fs::read_to_string(mount.path().join("_fc/version")).unwrap();
+    assert!(version.starts_with("fuse-crates "), "{version}");
+    missing(mount.path().join(".fuse-crates"));
+    drop(mount);
+
+    let mount = Mount::binary(cache.path(), &[&shown[..], &["--no-control-dir"]].concat());
+    assert_eq!(root_names(mount.path()), crates);
+    missing(mount.path().join(".fuse-crates/stats"));
+    drop(mount);
+
+    let sockets = TempDir::new("sockets");
+    let socket = sockets.path().join("control.sock");
+    let socket_args = ["--control-socket", socket.to_str().unwrap()];
+    let mount = Mount::binary(cache.path(), &[&shown[..], &socket_args].concat());
+    assert_eq!(root_names(mount.path()), crates);
+    missing(mount.path().join(".fuse-crates"));
+    let toml = std::fs::read(mount.path().join("other-2.0.0-rc.1/Cargo.toml")).unwrap();
+    let ask = |request: &str| {
+        let mut stream = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut answer = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut answer).unwrap();
+        answer
+    };
+    let stats = ask("stats\n");
+    assert!(
+        stats.contains(&format!("\nbytes_served {}\n", toml.len())),
+        "{stats}"
+    );
+    assert!(ask("").starts_with("crates 2\n"));
+    assert_eq!(ask("crates\n").lines().count(), 2);
+    assert_eq!(ask("no-such-file\n"), "");
+}
+
+#[test]
+fn control_writes_drop_caches_refresh_crates_and_set_the_log_level() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let mount = Mount::binary(
+        cache.path(),
+        &["--no-src-dir", "--ttl", "0", "--control-writes"],
+    );
+    let control = mount.path().join(".fuse-crates");
+    let write = |name: &str, text: &str| std::fs::write(control.join(name), text);
+    let errno = |result: std::io::Result<()>| result.unwrap_err().raw_os_error();
+    let toml = mount.path().join("other-2.0.0-rc.1/Cargo.toml");
+
+    std::fs::read(&toml).unwrap();
+    assert_eq!(stat(mount.path(), "content_cache_files"), 1);
+    assert_eq!(stat(mount.path(), "archives_opened"), 1);
+    write("drop-caches", "1\n").unwrap();
+    assert_eq!(stat(mount.path(), "content_cache_files"), 0);
+    std::fs::read(&toml).unwrap();
+    assert_eq!(stat(mount.path(), "archives_opened"), 2);
+    assert_eq!(errno(write("drop-caches", "all\n")), Some(libc::EINVAL));
+
+    // Rewritten in place, which leaves the cache directory as it was.
+    std::fs::write(
+        cache.path().join("other-2.0.0-rc.1.crate"),
+        Fixture::new("other-2.0.0-rc.1")
+            .file("Cargo.toml", b"[package]\nname = \"rewritten\"\n")
+            .into_gzip(),
+    )
+    .unwrap();
+    std::fs::read_dir(mount.path()).unwrap().for_each(drop);
+    assert_eq!(
+        std::fs::read(&toml).unwrap(),
+        b"[package]\nname = \"other\"\n"
+    );
+    write("refresh", "other-2.0.0-rc.1\n").unwrap();
+    assert_eq!(
+        std::fs::read(&toml).unwrap(),
+        b"[package]\nname = \"rewritten\"\n"
+    );
+    assert!(!mount.path().join("other-2.0.0-rc.1/data").exists());
+    assert_eq!(
+        errno(write("refresh", "absent-1.0.0\n")),
+        Some(libc::ENOENT)
+    );
+
+    // Everything else stays read-only.
+    assert_eq!(errno(write("stats", "0\n")), Some(libc::EROFS));
+    assert_eq!(errno(std::fs::write(&toml, "")), Some(libc::EROFS));
+    let error = std::fs::File::create(mount.path().join("fixture-0.1.0/new.rs")).unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::EROFS));
+
+    let level = || std::fs::read_to_string(control.join("log-level")).unwrap();
+    let from_rust_log = level();
+    write("log-level", "trace\n").unwrap();
+    assert_eq!(level(), "trace\n");
+    assert_eq!(errno(write("log-level", "loud\n")), Some(libc::EINVAL));
+    write("log-level", &from_rust_log).unwrap();
+    assert_eq!(level(), from_rust_log);
+}
+
+#[test]
+fn refreshed_crates_are_not_left_in_the_kernel_caches() {
+    if !fuse_available() {
+        return;
+    }
+    let cache = fixtures();
+    let mount = Mount::binary(
+        cache.path(),
+        &["--no-src-dir", "--ttl", "3600", "--control-writes"],
+    );
+    let toml = mount.path().join("other-2.0.0-rc.1/Cargo.toml");
+    let data = mount.path().join("other-2.0.0-rc.1/data");
+    assert_eq!(
+        std::fs::read(&toml).unwrap(),
+        b"[package]\nname = \"other\"\n"
+    );
+    assert!(data.exists());
+    assert!(!mount.path().join("other-2.0.0-rc.1/new.rs").exists());
+
+    std::fs::write(
+        cache.path().join("other-2.0.0-rc.1.crate"),
+        Fixture::new("other-2.0.0-rc.1")
+            .file("Cargo.toml", b"[package]\nname = \"rewritten\"\n")
+            .file("new.rs", b"")
+            .into_gzip(),
+    )
+    .unwrap();
+    std::fs::write(
+        mount.path().join(".fuse-crates/refresh"),
+        "other-2.0.0-rc.1\n",
+    )
+    .unwrap();
+    // Sent by a thread of its own, so not necessarily by the time the
+    // write is answered.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while std::fs::read(&toml).unwrap() != b"[package]\nname = \"rewritten\"\n" {
+        assert!(Instant::now() < deadline, "still reading the old archive");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(stat(mount.path(), "invalidations_sent") > 0);
+    let report =
+        std::fs::read_to_string(mount.path().join(".fuse-crates/last-rescan.json")).unwrap();
+    assert!(
+        report.contains(r#"other-2.0.0-rc.1.crate","reason":"asked"}]"#),
+        "{report}"
+    );
+    assert!(!data.exists());
+    // Looked up as missing before, and no longer.
+    assert!(mount.path().join("other-2.0.0-rc.1/new.rs").exists());
+}